@@ -1,8 +1,16 @@
-use std::{net::TcpStream, process, io::{self, Write}};
+use std::{net::TcpStream, os::unix::net::UnixStream, process, io, env, sync::{mpsc, Arc}, thread, time::Duration};
 
-use retry::{delay::Fixed, retry_with_index};
+use retry::{delay::{Fixed, Exponential, jitter}, retry_with_index};
 
-use file_share::{ShareCommand, Share, Location, Config};
+use file_share::{
+    ShareCommand, Share, Location, Config, CommandType, config, discovery, connection::Connection, throttle::{Bucket, Throttled}, tls,
+};
+
+mod subscribe;
+mod multiplex;
+mod sync;
+mod transfer;
+mod watch;
 
 fn main() {
     let config = Config::build("Config.toml").unwrap_or_else(|error| {
@@ -13,38 +21,150 @@ fn main() {
         process::exit(1);
     });
 
+    // Find a server on the local network when running with --discover, otherwise use the configured servers
+    let servers = if env::args().any(|arg| arg == "--discover") {
+        vec![discover_server(&config)]
+    } else {
+        config.servers()
+    };
+
+    if servers.is_empty() {
+        eprintln!("No server configured, set server or servers in Config.toml or run with --discover");
+        process::exit(1);
+    }
+
+    let (stream, active) = connect(&config, &servers, 0).unwrap_or_else(|error| {
+        eprintln!("Failed to connect to server!: {error}");
+        process::exit(1)
+    });
+
+    match config.multiplex() {
+        true => multiplex::handle_connection(stream),
+        false => handle_connection(stream, active, &config, &servers),
+    }
+}
+
+/// Connect to one of the servers, starting with the server at index start and moving on to the next server after every failed
+/// attempt. Returns the connection and the index of the server it is connected to
+fn connect(
+    config: &config::Client,
+    servers: &[String],
+    start: usize,
+) -> Result<(Throttled<Connection>, usize), Box<dyn std::error::Error>> {
     let stream = 
-    // Retry connecting to the server 10 times, once every 1000 milliseconds
-    retry_with_index(Fixed::from_millis(config.retry_delay()).take(config.retry_amount()), |current_try| {
-        match TcpStream::connect(config.server()) {
-            Ok(stream) => Ok(stream),
+    // Retry connecting to the servers, the amount and delay of attempts are set in the config
+    retry_with_index(delays(config)?, |current_try| {
+        let index = (start + current_try as usize - 1) % servers.len();
+
+        // Servers on this host can be reached through their Unix socket with a `unix:/path` address
+        let stream = match servers[index].strip_prefix("unix:") {
+            Some(path) => UnixStream::connect(path).map(Connection::Unix),
+            None => TcpStream::connect(&servers[index]).map(Connection::Tcp),
+        };
+
+        match stream {
+            Ok(stream) => Ok((stream, index)),
             Err(error) => {
-                eprintln!("Connection to server failed, attempt: {current_try}");
+                eprintln!("Connection to server {} failed, attempt: {current_try}", servers[index]);
                 Err(error)
             },
         }
     });
 
-    let stream = stream.unwrap_or_else(|error| {
-        eprintln!("Failed to connect to server!: {error}");
-        process::exit(1)
-    });
+    let (stream, index) = stream.map_err(|error| error.to_string())?;
+    let server = &servers[index];
+
+    // Wrap the stream in TLS if the server uses it, Unix sockets dont leave the host so they are never wrapped
+    let stream = match (config.tls(), stream) {
+        (Some(ca), Connection::Tcp(stream)) => {
+            // The server certificate must be valid for the host part of the address
+            let host = server.rsplit_once(':').map_or(server.as_str(), |(host, _)| host);
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+
+            tls::connect(stream, tls::client_config(ca)?, host)?
+        }
+        (_, stream) => stream,
+    };
+
+    // Every connection gets its own limit
+    let stream = Throttled::new(stream, vec![Arc::new(Bucket::new(config.bandwidth_limit()))]);
 
-    handle_connection(stream);
+    Ok((stream, index))
 }
 
-fn handle_connection(mut stream: TcpStream) {
-    println!("Connected to the server!");
+/// Returns the delays to wait between connection attempts, following the backoff set in the config. The delays stop once the
+/// attempts run out or when they would add up to more than the maximum wait
+fn delays(config: &config::Client) -> Result<Box<dyn Iterator<Item = Duration>>, Box<dyn std::error::Error>> {
+    let delays: Box<dyn Iterator<Item = Duration>> = match config.retry_backoff() {
+        "fixed" => Box::new(Fixed::from_millis(config.retry_delay())),
+        // The delay doubles after every attempt
+        "exponential" => Box::new(Exponential::from_millis_with_factor(config.retry_delay(), 2.0)),
+        // Randomize the doubling delays so clients that lost the same server dont all reconnect at once
+        "jitter" => Box::new(Exponential::from_millis_with_factor(config.retry_delay(), 2.0).map(jitter)),
+        backoff => return Err(format!("Unknown retry backoff: {backoff}").into()),
+    };
+
+    let max_wait = config.retry_max_wait().map(Duration::from_millis);
+    let mut waited = Duration::ZERO;
+
+    Ok(Box::new(delays.take(config.retry_amount()).take_while(move |delay| {
+        waited += *delay;
+        max_wait.is_none_or(|max_wait| waited <= max_wait)
+    })))
+}
+
+/// Discover the servers on the local network and let the user pick one, returns the address of the picked server
+fn discover_server(config: &config::Client) -> String {
+    println!("Looking for servers...");
+
+    let servers = discovery::discover(config.discovery_port(), Duration::from_millis(config.discovery_timeout()))
+        .unwrap_or_else(|error| {
+            eprintln!("Failed to discover servers: {error}");
+            process::exit(1);
+        });
+
+    if servers.is_empty() {
+        eprintln!("No servers found");
+        process::exit(1);
+    }
+
+    for (i, server) in servers.iter().enumerate() {
+        println!("{i}: {} ({})", server.name, server.address);
+    }
+
     let mut buf = String::new();
 
     loop {
-        // Empty the buffer
         buf.clear();
 
+        println!("Enter the number of the server to connect to");
+
+        if io::stdin().read_line(&mut buf).unwrap_or_default() == 0 {
+            process::exit(1);
+        }
+
+        match buf.trim().parse::<usize>().ok().and_then(|i| servers.get(i)) {
+            Some(server) => return server.address.to_string(),
+            None => eprintln!("Please enter a number from the list"),
+        }
+    }
+}
+
+fn handle_connection(mut stream: Throttled<Connection>, mut active: usize, config: &config::Client, servers: &[String]) {
+    println!("Connected to the server!");
+    let lines = read_lines();
+    // The last successful LOGIN command, this is sent again after failing over to another server
+    let mut login: Option<String> = None;
+    // Exit status of the last command, the client exits with it so scripts can tell why a command failed
+    let mut status = 0;
+
+    loop {
         println!("Enter what you would like to do, run HELP for help");
 
-        // Read in the command
-        io::stdin().read_line(&mut buf).unwrap_or_default();
+        // Read in the command, stdin was closed if there are no more lines
+        let Ok(buf) = lines.recv() else {
+            process::exit(status);
+        };
 
         // Parse the command into a Command struct
         let command = match ShareCommand::parse(buf.as_str()) {
@@ -57,6 +177,62 @@ fn handle_connection(mut stream: TcpStream) {
             }
         };
 
+        if *command.command_type() == CommandType::Exit {
+            process::exit(status);
+        }
+
+        // Syncing runs many commands over the connection
+        if *command.command_type() == CommandType::Sync {
+            status = match sync::run(&command, &mut stream) {
+                Ok(_) => 0,
+                Err(error) => {
+                    eprintln!("Sync failed: {error}");
+                    1
+                }
+            };
+            continue;
+        }
+
+        // Watching uploads files over the connection until it is stopped
+        if *command.command_type() == CommandType::Watch {
+            status = match watch::run(&command, &mut stream, config, &lines) {
+                Ok(_) => 0,
+                Err(error) => {
+                    eprintln!("Watch failed: {error}");
+                    1
+                }
+            };
+            continue;
+        }
+
+        // Transfers of many files run over connections of their own
+        if matches!(command.command_type(), CommandType::MUpload | CommandType::MReceive) {
+            let servers = transfer::Servers { config, servers, active, login: login.as_deref() };
+
+            status = match transfer::run(&command, &mut stream, &servers) {
+                Ok(_) => 0,
+                Err(error) => {
+                    eprintln!("Transfer failed: {error}");
+                    1
+                }
+            };
+            continue;
+        }
+
+        // Once subscribed the connection only carries events, it is replaced with a new one afterwards
+        if *command.command_type() == CommandType::Subscribe {
+            status = match subscribe::run(command, &mut stream, &lines) {
+                Ok(_) => 0,
+                Err(error) => {
+                    eprintln!("Subscribe failed: {error}");
+                    1
+                }
+            };
+
+            (stream, active) = reconnect(config, servers, active, login.as_deref());
+            continue;
+        }
+
         // Create a new share with the command we got above
         let mut share = Share::new(command, Location::Client);
 
@@ -65,29 +241,37 @@ fn handle_connection(mut stream: TcpStream) {
             // The error message should be clear to the user (file not found, is a directory, etc.) but better error handling will be 
             // added later
             eprintln!("Error occured while preparing data: {error}");
+            status = 1;
             continue;
         }
 
-        // Write the share we prepared to the server/stream
-        share.write_to_stream(&mut stream, Location::Client).unwrap_or_else(|error| {
-            // will handle these errors later.
-            eprintln!("Error occurred: {error}");
-            process::exit(1);
-        });
+        let mut failovers = 0;
 
-        // Make sure all buffered contents reach there destination
-        stream.flush().unwrap_or_else(|error| {
-            // will handle these errors later.
-            eprintln!("Error occurred: {error}");
-            process::exit(1);
-        });
+        // Send the share, if the connection to the server drops fail over to the next server and send it again
+        let mut server_response_share = loop {
+            let error = match share.exchange(&mut stream) {
+                Ok(response) => break response,
+                Err(error) => error,
+            };
 
-        // Read in the response the server send, this can contain requested files, text data, etc.
-        let mut server_response_share = Share::read_from_stream(&mut stream, Location::Client).unwrap_or_else(|error| {
-            // will handle these errors later.
-            eprintln!("Error occurred: {error}");
-            process::exit(1);
-        });
+            // Every server has been tried for this command
+            if failovers == servers.len() {
+                eprintln!("Error occurred: {error}");
+                process::exit(1);
+            }
+            failovers += 1;
+
+            eprintln!("Lost connection to {}: {error}. Failing over", servers[active]);
+
+            (stream, active) = reconnect(config, servers, active + 1, login.as_deref());
+        };
+
+        status = server_response_share.error_code().map_or(0, |code| code.exit_status());
+
+        // Remember the login so it can be sent again after failing over
+        if *server_response_share.command_type() == CommandType::Login && server_response_share.succeeded() {
+            login = Some(buf.clone());
+        }
 
         // If needed execute instructions to get data from the Share struct to storage, print some text data, etc.
         server_response_share.execute().unwrap_or_else(|error| {
@@ -97,3 +281,109 @@ fn handle_connection(mut stream: TcpStream) {
         });
     }
 }
+
+/// Connect again starting with the server at index start, the new connection does not know about the old session so the last
+/// successful LOGIN command is sent again
+fn reconnect(config: &config::Client, servers: &[String], start: usize, login: Option<&str>) -> (Throttled<Connection>, usize) {
+    let (mut stream, active) = connect(config, servers, start).unwrap_or_else(|error| {
+        eprintln!("Failed to connect to server!: {error}");
+        process::exit(1)
+    });
+
+    println!("Connected to {}", servers[active]);
+
+    if let Some(login) = login {
+        if let Err(error) = log_in(&mut stream, login) {
+            eprintln!("Failed to log in again: {error}");
+        }
+    }
+
+    (stream, active)
+}
+
+/// Send a LOGIN command that succeeded before over a new connection
+fn log_in(stream: &mut Throttled<Connection>, login: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut login_share = Share::new(ShareCommand::parse(login)?, Location::Client);
+    let response = login_share.exchange(stream)?;
+
+    match response.succeeded() {
+        true => Ok(()),
+        false => Err(response.server_text().unwrap_or_default().into()),
+    }
+}
+
+/// Read the lines entered on stdin on a thread of their own, commands that run until enter is pressed take their line from the
+/// same reader so no read of stdin outlives them. The receiver is disconnected once stdin is closed
+fn read_lines() -> mpsc::Receiver<String> {
+    let (sender, lines) = mpsc::channel();
+
+    thread::spawn(move || loop {
+        let mut buf = String::new();
+
+        // Stop once stdin is closed
+        if io::stdin().read_line(&mut buf).unwrap_or_default() == 0 || sender.send(buf).is_err() {
+            return;
+        }
+    });
+
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    fn client(retry_delay: u64, retry_amount: usize, settings: &str) -> config::Client {
+        let config = format!("[client]\nretry_delay = {retry_delay}\nretry_amount = {retry_amount}\n{settings}");
+
+        toml::from_str::<Config>(&config).unwrap().client().unwrap()
+    }
+
+    fn millis(config: &config::Client) -> Vec<u64> {
+        delays(config).unwrap().map(|delay| delay.as_millis() as u64).collect()
+    }
+
+    #[test]
+    fn delays_follow_the_backoff_until_the_attempts_run_out() {
+        // There is one delay less than there are attempts
+        assert_eq!(millis(&client(100, 3, "")), [100, 100]);
+        assert_eq!(millis(&client(100, 3, "retry_backoff = 'fixed'")), [100, 100]);
+        assert_eq!(millis(&client(100, 5, "retry_backoff = 'exponential'")), [100, 200, 400, 800]);
+
+        // Jittered delays are never longer than the doubling ones
+        let jittered = millis(&client(100, 5, "retry_backoff = 'jitter'"));
+        assert_eq!(jittered.len(), 4);
+
+        for (delay, max) in jittered.into_iter().zip([100, 200, 400, 800]) {
+            assert!(delay <= max, "{delay} > {max}");
+        }
+
+        assert!(delays(&client(100, 4, "retry_backoff = 'linear'")).is_err());
+    }
+
+    #[test]
+    fn delays_stop_at_the_maximum_wait() {
+        assert_eq!(millis(&client(100, 10, "retry_max_wait = 250")), [100, 100]);
+        assert_eq!(millis(&client(100, 10, "retry_backoff = 'exponential'\nretry_max_wait = 700")), [100, 200, 400]);
+        assert!(millis(&client(100, 10, "retry_max_wait = 50")).is_empty());
+    }
+
+    #[test]
+    fn failed_servers_are_skipped_in_order() {
+        // Nothing listens on the port of a dropped listener
+        let dead = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let live = listener.local_addr().unwrap().to_string();
+        let config = client(1, 3, "");
+
+        assert_eq!(connect(&config, &[dead.clone(), live.clone()], 0).unwrap().1, 1);
+        assert_eq!(connect(&config, &[dead.clone(), dead.clone(), live.clone()], 1).unwrap().1, 2);
+        // Moving past the last server wraps around to the first
+        assert_eq!(connect(&config, &[live.clone(), dead.clone()], 1).unwrap().1, 0);
+
+        // Every attempt is used up before reaching the live server
+        assert!(connect(&config, &[dead.clone(), dead.clone(), dead, live], 0).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+use std::process;
+
+use file_share::{connection::Connection, mux::Multiplexer, throttle::Throttled, CommandType, Location, Share, ShareCommand};
+
+/// Run commands in the background over one multiplexed connection. Commands are read on their own thread so responses can be
+/// handled while waiting for the next command, and a command entered during a large transfer does not wait for it to finish
+pub fn handle_connection(stream: Throttled<Connection>) {
+    println!("Connected to the server!");
+
+    let mut mux = Multiplexer::start(stream).unwrap_or_else(|error| {
+        eprintln!("Failed to multiplex the connection: {error}");
+        process::exit(1);
+    });
+
+    let lines = crate::read_lines();
+
+    println!("Enter what you would like to do, run HELP for help");
+
+    // Exit status of the last command that finished, the client exits with it so scripts can tell why a command failed
+    let mut status = 0;
+
+    loop {
+        // Only wait for a command when no response is outstanding
+        let line = match mux.pending() {
+            0 => match lines.recv() {
+                Ok(line) => Some(line),
+                Err(_) => process::exit(status),
+            },
+            _ => lines.try_recv().ok(),
+        };
+
+        if let Some(line) = line {
+            send(&mut mux, &line, &mut status);
+        }
+
+        if mux.pending() == 0 {
+            continue;
+        }
+
+        poll(&mut mux, &mut status);
+    }
+}
+
+/// Move the transfers along, handling the response if one finished and setting status to its exit status. The connection cant be
+/// used after an error
+fn poll(mux: &mut Multiplexer<Throttled<Connection>>, status: &mut i32) {
+    match mux.poll() {
+        Ok(Some((id, mut response))) => {
+            println!("[{id}] Finished");
+            *status = response.error_code().map_or(0, |code| code.exit_status());
+
+            response.execute().unwrap_or_else(|error| {
+                eprintln!("Error occurred: {error}");
+            });
+        }
+        Ok(None) => (),
+        Err(error) => {
+            eprintln!("Error occurred: {error}");
+            process::exit(1);
+        }
+    }
+}
+
+/// Parse a command and queue it on the connection
+fn send(mux: &mut Multiplexer<Throttled<Connection>>, line: &str, status: &mut i32) {
+    let command = match ShareCommand::parse(line) {
+        Ok(command) => command,
+        Err(error) => {
+            eprintln!("Please type a correct command, or HELP for help: {error}");
+            return;
+        }
+    };
+
+    match command.command_type() {
+        // Finish the commands that are still running first
+        CommandType::Exit => {
+            if mux.pending() > 0 {
+                println!("Waiting for {} commands to finish", mux.pending());
+            }
+
+            while mux.pending() > 0 {
+                poll(mux, status);
+            }
+
+            process::exit(*status);
+        }
+        CommandType::Sync | CommandType::Watch | CommandType::Subscribe | CommandType::MUpload | CommandType::MReceive => {
+            eprintln!("{} cant run on a multiplexed connection, set multiplex = false in Config.toml to use it", line.trim());
+            return;
+        }
+        _ => (),
+    }
+
+    let mut share = Share::new(command, Location::Client);
+
+    if let Err(error) = share.prepare_data() {
+        eprintln!("Error occured while preparing data: {error}");
+        *status = 1;
+        return;
+    }
+
+    match mux.send(share) {
+        Ok(id) => println!("[{id}] Started {}", line.trim()),
+        Err(error) => eprintln!("Error occurred: {error}"),
+    }
+}
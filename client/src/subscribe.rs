@@ -0,0 +1,40 @@
+use std::{sync::mpsc::Receiver, thread};
+
+use file_share::{connection::Connection, events::Event, throttle::Throttled, Location, Share, ShareCommand};
+
+/// Subscribe to the changes other clients make to a directory on the server and print them as they arrive, the command is
+/// `SUBSCRIBE [dir]`. Printing stops when enter is pressed, the connection only carries events once subscribed so it is shut down
+/// and must be replaced
+pub fn run(
+    command: ShareCommand,
+    stream: &mut Throttled<Connection>,
+    lines: &Receiver<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut share = Share::new(command, Location::Client);
+    let response = share.exchange(stream)?;
+
+    if !response.succeeded() {
+        return Err(response.server_text().unwrap_or_default().into());
+    }
+
+    println!("Subscribed, press enter to stop");
+
+    let socket = stream.get_ref().try_clone_socket()?;
+
+    thread::scope(|scope| {
+        // Reading fails once the connection is shut down after enter is pressed, or when the server goes away
+        scope.spawn(|| {
+            while let Ok(event) = Event::read_from_stream(stream) {
+                println!("{event}");
+            }
+        });
+
+        // Stdin being closed stops it too
+        lines.recv().unwrap_or_default();
+
+        // The server may already have closed the connection
+        socket.shutdown().unwrap_or_default();
+    });
+
+    Ok(())
+}
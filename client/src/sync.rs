@@ -0,0 +1,381 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs,
+    path::{Component, Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use sha2::{Digest, Sha256};
+
+use file_share::{connection::Connection, throttle::Throttled, CommandType, Location, Share, ShareCommand};
+
+/// File inside the local directory that remembers what was synced last time, this tells deleted files apart from new ones and lets
+/// unchanged files skip hashing
+const STATE_FILE: &str = ".file_share-sync";
+
+/// Which side of the sync wins
+#[derive(PartialEq)]
+enum Mode {
+    /// Make the remote directory match the local directory
+    Push,
+    /// Make the local directory match the remote directory
+    Pull,
+    /// Copy changes both ways, files changed on both sides are conflicts
+    Both,
+}
+
+/// A file in the local directory
+struct LocalFile {
+    size: u64,
+    /// Modification time in nanoseconds since the unix epoch
+    modified: u64,
+    hash: String,
+}
+
+/// What was synced last time, for a single file
+struct Synced {
+    /// Hash both sides had after the last sync
+    hash: String,
+    /// Size and modification time of the local file after the last sync, if they did not change the hash did not either
+    size: u64,
+    modified: u64,
+}
+
+/// What to do with a single file
+#[derive(Debug, PartialEq)]
+enum Action {
+    Push,
+    Pull,
+    DeleteRemote,
+    DeleteLocal,
+    Conflict,
+}
+
+/// Sync a local directory with a directory on the server, the command is `SYNC local_dir remote_dir [push|pull|both] [delete]`.
+/// Files are compared by hash, only files that changed are transferred
+pub fn run(command: &ShareCommand, stream: &mut Throttled<Connection>) -> Result<(), Box<dyn std::error::Error>> {
+    let args = command.args();
+    let local_dir = Path::new(&args[0]);
+    let remote_dir = args[1].trim_start_matches("./").trim_matches('/');
+    let remote_dir = if remote_dir == "." { "" } else { remote_dir };
+
+    let mut mode = Mode::Both;
+    let mut delete = false;
+
+    for option in &args[2..] {
+        match option.as_str() {
+            "push" => mode = Mode::Push,
+            "pull" => mode = Mode::Pull,
+            "both" => mode = Mode::Both,
+            "delete" => delete = true,
+            unknown => return Err(format!("Unknown sync option: {unknown}").into()),
+        }
+    }
+
+    if !local_dir.is_dir() {
+        return Err(format!("{} is not a directory", local_dir.display()).into());
+    }
+
+    let mut synced = load_state(local_dir, remote_dir);
+    let local = local_files(local_dir, &synced)?;
+    let remote = remote_files(stream, remote_dir)?;
+
+    let paths: BTreeSet<&String> = local.keys().chain(remote.keys()).chain(synced.keys()).collect();
+    let mut actions = Vec::new();
+
+    for path in paths {
+        let local_hash = local.get(path).map(|file| file.hash.as_str());
+        let remote_hash = remote.get(path).map(String::as_str);
+        let synced_hash = synced.get(path).map(|synced| synced.hash.as_str());
+
+        if let Some(action) = decide(&mode, delete, local_hash, remote_hash, synced_hash) {
+            actions.push((path.clone(), action));
+        }
+    }
+
+    let (mut pushed, mut pulled, mut deleted) = (0, 0, 0);
+    let mut conflicts = Vec::new();
+
+    for (path, action) in actions {
+        let local_path = local_dir.join(&path);
+        let remote_path = remote_path(remote_dir, &path);
+
+        match action {
+            Action::Push => {
+                let mut share = Share::new(ShareCommand::new(CommandType::Upload, vec![remote_path]), Location::Client);
+                share.load_file(fs::read(&local_path)?);
+
+                check(share.exchange(stream)?, &path)?;
+                pushed += 1;
+            }
+            Action::Pull => {
+                let mut share = Share::new(ShareCommand::new(CommandType::Receive, vec![remote_path]), Location::Client);
+                let file = check(share.exchange(stream)?, &path)?.take_file().unwrap_or_default();
+
+                if let Some(parent) = local_path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(&local_path, file)?;
+                pulled += 1;
+            }
+            Action::DeleteRemote => {
+                let mut share = Share::new(ShareCommand::new(CommandType::Delete, vec![remote_path]), Location::Client);
+
+                check(share.exchange(stream)?, &path)?;
+                synced.remove(&path);
+                deleted += 1;
+            }
+            Action::DeleteLocal => {
+                fs::remove_file(&local_path)?;
+                synced.remove(&path);
+                deleted += 1;
+            }
+            Action::Conflict => conflicts.push(path),
+        }
+    }
+
+    // Remember the files both sides now have
+    let local = local_files(local_dir, &synced)?;
+    let remote = remote_files(stream, remote_dir)?;
+    let synced: HashMap<String, Synced> = local.into_iter()
+        .filter(|(path, file)| remote.get(path) == Some(&file.hash))
+        .map(|(path, file)| (path, Synced { hash: file.hash, size: file.size, modified: file.modified }))
+        .collect();
+
+    save_state(local_dir, remote_dir, &synced)?;
+
+    println!("Synced {}: {pushed} pushed, {pulled} pulled, {deleted} deleted, {} conflicts", local_dir.display(), conflicts.len());
+    for conflict in conflicts {
+        println!("Conflict: {conflict} changed on both sides, skipped");
+    }
+
+    Ok(())
+}
+
+/// Decide what to do with a file given its hash on both sides and the hash both sides had after the last sync
+fn decide(mode: &Mode, delete: bool, local: Option<&str>, remote: Option<&str>, synced: Option<&str>) -> Option<Action> {
+    if local == remote {
+        return None;
+    }
+
+    match mode {
+        Mode::Push => match local {
+            Some(_) => Some(Action::Push),
+            None if delete => Some(Action::DeleteRemote),
+            None => None,
+        },
+        Mode::Pull => match remote {
+            Some(_) => Some(Action::Pull),
+            None if delete => Some(Action::DeleteLocal),
+            None => None,
+        },
+        Mode::Both => match (local, remote) {
+            // Deleted on one side and unchanged on the other since the last sync
+            (Some(local), None) if delete && Some(local) == synced => Some(Action::DeleteLocal),
+            (None, Some(remote)) if delete && Some(remote) == synced => Some(Action::DeleteRemote),
+            (Some(_), None) => Some(Action::Push),
+            (None, Some(_)) => Some(Action::Pull),
+            // Changed on one side only
+            (Some(_), Some(remote)) if Some(remote) == synced => Some(Action::Push),
+            (Some(local), Some(_)) if Some(local) == synced => Some(Action::Pull),
+            _ => Some(Action::Conflict),
+        },
+    }
+}
+
+/// Returns an error if the server reported one for the file at path, otherwise returns the response
+fn check(response: Share, path: &str) -> Result<Share, Box<dyn std::error::Error>> {
+    match response.succeeded() {
+        true => Ok(response),
+        false => Err(format!("Failed to sync {path}: {}", response.server_text().unwrap_or_default()).into()),
+    }
+}
+
+/// Returns the path of a file on the server
+fn remote_path(remote_dir: &str, path: &str) -> String {
+    match remote_dir.is_empty() {
+        true => path.to_string(),
+        false => format!("{remote_dir}/{path}"),
+    }
+}
+
+/// Check a path from the server before it is joined to a local directory, absolute paths and paths with `..` would write outside of
+/// the directory
+pub fn check_local_path(path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let inside = Path::new(path).components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+
+    if path.is_empty() || !inside {
+        return Err(format!("Refusing to write outside of the local directory: {path}").into());
+    }
+
+    Ok(())
+}
+
+/// Returns the files in the remote directory, mapping their paths (relative to the directory) to their hash
+fn remote_files(
+    stream: &mut Throttled<Connection>,
+    remote_dir: &str,
+) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
+    let args = match remote_dir.is_empty() {
+        true => Vec::new(),
+        false => vec![remote_dir.to_string()],
+    };
+    let mut share = Share::new(ShareCommand::new(CommandType::Manifest, args), Location::Client);
+    let response = check(share.exchange(stream)?, remote_dir)?;
+    let mut files = HashMap::new();
+
+    // Every line is formated like `hash size path`
+    for line in response.text_data().unwrap_or_default().lines() {
+        let mut parts = line.splitn(3, ' ');
+
+        if let (Some(hash), Some(_), Some(path)) = (parts.next(), parts.next(), parts.next()) {
+            let path = match remote_dir.is_empty() {
+                true => path,
+                false => path.strip_prefix(&format!("{remote_dir}/")).unwrap_or(path),
+            };
+
+            // The path is joined to the local directory when the file is pulled
+            check_local_path(path)?;
+
+            files.insert(path.to_string(), hash.to_string());
+        }
+    }
+
+    Ok(files)
+}
+
+/// Returns the files in the local directory, mapping their paths (relative to the directory, `/` separated) to their size,
+/// modification time and hash. Files that did not change since the last sync are not hashed again
+fn local_files(dir: &Path, synced: &HashMap<String, Synced>) -> Result<HashMap<String, LocalFile>, Box<dyn std::error::Error>> {
+    let mut files = HashMap::new();
+    let mut directories: Vec<PathBuf> = vec![dir.to_path_buf()];
+
+    while let Some(directory) = directories.pop() {
+        for entry in fs::read_dir(&directory)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+
+            if metadata.is_dir() {
+                directories.push(entry.path());
+                continue;
+            }
+
+            let path = entry.path()
+                .strip_prefix(dir)?
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+
+            if path == STATE_FILE {
+                continue;
+            }
+
+            let size = metadata.len();
+            let modified = metadata.modified()?.duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_nanos() as u64);
+
+            let hash = match synced.get(&path) {
+                Some(synced) if synced.size == size && synced.modified == modified => synced.hash.clone(),
+                _ => hex::encode(Sha256::digest(fs::read(entry.path())?)),
+            };
+
+            files.insert(path, LocalFile { size, modified, hash });
+        }
+    }
+
+    Ok(files)
+}
+
+/// Load what was synced last time, nothing is known if the directory was last synced with another remote directory
+fn load_state(dir: &Path, remote_dir: &str) -> HashMap<String, Synced> {
+    let mut synced = HashMap::new();
+    let state = fs::read_to_string(dir.join(STATE_FILE)).unwrap_or_default();
+    let mut lines = state.lines();
+
+    // The first line is the remote directory, every other line is formated like `hash size modified path`
+    if lines.next() != Some(remote_dir) {
+        return synced;
+    }
+
+    for line in lines {
+        let mut parts = line.splitn(4, ' ');
+
+        if let (Some(hash), Some(size), Some(modified), Some(path)) = (parts.next(), parts.next(), parts.next(), parts.next()) {
+            if let (Ok(size), Ok(modified)) = (size.parse(), modified.parse()) {
+                synced.insert(path.to_string(), Synced { hash: hash.to_string(), size, modified });
+            }
+        }
+    }
+
+    synced
+}
+
+/// Save what was synced so the next sync can tell what changed
+fn save_state(dir: &Path, remote_dir: &str, synced: &HashMap<String, Synced>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut state = format!("{remote_dir}\n");
+
+    for (path, synced) in synced {
+        state.push_str(&format!("{} {} {} {path}\n", synced.hash, synced.size, synced.modified));
+    }
+
+    fs::write(dir.join(STATE_FILE), state)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_paths_stay_inside() {
+        for path in ["a.txt", "dir/a.txt", "./dir/a.txt"] {
+            assert!(check_local_path(path).is_ok(), "{path}");
+        }
+        for path in ["", "/etc/passwd", "../a.txt", "dir/../../a.txt"] {
+            assert!(check_local_path(path).is_err(), "{path}");
+        }
+    }
+
+    #[test]
+    fn actions_depend_on_which_side_changed() {
+        use Action::*;
+
+        // Mode, delete, local hash, remote hash, hash after the last sync, and what should happen
+        let table = [
+            // Same on both sides
+            (Mode::Both, true, Some("a"), Some("a"), Some("x"), None),
+            (Mode::Push, false, None, None, Some("a"), None),
+            // Only on the local side
+            (Mode::Push, false, Some("a"), None, None, Some(Push)),
+            (Mode::Pull, false, Some("a"), None, None, None),
+            (Mode::Pull, true, Some("a"), None, None, Some(DeleteLocal)),
+            (Mode::Both, false, Some("a"), None, None, Some(Push)),
+            // Only on the remote side
+            (Mode::Pull, false, None, Some("b"), None, Some(Pull)),
+            (Mode::Push, false, None, Some("b"), None, None),
+            (Mode::Push, true, None, Some("b"), None, Some(DeleteRemote)),
+            (Mode::Both, false, None, Some("b"), None, Some(Pull)),
+            // Changed on one side since the last sync
+            (Mode::Both, false, Some("a2"), Some("a"), Some("a"), Some(Push)),
+            (Mode::Both, false, Some("a"), Some("a2"), Some("a"), Some(Pull)),
+            (Mode::Push, false, Some("a"), Some("a2"), Some("a"), Some(Push)),
+            (Mode::Pull, false, Some("a2"), Some("a"), Some("a"), Some(Pull)),
+            // Changed on both sides
+            (Mode::Both, false, Some("a2"), Some("a3"), Some("a"), Some(Conflict)),
+            (Mode::Both, true, Some("a2"), Some("a3"), None, Some(Conflict)),
+            // Deleted on one side and unchanged on the other
+            (Mode::Both, true, Some("a"), None, Some("a"), Some(DeleteLocal)),
+            (Mode::Both, true, None, Some("a"), Some("a"), Some(DeleteRemote)),
+            // Deleted on one side, but changed on the other or not allowed to delete, the file is kept
+            (Mode::Both, true, Some("a2"), None, Some("a"), Some(Push)),
+            (Mode::Both, true, None, Some("a2"), Some("a"), Some(Pull)),
+            (Mode::Both, false, Some("a"), None, Some("a"), Some(Push)),
+            (Mode::Both, false, None, Some("a"), Some("a"), Some(Pull)),
+        ];
+
+        for (i, (mode, delete, local, remote, synced, action)) in table.into_iter().enumerate() {
+            assert_eq!(decide(&mode, delete, local, remote, synced), action, "row {i}");
+        }
+    }
+}
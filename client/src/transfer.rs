@@ -0,0 +1,266 @@
+use std::{
+    collections::VecDeque,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    thread,
+    time::Duration,
+};
+
+use glob::Pattern;
+
+use file_share::{config, connection::Connection, throttle::Throttled, CommandType, Location, Share, ShareCommand};
+
+use crate::sync;
+
+/// A single file to transfer
+struct Job {
+    /// Position of the file in the queue, the summary lists files in this order
+    index: usize,
+    /// Path of the file on this host
+    local: PathBuf,
+    /// Path of the file on the server
+    remote: String,
+    /// Amount of times the transfer was tried
+    attempts: usize,
+}
+
+/// The last attempt to transfer a file, holding the amount of bytes transferred or the reason it failed
+struct Outcome {
+    job: Job,
+    result: Result<u64, String>,
+}
+
+/// The servers the workers connect to and how they log in
+pub struct Servers<'a> {
+    pub config: &'a config::Client,
+    pub servers: &'a [String],
+    /// Index of the server the workers try first
+    pub active: usize,
+    /// The last successful LOGIN command, every worker sends it after connecting
+    pub login: Option<&'a str>,
+}
+
+/// Transfer many files at once, the commands are `MUPLOAD glob|@list [remote_dir]` and `MRECEIVE glob|@list [local_dir]`. The files
+/// are put in a queue that several workers take from, each over its own connection. Files that fail are put back in the queue until
+/// they run out of retries, and a summary of every file is printed at the end
+pub fn run(
+    command: &ShareCommand,
+    stream: &mut Throttled<Connection>,
+    servers: &Servers,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let command_type = command.command_type();
+    let jobs = match command_type {
+        CommandType::MUpload => upload_jobs(command)?,
+        _ => receive_jobs(command, stream)?,
+    };
+
+    if jobs.is_empty() {
+        return Err(format!("No files match {}", command.arg(0)).into());
+    }
+
+    let total = jobs.len();
+    let workers = servers.config.transfer_concurrency().min(total);
+    let queue = Mutex::new(VecDeque::from(jobs));
+    let outcomes = Mutex::new(Vec::new());
+
+    println!("Transferring {total} files over {workers} connections");
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| work(command_type, &queue, &outcomes, servers));
+        }
+    });
+
+    // Print the summary in the order the files were queued
+    let mut outcomes = outcomes.into_inner().unwrap();
+    outcomes.sort_by_key(|outcome| outcome.job.index);
+
+    let (mut transferred, mut bytes) = (0, 0);
+
+    for outcome in &outcomes {
+        match outcome.result.as_ref() {
+            Ok(size) => {
+                println!("OK     {} ({size} bytes)", outcome.job.remote);
+                transferred += 1;
+                bytes += size;
+            }
+            Err(error) => println!("FAILED {} after {} attempts: {error}", outcome.job.remote, outcome.job.attempts),
+        }
+    }
+
+    println!("Transferred {transferred} of {total} files ({bytes} bytes), {} failed", total - transferred);
+
+    Ok(())
+}
+
+/// Take files from the queue and transfer them until the queue is empty. The connection is opened when the first file is taken
+/// and opened again after a transfer fails
+fn work(command_type: &CommandType, queue: &Mutex<VecDeque<Job>>, outcomes: &Mutex<Vec<Outcome>>, servers: &Servers) {
+    let mut connection: Option<Throttled<Connection>> = None;
+
+    loop {
+        let Some(mut job) = queue.lock().unwrap().pop_front() else {
+            return;
+        };
+        job.attempts += 1;
+
+        let result = match connection.as_mut() {
+            Some(stream) => transfer(command_type, &job, stream),
+            None => open(servers).and_then(|stream| transfer(command_type, &job, connection.insert(stream))),
+        };
+
+        match result {
+            Ok(size) => outcomes.lock().unwrap().push(Outcome { job, result: Ok(size) }),
+            Err(error) => {
+                // The connection may be broken, start over with a new one
+                connection = None;
+
+                match job.attempts > servers.config.transfer_retries() {
+                    true => outcomes.lock().unwrap().push(Outcome { job, result: Err(error.to_string()) }),
+                    false => queue.lock().unwrap().push_back(job),
+                }
+            }
+        }
+    }
+}
+
+/// Open a connection for a worker and log in
+fn open(servers: &Servers) -> Result<Throttled<Connection>, Box<dyn std::error::Error>> {
+    let (mut stream, _) = super::connect(servers.config, servers.servers, servers.active)?;
+
+    if let Some(login) = servers.login {
+        super::log_in(&mut stream, login)?;
+    }
+
+    Ok(stream)
+}
+
+/// Transfer a single file, returns its size
+fn transfer(
+    command_type: &CommandType,
+    job: &Job,
+    stream: &mut Throttled<Connection>,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    match command_type {
+        CommandType::MUpload => {
+            let mut share = Share::new(ShareCommand::new(CommandType::Upload, vec![job.remote.clone()]), Location::Client);
+            let file = fs::read(&job.local)?;
+            let size = file.len() as u64;
+            share.load_file(file);
+
+            check(share.exchange(stream)?)?;
+
+            Ok(size)
+        }
+        _ => {
+            let mut share = Share::new(ShareCommand::new(CommandType::Receive, vec![job.remote.clone()]), Location::Client);
+            let file = check(share.exchange(stream)?)?.take_file().unwrap_or_default();
+
+            if let Some(parent) = job.local.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&job.local, &file)?;
+
+            Ok(file.len() as u64)
+        }
+    }
+}
+
+/// Returns an error if the server reported one, otherwise returns the response. If the server blocked the command this waits as long
+/// as it asked first, so the retry is not blocked too
+fn check(response: Share) -> Result<Share, Box<dyn std::error::Error>> {
+    if let Some(retry_after) = response.retry_after() {
+        thread::sleep(Duration::from_secs(retry_after));
+    }
+
+    match response.succeeded() {
+        true => Ok(response),
+        false => Err(response.server_text().unwrap_or_default().into()),
+    }
+}
+
+/// Returns the files to upload, either the files matching a glob or the files listed (one per line) in the file after `@`
+fn upload_jobs(command: &ShareCommand) -> Result<Vec<Job>, Box<dyn std::error::Error>> {
+    let remote_dir = command.args().get(1).map_or("", |dir| dir.trim_start_matches("./").trim_matches('/'));
+
+    let files: Vec<PathBuf> = match command.arg(0).strip_prefix('@') {
+        Some(list) => read_list(list)?.into_iter().map(PathBuf::from).collect(),
+        None => glob::glob(command.arg(0))?
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .filter(|path| path.is_file())
+            .collect(),
+    };
+
+    Ok(files.into_iter()
+        .enumerate()
+        .map(|(index, local)| {
+            let path = slash_path(&local);
+            let remote = match remote_dir.is_empty() || remote_dir == "." {
+                true => path,
+                false => format!("{remote_dir}/{path}"),
+            };
+
+            Job { index, local, remote, attempts: 0 }
+        })
+        .collect())
+}
+
+/// Returns the files to receive, either the files on the server matching a glob or the files listed (one per line) in the file
+/// after `@`
+fn receive_jobs(
+    command: &ShareCommand,
+    stream: &mut Throttled<Connection>,
+) -> Result<Vec<Job>, Box<dyn std::error::Error>> {
+    let local_dir = Path::new(command.args().get(1).map_or(".", String::as_str));
+
+    let files = match command.arg(0).strip_prefix('@') {
+        Some(list) => read_list(list)?,
+        None => {
+            let pattern = Pattern::new(command.arg(0).trim_start_matches("./"))?;
+            let mut share = Share::new(ShareCommand::new(CommandType::Catalog, Vec::new()), Location::Client);
+            let catalog = check(share.exchange(stream)?)?;
+
+            // The catalog has one file per line, formated like `./path`
+            catalog.text_data()
+                .unwrap_or_default()
+                .lines()
+                .map(|line| line.trim_start_matches("./").to_string())
+                .filter(|path| pattern.matches(path))
+                .collect()
+        }
+    };
+
+    let mut jobs = Vec::new();
+
+    for (index, remote) in files.into_iter().enumerate() {
+        let remote = remote.trim_start_matches("./").trim_start_matches('/').to_string();
+
+        // The path is joined to the local directory, the whole transfer is refused before anything is written if one would leave it
+        sync::check_local_path(&remote)?;
+
+        jobs.push(Job { index, local: local_dir.join(&remote), remote, attempts: 0 });
+    }
+
+    Ok(jobs)
+}
+
+/// Returns the paths listed in a file, one per line
+fn read_list(list: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    Ok(fs::read_to_string(list)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+/// Returns a local path as a `/` separated path without a leading `./`
+fn slash_path(path: &Path) -> String {
+    path.components()
+        .filter(|component| component.as_os_str() != ".")
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
@@ -0,0 +1,190 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver},
+    thread,
+    time::{Duration, Instant},
+};
+
+use glob::Pattern;
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+
+use file_share::{config, connection::Connection, throttle::Throttled, CommandType, Location, Share, ShareCommand};
+
+/// How often the watcher checks for events and files that are ready to upload
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Watches a directory and the directories inside it
+struct Watcher {
+    inotify: Inotify,
+    /// Map of watches to the directory they watch
+    directories: HashMap<WatchDescriptor, PathBuf>,
+}
+
+/// Upload the files in a local directory to a directory on the server as they are created or changed, the command is
+/// `WATCH local_dir [remote_dir]`. A file is uploaded once it has gone unchanged for the debounce time, and files matching the
+/// ignore patterns are never uploaded. Watching stops when a line is read from lines (enter is pressed) or stdin is closed
+pub fn run(
+    command: &ShareCommand,
+    stream: &mut Throttled<Connection>,
+    config: &config::Client,
+    lines: &Receiver<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let args = command.args();
+    let local_dir = Path::new(&args[0]);
+    let remote_dir = args.get(1).map_or("", |dir| dir.trim_start_matches("./").trim_matches('/'));
+    let remote_dir = if remote_dir == "." { "" } else { remote_dir };
+
+    if !local_dir.is_dir() {
+        return Err(format!("{} is not a directory", local_dir.display()).into());
+    }
+
+    let ignore = config.watch_ignore()
+        .iter()
+        .map(|pattern| Pattern::new(pattern).map_err(|error| format!("Invalid watch ignore pattern {pattern}: {error}")))
+        .collect::<Result<Vec<_>, _>>()?;
+    let debounce = Duration::from_millis(config.watch_debounce());
+
+    let mut watcher = Watcher {
+        inotify: Inotify::init()?,
+        directories: HashMap::new(),
+    };
+    watcher.add(local_dir)?;
+
+    println!("Watching {}, press enter to stop", local_dir.display());
+
+    // Map of files waiting to be uploaded to the last time they changed
+    let mut pending: HashMap<PathBuf, Instant> = HashMap::new();
+    let mut buffer = [0; 4096];
+    let mut uploaded = 0;
+
+    while lines.try_recv() == Err(mpsc::TryRecvError::Empty) {
+        for path in watcher.changed(&mut buffer)? {
+            pending.insert(path, Instant::now());
+        }
+
+        let ready: Vec<PathBuf> = pending.iter()
+            .filter(|(_, changed)| changed.elapsed() >= debounce)
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        for path in ready {
+            pending.remove(&path);
+
+            let relative = relative_path(local_dir, &path);
+            if !path.is_file() || ignore.iter().any(|pattern| pattern.matches(&relative)) {
+                continue;
+            }
+
+            let remote_path = match remote_dir.is_empty() {
+                true => relative.clone(),
+                false => format!("{remote_dir}/{relative}"),
+            };
+
+            // The file may be removed or become unreadable before it is uploaded, that only skips the file
+            let data = match fs::read(&path) {
+                Ok(data) => data,
+                Err(error) => {
+                    eprintln!("Failed to read {relative}: {error}");
+                    continue;
+                }
+            };
+
+            let mut share = Share::new(ShareCommand::new(CommandType::Upload, vec![remote_path]), Location::Client);
+            share.load_file(data);
+
+            let response = share.exchange(stream)?;
+            match (response.succeeded(), response.retry_after()) {
+                (true, _) => {
+                    println!("Uploaded {relative}");
+                    uploaded += 1;
+                }
+                // Try again once the server lets us
+                (false, Some(retry_after)) => {
+                    eprintln!("{}", response.server_text().unwrap_or_default());
+                    pending.insert(path, Instant::now() + Duration::from_secs(retry_after));
+                }
+                (false, None) => eprintln!("Failed to upload {relative}: {}", response.server_text().unwrap_or_default()),
+            }
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    println!("Stopped watching {}, {uploaded} files uploaded", local_dir.display());
+
+    Ok(())
+}
+
+impl Watcher {
+    /// Watch a directory and every directory inside it, returns the files already inside them
+    fn add(&mut self, dir: &Path) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+        let mask = WatchMask::CREATE | WatchMask::MODIFY | WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO;
+        let mut files = Vec::new();
+        let mut directories = vec![dir.to_path_buf()];
+
+        while let Some(directory) = directories.pop() {
+            let watch = self.inotify.watches().add(&directory, mask)?;
+            self.directories.insert(watch, directory.clone());
+
+            for entry in fs::read_dir(&directory)? {
+                let entry = entry?;
+
+                match entry.file_type()?.is_dir() {
+                    true => directories.push(entry.path()),
+                    false => files.push(entry.path()),
+                }
+            }
+        }
+
+        Ok(files)
+    }
+    /// Returns the files that changed since the last call. New directories are watched too, the files created in them before the
+    /// watch was added count as changed
+    fn changed(&mut self, buffer: &mut [u8]) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+        let mut changed = Vec::new();
+        let mut new_directories = Vec::new();
+
+        loop {
+            let events = match self.inotify.read_events(buffer) {
+                Ok(events) => events,
+                Err(error) if error.kind() == ErrorKind::WouldBlock => break,
+                Err(error) => return Err(error.into()),
+            };
+
+            for event in events {
+                let (Some(directory), Some(name)) = (self.directories.get(&event.wd), event.name) else {
+                    continue;
+                };
+                let path = directory.join(name);
+
+                match event.mask.contains(EventMask::ISDIR) {
+                    true if event.mask.intersects(EventMask::CREATE | EventMask::MOVED_TO) => new_directories.push(path),
+                    true => (),
+                    false => changed.push(path),
+                }
+            }
+        }
+
+        for directory in new_directories {
+            // The directory may already be gone again
+            if directory.is_dir() {
+                changed.extend(self.add(&directory)?);
+            }
+        }
+
+        Ok(changed)
+    }
+}
+
+/// Returns the path of a file relative to the watched directory, `/` separated
+fn relative_path(dir: &Path, path: &Path) -> String {
+    path.strip_prefix(dir)
+        .unwrap_or(path)
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
@@ -0,0 +1,167 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom},
+    ffi::CString,
+    mem::MaybeUninit,
+    os::unix::ffi::OsStrExt,
+    path::{Component, Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use super::{Metadata, StorageBackend};
+
+/// Stores files in a directory on the local filesystem
+pub struct LocalBackend {
+    root: PathBuf,
+}
+
+impl LocalBackend {
+    /// Create a new LocalBackend that stores files inside root
+    pub fn new(root: impl Into<PathBuf>) -> LocalBackend {
+        LocalBackend { root: root.into() }
+    }
+    /// Returns where path is stored, paths that would leave the root (absolute paths or paths with `..`) are rejected
+    fn resolve(&self, path: &str) -> io::Result<PathBuf> {
+        if Path::new(path).components().any(|component| !matches!(component, Component::Normal(_) | Component::CurDir)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidFilename, format!("Invalid path: {path}")));
+        }
+
+        Ok(self.root.join(path))
+    }
+    /// Recursively collect the paths of all files inside dir, relative to the root
+    fn walk(&self, dir: &Path, paths: &mut Vec<String>) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+
+            if entry.file_type()?.is_dir() {
+                self.walk(&entry.path(), paths)?;
+            } else if let Ok(path) = entry.path().strip_prefix(&self.root) {
+                paths.push(path.to_string_lossy().replace('\\', "/"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl StorageBackend for LocalBackend {
+    fn open(&self, path: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.resolve(path)?)
+    }
+    fn reader(&self, path: &str, offset: u64) -> io::Result<Box<dyn Read + Send>> {
+        let mut file = File::open(self.resolve(path)?)?;
+        file.seek(SeekFrom::Start(offset))?;
+
+        Ok(Box::new(file))
+    }
+    fn create(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        let file = self.resolve(path)?;
+
+        if let Some(parent) = file.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(file, data)?;
+
+        Ok(())
+    }
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        // Only walk the directory the prefix is in, instead of the whole root
+        let dir = match prefix.rfind('/') {
+            Some(i) => self.resolve(&prefix[..i])?,
+            None => self.root.clone(),
+        };
+        let mut paths = Vec::new();
+
+        if dir.is_dir() {
+            self.walk(&dir, &mut paths)?;
+        }
+
+        paths.retain(|path| path.starts_with(prefix));
+        paths.sort();
+
+        Ok(paths)
+    }
+    fn list_dir(&self, dir: &str) -> io::Result<(Vec<String>, Vec<String>)> {
+        let path = self.resolve(dir)?;
+        let mut files = Vec::new();
+        let mut dirs = Vec::new();
+
+        if !path.is_dir() {
+            return Ok((files, dirs));
+        }
+
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let child = match dir.is_empty() {
+                true => name,
+                false => format!("{dir}/{name}"),
+            };
+
+            if entry.file_type()?.is_dir() {
+                dirs.push(child);
+            } else {
+                files.push(child);
+            }
+        }
+
+        files.sort();
+        dirs.sort();
+
+        Ok((files, dirs))
+    }
+    fn stat(&self, path: &str) -> io::Result<Option<Metadata>> {
+        let metadata = match fs::metadata(self.resolve(path)?) {
+            Ok(metadata) if metadata.is_file() => metadata,
+            Ok(_) => return Ok(None),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error),
+        };
+
+        Ok(Some(Metadata {
+            size: metadata.len(),
+            modified: metadata.modified()?.duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs()),
+        }))
+    }
+    fn delete(&self, path: &str) -> io::Result<()> {
+        fs::remove_file(self.resolve(path)?)
+    }
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let to = self.resolve(to)?;
+
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(self.resolve(from)?, to)?;
+
+        Ok(())
+    }
+    fn available_space(&self) -> io::Result<Option<u64>> {
+        let root = CString::new(self.root.as_os_str().as_bytes())?;
+        let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+
+        // SAFETY: root is a valid nul terminated string and stat is only read after statvfs initialized it
+        if unsafe { libc::statvfs(root.as_ptr(), stat.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let stat = unsafe { stat.assume_init() };
+
+        #[allow(clippy::unnecessary_cast)]
+        Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_leaving_the_root_are_rejected() {
+        let backend = LocalBackend::new("/nonexistent");
+
+        for path in ["../etc/passwd", "a/../../b", "/etc/passwd"] {
+            assert_eq!(backend.open(path).unwrap_err().kind(), io::ErrorKind::InvalidFilename, "{path}");
+            assert!(backend.create(path, b"").is_err(), "{path}");
+        }
+    }
+}
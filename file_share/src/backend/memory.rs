@@ -0,0 +1,117 @@
+use std::{collections::BTreeMap, io, sync::Mutex};
+
+use super::{Metadata, StorageBackend};
+use crate::storage::now;
+
+/// Stores files in memory, everything is lost when the server stops. This is mostly useful for testing
+#[derive(Default)]
+pub struct MemoryBackend {
+    /// Map of paths to the file data and the time the file was last modified
+    files: Mutex<BTreeMap<String, (Vec<u8>, u64)>>,
+}
+
+impl MemoryBackend {
+    /// Create a new empty MemoryBackend
+    pub fn new() -> MemoryBackend {
+        MemoryBackend::default()
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn open(&self, path: &str) -> io::Result<Vec<u8>> {
+        match self.files.lock().unwrap().get(path) {
+            Some((data, _)) => Ok(data.clone()),
+            None => Err(not_found(path)),
+        }
+    }
+    fn create(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        self.files.lock().unwrap().insert(path.to_string(), (data.to_vec(), now()));
+
+        Ok(())
+    }
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        Ok(
+            self.files.lock().unwrap()
+                .keys()
+                .filter(|path| path.starts_with(prefix))
+                .cloned()
+                .collect()
+        )
+    }
+    fn stat(&self, path: &str) -> io::Result<Option<Metadata>> {
+        Ok(
+            self.files.lock().unwrap()
+                .get(path)
+                .map(|(data, modified)| Metadata { size: data.len() as u64, modified: *modified })
+        )
+    }
+    fn delete(&self, path: &str) -> io::Result<()> {
+        match self.files.lock().unwrap().remove(path) {
+            Some(_) => Ok(()),
+            None => Err(not_found(path)),
+        }
+    }
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let file = files.remove(from).ok_or_else(|| not_found(from))?;
+
+        files.insert(to.to_string(), file);
+
+        Ok(())
+    }
+}
+
+/// Returns the error for a file that does not exist
+fn not_found(path: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{path} does not exist"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn create_open_and_stat() {
+        let backend = MemoryBackend::new();
+
+        backend.create("dir/a.txt", b"hello").unwrap();
+
+        assert_eq!(backend.open("dir/a.txt").unwrap(), b"hello");
+        assert_eq!(backend.stat("dir/a.txt").unwrap().map(|metadata| metadata.size), Some(5));
+        assert_eq!(backend.stat("dir/b.txt").unwrap(), None);
+
+        // Creating an existing file replaces it
+        backend.create("dir/a.txt", b"hi").unwrap();
+        assert_eq!(backend.open("dir/a.txt").unwrap(), b"hi");
+    }
+
+    #[test]
+    fn list_by_prefix() {
+        let backend = MemoryBackend::new();
+
+        for path in ["b.txt", "dir/a.txt", "dir/sub/c.txt", "directory.txt"] {
+            backend.create(path, b"").unwrap();
+        }
+
+        assert_eq!(backend.list("").unwrap(), ["b.txt", "dir/a.txt", "dir/sub/c.txt", "directory.txt"]);
+        assert_eq!(backend.list("dir/").unwrap(), ["dir/a.txt", "dir/sub/c.txt"]);
+        assert!(backend.list("none/").unwrap().is_empty());
+    }
+
+    #[test]
+    fn delete_and_rename() {
+        let backend = MemoryBackend::new();
+
+        backend.create("a.txt", b"data").unwrap();
+        backend.rename("a.txt", "b.txt").unwrap();
+
+        assert_eq!(backend.stat("a.txt").unwrap(), None);
+        assert_eq!(backend.open("b.txt").unwrap(), b"data");
+
+        backend.delete("b.txt").unwrap();
+
+        assert!(backend.list("").unwrap().is_empty());
+        assert_eq!(backend.open("b.txt").unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(backend.delete("b.txt").is_err());
+    }
+}
@@ -0,0 +1,91 @@
+use std::io::{self, Cursor, Read};
+
+use crate::{config, FileShareError};
+
+mod local;
+mod memory;
+mod s3;
+
+pub use local::LocalBackend;
+pub use memory::MemoryBackend;
+pub use s3::S3Backend;
+
+/// Metadata of a file stored in a StorageBackend
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Metadata {
+    /// Size of the file in bytes
+    pub size: u64,
+    /// Time (in seconds since the unix epoch) the file was last modified
+    pub modified: u64,
+}
+
+/// The file operations the server performs, implemented for every place the server can store files in. Paths are `/` separated
+/// and relative to the root of the backend
+pub trait StorageBackend: Send + Sync {
+    /// Read the whole file at path into memory
+    fn open(&self, path: &str) -> io::Result<Vec<u8>>;
+    /// Open the file at path for reading, starting offset bytes into it. Backends that can read files without loading them into
+    /// memory should override this
+    fn reader(&self, path: &str, offset: u64) -> io::Result<Box<dyn Read + Send>> {
+        let mut cursor = Cursor::new(self.open(path)?);
+        cursor.set_position(offset);
+
+        Ok(Box::new(cursor))
+    }
+    /// Create the file at path containing data, replacing the file if it already exists
+    fn create(&self, path: &str, data: &[u8]) -> io::Result<()>;
+    /// Returns the paths of all files (including files inside directories) that start with prefix
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>>;
+    /// Returns the paths of the files and the directories directly inside the directory dir (the root if dir is empty). Backends that
+    /// can list a directory without listing everything inside it should override this
+    fn list_dir(&self, dir: &str) -> io::Result<(Vec<String>, Vec<String>)> {
+        let prefix = match dir.is_empty() {
+            true => String::new(),
+            false => format!("{dir}/"),
+        };
+        let mut files = Vec::new();
+        let mut dirs = Vec::new();
+
+        for path in self.list(&prefix)? {
+            match path[prefix.len()..].split_once('/') {
+                Some((name, _)) => dirs.push(format!("{prefix}{name}")),
+                None => files.push(path),
+            }
+        }
+
+        dirs.sort();
+        dirs.dedup();
+
+        Ok((files, dirs))
+    }
+    /// Returns the metadata of the file at path, or None if it does not exist
+    fn stat(&self, path: &str) -> io::Result<Option<Metadata>>;
+    /// Delete the file at path
+    fn delete(&self, path: &str) -> io::Result<()>;
+    /// Move the file at from to to, backends that can move files without copying them should override this
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let data = self.open(from)?;
+
+        self.create(to, &data)?;
+        self.delete(from)
+    }
+    /// Returns the amount of bytes that can still be stored, or None if the backend has no meaningful limit or cant tell
+    fn available_space(&self) -> io::Result<Option<u64>> {
+        Ok(None)
+    }
+}
+
+/// Create the StorageBackend selected in the server configuration
+pub fn build(config: &config::Server) -> Result<Box<dyn StorageBackend>, FileShareError> {
+    match config.storage() {
+        "local" => Ok(Box::new(LocalBackend::new(config.root()))),
+        "memory" => Ok(Box::new(MemoryBackend::new())),
+        "s3" => {
+            let s3 = config.s3()
+                .ok_or(FileShareError::Config(String::from("S3 storage selected but the s3 configuration is empty")))?;
+
+            Ok(Box::new(S3Backend::new(s3)))
+        }
+        unknown => Err(FileShareError::Config(format!("Unknown storage backend: {unknown}"))),
+    }
+}
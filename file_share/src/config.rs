@@ -1,7 +1,9 @@
-use std::fs;
+use std::{fs, collections::HashMap};
 
 use serde::Deserialize;
 
+use crate::{discovery, FileShareError};
+
 #[derive(Deserialize)]
 pub struct Config {
     pub server: Option<Server>,
@@ -12,39 +14,122 @@ pub struct Config {
 pub struct Server {
     thread_count: usize,
     ips: Vec<String>,
+    listen_all: Option<bool>,
+    listeners: Option<Vec<Listener>>,
+    unix_socket: Option<String>,
+    http: Option<String>,
+    web_ui: Option<bool>,
+    webdav: Option<String>,
+
+    link_secret: Option<String>,
+    link_ttl: Option<u64>,
+
+    delta: Option<bool>,
 
     max_share_size_without_file: Option<u64>,
     max_file_size: Option<u64>,
 
     return_on_success: Option<String>,
     return_on_help: Option<String>,
+
+    version_count: Option<usize>,
+    version_age: Option<u64>,
+
+    trash_age: Option<u64>,
+    purge_interval: Option<u64>,
+
+    storage: Option<String>,
+    root: Option<String>,
+    s3: Option<S3>,
+
+    dedup: Option<bool>,
+
+    users: Option<HashMap<String, String>>,
+    quota: Option<u64>,
+    user_quota: Option<u64>,
+    user_quotas: Option<HashMap<String, u64>>,
+    admins: Option<Vec<String>>,
+
+    bandwidth_limit: Option<u64>,
+    client_bandwidth_limit: Option<u64>,
+
+    rate_limit: Option<u64>,
+    user_rate_limit: Option<u64>,
+    max_login_failures: Option<u64>,
+    ban_time: Option<u64>,
+
+    name: Option<String>,
+    discovery: Option<bool>,
+    discovery_port: Option<u16>,
+}
+
+#[derive(Deserialize, PartialEq)]
+pub struct Listener {
+    address: String,
+
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
+
+    require_login: Option<bool>,
+    allowed_users: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, PartialEq)]
+pub struct S3 {
+    endpoint: String,
+    bucket: String,
+    region: Option<String>,
+
+    access_key: String,
+    secret_key: String,
 }
 
 #[derive(Deserialize, PartialEq)]
 pub struct Client {
-    server: String,
+    server: Option<String>,
+    servers: Option<Vec<String>>,
 
     retry_delay: u64,
     retry_amount: usize,
+    retry_backoff: Option<String>,
+    retry_max_wait: Option<u64>,
+
+    discovery_port: Option<u16>,
+    discovery_timeout: Option<u64>,
+
+    watch_debounce: Option<u64>,
+    watch_ignore: Option<Vec<String>>,
+
+    transfer_concurrency: Option<usize>,
+    transfer_retries: Option<usize>,
+
+    multiplex: Option<bool>,
+
+    bandwidth_limit: Option<u64>,
+
+    tls: Option<bool>,
+    tls_ca: Option<String>,
 }
 
 impl Config {
-    pub fn build(path: &str) -> Result<Config, Box<dyn std::error::Error>> {
-        Ok(toml::from_str(&fs::read_to_string(path)?)?)
+    pub fn build(path: &str) -> Result<Config, FileShareError> {
+        let config = fs::read_to_string(path).map_err(|error| FileShareError::Config(format!("Failed to read {path}: {error}")))?;
+
+        toml::from_str(&config).map_err(|error| FileShareError::Config(format!("Invalid {path}: {error}")))
     }
-    pub fn server(self) -> Result<Server, Box<dyn std::error::Error>> {
+    pub fn server(self) -> Result<Server, FileShareError> {
         if let Some(server) = self.server {
             return Ok(server);
         } 
 
-        Err("Server configuration is empty".into())
+        Err(FileShareError::Config(String::from("Server configuration is empty")))
     }
-    pub fn client(self) -> Result<Client, Box<dyn std::error::Error>> {
+    pub fn client(self) -> Result<Client, FileShareError> {
         if let Some(client) = self.client {
             return Ok(client);
         } 
 
-        Err("Client configuration is empty".into())
+        Err(FileShareError::Config(String::from("Client configuration is empty")))
     }
 }
 
@@ -58,11 +143,187 @@ impl Server {
     pub fn ip_backups(&self) -> &Vec<String> {
         &self.ips
     }
+    /// Returns true if every address in ips should be listened on at once, instead of treating them as backups. Defaults to false
+    pub fn listen_all(&self) -> bool {
+        self.listen_all.unwrap_or(false)
+    }
+    /// Returns the settings of the listener for the given address (as written in ips), or None if it has no settings
+    pub fn listener(&self, address: &str) -> Option<&Listener> {
+        self.listeners.as_ref()?.iter().find(|listener| listener.address == address)
+    }
+    /// Returns the path of the Unix socket to listen on for local clients, or None if the server should not listen on one
+    pub fn unix_socket(&self) -> Option<&str> {
+        self.unix_socket.as_deref()
+    }
+    /// Returns the address the HTTP gateway listens on, or None if the gateway is disabled
+    pub fn http(&self) -> Option<&str> {
+        self.http.as_deref()
+    }
+    /// Returns true if the HTTP gateway should serve the web UI at `/`, defaults to false
+    pub fn web_ui(&self) -> bool {
+        self.web_ui.unwrap_or(false)
+    }
+    /// Returns the address the WebDAV frontend listens on, or None if the frontend is disabled
+    pub fn webdav(&self) -> Option<&str> {
+        self.webdav.as_deref()
+    }
+    /// Returns the secret download links are signed with, or None if the server should generate one
+    pub fn link_secret(&self) -> Option<&str> {
+        self.link_secret.as_deref()
+    }
+    /// Returns how long (in seconds) download links work for when created without a ttl, defaults to a day
+    pub fn link_ttl(&self) -> u64 {
+        self.link_ttl.unwrap_or(86400)
+    }
+    /// Returns true if clients should send only the changed blocks when replacing files, defaults to true
+    pub fn delta(&self) -> bool {
+        self.delta.unwrap_or(true)
+    }
+    pub fn version_count(&self) -> Option<usize> {
+        self.version_count
+    }
+    pub fn version_age(&self) -> Option<u64> {
+        self.version_age
+    }
+    pub fn trash_age(&self) -> Option<u64> {
+        self.trash_age
+    }
+    /// Returns the interval (in seconds) between purges of the trash and versions store, defaults to an hour
+    pub fn purge_interval(&self) -> u64 {
+        self.purge_interval.unwrap_or(3600)
+    }
+    /// Returns the name of the storage backend files are stored in, defaults to `local`
+    pub fn storage(&self) -> &str {
+        self.storage.as_deref().unwrap_or("local")
+    }
+    /// Returns the directory the local storage backend stores files in, defaults to the current directory
+    pub fn root(&self) -> &str {
+        self.root.as_deref().unwrap_or(".")
+    }
+    pub fn s3(&self) -> Option<&S3> {
+        self.s3.as_ref()
+    }
+    /// Returns true if uploads should be stored once per unique content, defaults to false
+    pub fn dedup(&self) -> bool {
+        self.dedup.unwrap_or(false)
+    }
+    pub fn max_file_size(&self) -> Option<u64> {
+        self.max_file_size
+    }
+    /// Returns the largest size of a share without its file (the command, hashes and instructions of a delta), defaults to 1MB
+    pub fn max_share_size_without_file(&self) -> u64 {
+        self.max_share_size_without_file.unwrap_or(1_000_000)
+    }
+    /// Returns the largest share the server reads from a client, a share carries at most one file so this is the largest file that
+    /// may be stored plus the rest of the share. None if neither the file size nor the quota is limited
+    pub fn max_share_size(&self) -> Option<u64> {
+        let max_file_size = self.max_file_size.into_iter().chain(self.quota).min()?;
+
+        Some(max_file_size.saturating_add(self.max_share_size_without_file()))
+    }
+    /// Returns the password of the user with the given name, or None if there is no such user
+    pub fn password(&self, user: &str) -> Option<&str> {
+        self.users.as_ref()?.get(user).map(String::as_str)
+    }
+    /// Returns the amount of bytes all users together may store
+    pub fn quota(&self) -> Option<u64> {
+        self.quota
+    }
+    /// Returns the amount of bytes the given user may store, users without their own quota use the default user quota
+    pub fn user_quota(&self, user: &str) -> Option<u64> {
+        self.user_quotas.as_ref()
+            .and_then(|quotas| quotas.get(user).copied())
+            .or(self.user_quota)
+    }
+    /// Returns true if the user may run admin commands
+    pub fn is_admin(&self, user: &str) -> bool {
+        self.admins.as_ref().is_some_and(|admins| admins.iter().any(|admin| admin == user))
+    }
+    /// Returns the amount of bytes per second all connections together may transfer, 0 (the default) means unlimited
+    pub fn bandwidth_limit(&self) -> u64 {
+        self.bandwidth_limit.unwrap_or(0)
+    }
+    /// Returns the amount of bytes per second a single connection may transfer, 0 (the default) means unlimited
+    pub fn client_bandwidth_limit(&self) -> u64 {
+        self.client_bandwidth_limit.unwrap_or(0)
+    }
+    /// Returns the amount of commands a single ip may send per minute, defaults to 600, 0 means unlimited
+    pub fn rate_limit(&self) -> u64 {
+        self.rate_limit.unwrap_or(600)
+    }
+    /// Returns the amount of commands a single user may send per minute over all their connections, defaults to 600, 0 means
+    /// unlimited
+    pub fn user_rate_limit(&self) -> u64 {
+        self.user_rate_limit.unwrap_or(600)
+    }
+    /// Returns the amount of failed logins after which the ip and user name are banned, defaults to 5, 0 means never
+    pub fn max_login_failures(&self) -> u64 {
+        self.max_login_failures.unwrap_or(5)
+    }
+    /// Returns the time (in seconds) failed logins are counted for and bans last, defaults to 300
+    pub fn ban_time(&self) -> u64 {
+        self.ban_time.unwrap_or(300)
+    }
+    /// Returns the name clients see when discovering the server, defaults to `file_share`
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or("file_share")
+    }
+    /// Returns true if the server should reply to discovery requests, defaults to false so servers are only announced on purpose
+    pub fn discovery(&self) -> bool {
+        self.discovery.unwrap_or(false)
+    }
+    pub fn discovery_port(&self) -> u16 {
+        self.discovery_port.unwrap_or(discovery::DISCOVERY_PORT)
+    }
+}
+
+impl Listener {
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+    /// Returns the paths of the certificate chain and private key, or None if the listener does not use TLS
+    pub fn tls(&self) -> Option<(&str, &str)> {
+        Some((self.tls_cert.as_deref()?, self.tls_key.as_deref()?))
+    }
+    /// Returns true if clients must log in before running any other command, defaults to false
+    pub fn require_login(&self) -> bool {
+        self.require_login.unwrap_or(false)
+    }
+    /// Returns the users that may log in on the listener, or None if every user may
+    pub fn allowed_users(&self) -> Option<&Vec<String>> {
+        self.allowed_users.as_ref()
+    }
+}
+
+impl S3 {
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+    pub fn bucket(&self) -> &str {
+        &self.bucket
+    }
+    /// Returns the region of the bucket, defaults to `us-east-1` which most S3 compatible stores accept
+    pub fn region(&self) -> &str {
+        self.region.as_deref().unwrap_or("us-east-1")
+    }
+    pub fn access_key(&self) -> &str {
+        &self.access_key
+    }
+    pub fn secret_key(&self) -> &str {
+        &self.secret_key
+    }
 }
 
 impl Client {
-    pub fn server(&self) -> &str {
-        &self.server
+    pub fn server(&self) -> Option<&str> {
+        self.server.as_deref()
+    }
+    /// Returns every server to connect to in order, server comes first followed by the servers list
+    pub fn servers(&self) -> Vec<String> {
+        self.server.iter()
+            .chain(self.servers.iter().flatten())
+            .cloned()
+            .collect()
     }
     pub fn retry_amount(&self) -> usize {
         self.retry_amount - 1
@@ -70,4 +331,50 @@ impl Client {
     pub fn retry_delay(&self) -> u64 {
         self.retry_delay
     }
+    /// Returns how the delay between connection attempts grows: `fixed`, `exponential` or `jitter`, defaults to `fixed`
+    pub fn retry_backoff(&self) -> &str {
+        self.retry_backoff.as_deref().unwrap_or("fixed")
+    }
+    /// Returns the most time (in milliseconds) to spend waiting between connection attempts, or None if there is no limit
+    pub fn retry_max_wait(&self) -> Option<u64> {
+        self.retry_max_wait
+    }
+    pub fn discovery_port(&self) -> u16 {
+        self.discovery_port.unwrap_or(discovery::DISCOVERY_PORT)
+    }
+    /// Returns how long (in milliseconds) to wait for servers to reply to a discovery request, defaults to a second
+    pub fn discovery_timeout(&self) -> u64 {
+        self.discovery_timeout.unwrap_or(1000)
+    }
+    /// Returns how long (in milliseconds) a watched file must go unchanged before it is uploaded, defaults to 500
+    pub fn watch_debounce(&self) -> u64 {
+        self.watch_debounce.unwrap_or(500)
+    }
+    /// Returns the glob patterns of paths (relative to the watched directory) that are not uploaded when watching
+    pub fn watch_ignore(&self) -> &[String] {
+        self.watch_ignore.as_deref().unwrap_or_default()
+    }
+    /// Returns the amount of connections MUPLOAD and MRECEIVE transfer files over at once, defaults to 4
+    pub fn transfer_concurrency(&self) -> usize {
+        self.transfer_concurrency.unwrap_or(4).max(1)
+    }
+    /// Returns how many more times MUPLOAD and MRECEIVE try a file that failed to transfer, defaults to 2
+    pub fn transfer_retries(&self) -> usize {
+        self.transfer_retries.unwrap_or(2)
+    }
+    /// Returns true if commands should run in the background over one multiplexed connection, defaults to false
+    pub fn multiplex(&self) -> bool {
+        self.multiplex.unwrap_or(false)
+    }
+    /// Returns the amount of bytes per second a single connection may transfer, 0 (the default) means unlimited
+    pub fn bandwidth_limit(&self) -> u64 {
+        self.bandwidth_limit.unwrap_or(0)
+    }
+    /// Returns the path of the PEM file containing the certificates to trust, or None if the connection should not use TLS
+    pub fn tls(&self) -> Option<&str> {
+        match self.tls {
+            Some(true) => Some(self.tls_ca.as_deref().unwrap_or("ca.pem")),
+            _ => None,
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,254 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    mem,
+    net::{Shutdown, TcpStream},
+    os::{fd::AsRawFd, unix::net::UnixStream},
+    sync::mpsc::{self, Receiver, Sender},
+};
+
+use rustls::{ClientConnection, ServerConnection, StreamOwned};
+
+/// A stream shares can be sent over, anything that can be read from and written to is a transport (TCP, TLS, Unix sockets, pipes,
+/// etc.)
+pub trait Transport: Read + Write {}
+
+impl<T: Read + Write> Transport for T {}
+
+/// One end of an in-memory connection created with pipe, data written to one end is read from the other. Reading returns end of
+/// file once the other end is dropped
+pub struct Pipe {
+    sender: Sender<Vec<u8>>,
+    receiver: Receiver<Vec<u8>>,
+    /// Data received from the other end that has not been read yet
+    buffer: VecDeque<u8>,
+}
+
+/// Create an in-memory connection, returning both of its ends. This lets a client and server exchange shares in the same process
+/// without opening a socket
+pub fn pipe() -> (Pipe, Pipe) {
+    let (client_sender, server_receiver) = mpsc::channel();
+    let (server_sender, client_receiver) = mpsc::channel();
+
+    (
+        Pipe {
+            sender: client_sender,
+            receiver: client_receiver,
+            buffer: VecDeque::new(),
+        },
+        Pipe {
+            sender: server_sender,
+            receiver: server_receiver,
+            buffer: VecDeque::new(),
+        },
+    )
+}
+
+impl Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Wait for the other end to write something
+        if self.buffer.is_empty() {
+            match self.receiver.recv() {
+                Ok(data) => self.buffer.extend(data),
+                // The other end was dropped
+                Err(_) => return Ok(0),
+            }
+        }
+
+        self.buffer.read(buf)
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Sending nothing would look like end of file to the other end
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        self.sender.send(buf.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "The other end of the pipe was dropped"))?;
+
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A connection between a client and server, either plain TCP, TCP wrapped in TLS or a Unix socket
+pub enum Connection {
+    Tcp(TcpStream),
+    /// Connection from a process on the same host
+    Unix(UnixStream),
+    /// Server side of a TLS connection
+    TlsServer(Box<StreamOwned<ServerConnection, TcpStream>>),
+    /// Client side of a TLS connection
+    TlsClient(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl Connection {
+    /// Returns a description of the other end of the connection, its address for TCP or its user id for Unix sockets
+    pub fn peer(&self) -> String {
+        let peer = match self {
+            Connection::Tcp(stream) => stream.peer_addr(),
+            Connection::Unix(_) => return match self.peer_uid() {
+                Ok(uid) => format!("uid {uid}"),
+                Err(error) => format!("unknown uid: {error}"),
+            },
+            Connection::TlsServer(stream) => stream.get_ref().peer_addr(),
+            Connection::TlsClient(stream) => stream.get_ref().peer_addr(),
+        };
+
+        match peer {
+            Ok(address) => address.to_string(),
+            Err(error) => format!("unknown address: {error}"),
+        }
+    }
+    /// Returns a handle to the socket under the connection (without TLS), used to shut the connection down from another thread
+    pub fn try_clone_socket(&self) -> io::Result<Connection> {
+        match self {
+            Connection::Tcp(stream) => stream.try_clone().map(Connection::Tcp),
+            Connection::Unix(stream) => stream.try_clone().map(Connection::Unix),
+            Connection::TlsServer(stream) => stream.get_ref().try_clone().map(Connection::Tcp),
+            Connection::TlsClient(stream) => stream.get_ref().try_clone().map(Connection::Tcp),
+        }
+    }
+    /// Shut the connection down, reads that are waiting on it return right away
+    pub fn shutdown(&self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.shutdown(Shutdown::Both),
+            Connection::Unix(stream) => stream.shutdown(Shutdown::Both),
+            Connection::TlsServer(stream) => stream.get_ref().shutdown(Shutdown::Both),
+            Connection::TlsClient(stream) => stream.get_ref().shutdown(Shutdown::Both),
+        }
+    }
+    /// Returns the user id of the process on the other end of a Unix socket, as reported by the kernel
+    pub fn peer_uid(&self) -> io::Result<u32> {
+        let Connection::Unix(stream) = self else {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "Only Unix sockets have peer credentials"));
+        };
+
+        let mut credentials = libc::ucred { pid: 0, uid: 0, gid: 0 };
+        let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+
+        let result = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut credentials as *mut libc::ucred as *mut libc::c_void,
+                &mut len,
+            )
+        };
+
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(credentials.uid)
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.read(buf),
+            Connection::Unix(stream) => stream.read(buf),
+            Connection::TlsServer(stream) => stream.read(buf),
+            Connection::TlsClient(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.write(buf),
+            Connection::Unix(stream) => stream.write(buf),
+            Connection::TlsServer(stream) => stream.write(buf),
+            Connection::TlsClient(stream) => stream.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.flush(),
+            Connection::Unix(stream) => stream.flush(),
+            Connection::TlsServer(stream) => stream.flush(),
+            Connection::TlsClient(stream) => stream.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::{config::Config, server::{self, ServerState, Session}, CommandType, Location, Share, ShareCommand};
+
+    /// Returns the state of a server storing files in memory
+    fn state() -> ServerState {
+        let config: Config = toml::from_str("[server]\nthread_count = 1\nips = []\nstorage = \"memory\"").unwrap();
+
+        ServerState::build(config.server().unwrap()).unwrap()
+    }
+
+    /// Send a command over the stream and return the response
+    fn send(stream: &mut Pipe, command_type: CommandType, args: &[&str], file: Option<&[u8]>) -> Share {
+        let command = ShareCommand::new(command_type, args.iter().map(|arg| arg.to_string()).collect());
+        let mut share = Share::new(command, Location::Client);
+
+        if let Some(file) = file {
+            share.load_file(file.to_vec());
+        }
+
+        share.exchange(stream).unwrap()
+    }
+
+    #[test]
+    fn pipe_carries_data_both_ways() {
+        let (mut client, mut server) = pipe();
+
+        client.write_all(b"ping").unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+
+        // Dropping one end is end of file for the other
+        drop(server);
+        assert_eq!(client.read(&mut buf).unwrap(), 0);
+        assert!(client.write(b"pong").is_err());
+    }
+
+    #[test]
+    fn server_handles_commands_over_pipe() {
+        let (mut client, server) = pipe();
+        let handle = thread::spawn(move || server::handle_client(server, Session::new(), &state()));
+
+        let file: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+        assert!(send(&mut client, CommandType::Upload, &["dir/a.bin"], Some(&file)).succeeded());
+        assert!(send(&mut client, CommandType::Upload, &["b.txt"], Some(b"hello")).succeeded());
+
+        let mut response = send(&mut client, CommandType::Receive, &["dir/a.bin"], None);
+        assert!(response.succeeded());
+        assert_eq!(response.take_file(), Some(file.clone()));
+
+        // Uploading a changed file only sends a delta against the copy the server has
+        let mut changed = file.clone();
+        changed[10_000] ^= 0xff;
+        assert!(send(&mut client, CommandType::Upload, &["dir/a.bin"], Some(&changed)).succeeded());
+        assert_eq!(send(&mut client, CommandType::Receive, &["dir/a.bin"], None).take_file(), Some(changed));
+
+        let response = send(&mut client, CommandType::Catalog, &[], None);
+        assert_eq!(response.text_data(), Some("./b.txt\n./dir/a.bin\n"));
+
+        let response = send(&mut client, CommandType::Receive, &["missing.txt"], None);
+        assert!(!response.succeeded());
+        assert_eq!(response.error_code(), Some(crate::ErrorCode::NotFound));
+
+        // The server returns once the client closes the connection
+        drop(client);
+        handle.join().unwrap();
+    }
+}
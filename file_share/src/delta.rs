@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::{ErrorCode, ServerError};
+
+/// Files smaller than this are always sent whole, the signatures would not save much
+pub const MIN_DELTA_SIZE: u64 = 64 * 1024;
+
+/// Signatures of the blocks of a file, sent by the server so the client can find which parts of the file it already has
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Signatures {
+    block_size: usize,
+    /// Signatures of every whole block, a shorter block at the end of the file is left out
+    blocks: Vec<BlockSignature>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BlockSignature {
+    /// Rolling checksum, cheap to compute at every offset of the new file
+    weak: u32,
+    /// First half of the SHA-256 hash, checked when the weak checksum matches
+    strong: [u8; 16],
+}
+
+/// The changes between the copy of a file on the server and the new file, made of blocks the server already has and data it
+/// doesnt
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Delta {
+    block_size: usize,
+    operations: Vec<Operation>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Operation {
+    /// Copy count blocks starting at block start from the copy on the server
+    Copy { start: u64, count: u64 },
+    /// Data the server does not have
+    Data(Vec<u8>),
+}
+
+impl Signatures {
+    /// Compute the signatures of the blocks of a file, the block size grows with the square root of the file size
+    pub fn compute(data: &[u8]) -> Signatures {
+        let block_size = ((data.len() as f64).sqrt() as usize).clamp(2048, 128 * 1024);
+
+        Signatures {
+            block_size,
+            blocks: data.chunks_exact(block_size)
+                .map(|block| BlockSignature {
+                    weak: Rolling::new(block).digest(),
+                    strong: strong(block),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Delta {
+    /// Find the blocks of data the server already has, everything else is sent as is
+    pub fn compute(signatures: &Signatures, data: &[u8]) -> Delta {
+        let block_size = signatures.block_size;
+        let mut delta = Delta {
+            block_size,
+            operations: Vec::new(),
+        };
+
+        // Map weak checksums to the blocks that have them
+        let mut blocks: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (index, block) in signatures.blocks.iter().enumerate() {
+            blocks.entry(block.weak).or_default().push(index);
+        }
+
+        let mut literal = Vec::new();
+        let mut offset = 0;
+        let mut rolling = (data.len() >= block_size).then(|| Rolling::new(&data[..block_size]));
+
+        while let Some(checksum) = rolling.as_mut() {
+            let end = offset + block_size;
+
+            let matched = blocks.get(&checksum.digest()).and_then(|candidates| {
+                let strong = strong(&data[offset..end]);
+
+                candidates.iter().find(|index| signatures.blocks[**index].strong == strong)
+            });
+
+            if let Some(index) = matched {
+                if !literal.is_empty() {
+                    delta.operations.push(Operation::Data(literal.split_off(0)));
+                }
+                delta.copy(*index as u64);
+
+                offset = end;
+                rolling = (data.len() >= offset + block_size).then(|| Rolling::new(&data[offset..offset + block_size]));
+                continue;
+            }
+
+            // Move the window forward a byte
+            literal.push(data[offset]);
+            match data.get(end) {
+                Some(next) => checksum.roll(data[offset], *next),
+                None => rolling = None,
+            }
+            offset += 1;
+        }
+
+        literal.extend_from_slice(&data[offset..]);
+        if !literal.is_empty() {
+            delta.operations.push(Operation::Data(literal));
+        }
+
+        delta
+    }
+    /// Returns the amount of bytes of data the delta contains, the blocks the server has are not counted
+    pub fn data_size(&self) -> usize {
+        self.operations.iter()
+            .map(|operation| match operation {
+                Operation::Data(data) => data.len(),
+                Operation::Copy { .. } => 0,
+            })
+            .sum()
+    }
+    /// Rebuild the new file from the copy on the server, the delta is rejected if it references blocks the copy does not have or
+    /// builds a file larger than size (the size the client said the file has)
+    pub fn apply(&self, basis: &[u8], size: u64) -> Result<Vec<u8>, ServerError> {
+        let missing = || ServerError::new(ErrorCode::InvalidArgument, "Delta references blocks the file does not have");
+        let mut data = Vec::new();
+
+        for operation in &self.operations {
+            let part = match operation {
+                Operation::Copy { start, count } => {
+                    let blocks = |blocks: u64| usize::try_from(blocks).ok().and_then(|blocks| blocks.checked_mul(self.block_size));
+                    let start = blocks(*start).ok_or_else(missing)?;
+                    let end = blocks(*count).and_then(|len| start.checked_add(len)).ok_or_else(missing)?;
+
+                    basis.get(start..end).ok_or_else(missing)?
+                }
+                Operation::Data(literal) => literal.as_slice(),
+            };
+
+            // Copies can repeat the same blocks any number of times, the file is checked while it is built so a small delta cant use
+            // up the memory of the server
+            if (data.len() + part.len()) as u64 > size {
+                return Err(ServerError::new(ErrorCode::InvalidArgument, "Delta builds a file larger than its size"));
+            }
+
+            data.extend_from_slice(part);
+        }
+
+        Ok(data)
+    }
+    /// Add a copy of a block, blocks following the previous copy extend it
+    fn copy(&mut self, index: u64) {
+        if let Some(Operation::Copy { start, count }) = self.operations.last_mut() {
+            if *start + *count == index {
+                *count += 1;
+                return;
+            }
+        }
+
+        self.operations.push(Operation::Copy { start: index, count: 1 });
+    }
+}
+
+/// Rolling checksum of a window of bytes (like the one rsync uses), it can be moved forward a byte without going over the whole
+/// window again
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(window: &[u8]) -> Rolling {
+        let mut rolling = Rolling {
+            a: 0,
+            b: 0,
+            len: window.len() as u32,
+        };
+
+        for (index, byte) in window.iter().enumerate() {
+            rolling.a = rolling.a.wrapping_add(*byte as u32);
+            rolling.b = rolling.b.wrapping_add((window.len() - index) as u32 * *byte as u32);
+        }
+
+        rolling
+    }
+    /// Move the window forward, removing out from the start and adding next to the end
+    fn roll(&mut self, out: u8, next: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(next as u32);
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out as u32)).wrapping_add(self.a);
+    }
+    fn digest(&self) -> u32 {
+        (self.b << 16) | (self.a & 0xffff)
+    }
+}
+
+/// Returns the first half of the SHA-256 hash of a block
+fn strong(block: &[u8]) -> [u8; 16] {
+    let mut strong = [0; 16];
+    strong.copy_from_slice(&Sha256::digest(block)[..16]);
+
+    strong
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delta_rebuilds_the_file() {
+        let basis: Vec<u8> = (0..200_000u32).map(|index| (index * 7 % 251) as u8).collect();
+        let mut data = basis.clone();
+        data[100_000] ^= 1;
+        data.extend_from_slice(b"appended");
+
+        let delta = Delta::compute(&Signatures::compute(&basis), &data);
+
+        assert!(delta.data_size() < data.len());
+        assert_eq!(delta.apply(&basis, data.len() as u64).unwrap(), data);
+    }
+
+    #[test]
+    fn deltas_are_checked_while_applying() {
+        let basis = vec![0; 4096];
+        let delta = |operations| Delta { block_size: 2048, operations };
+
+        // Blocks past the end of the basis, and block numbers that overflow
+        assert!(delta(vec![Operation::Copy { start: 1, count: 2 }]).apply(&basis, 8192).is_err());
+        assert!(delta(vec![Operation::Copy { start: u64::MAX, count: 1 }]).apply(&basis, 8192).is_err());
+        assert!(delta(vec![Operation::Copy { start: 0, count: u64::MAX }]).apply(&basis, 8192).is_err());
+
+        // The same blocks copied over and over dont grow the file past its size
+        let repeated = delta((0..1000).map(|_| Operation::Copy { start: 0, count: 2 }).collect());
+        assert_eq!(repeated.apply(&basis, 4096 * 1000).unwrap().len(), 4096 * 1000);
+        assert_eq!(repeated.apply(&basis, 4096 * 3).unwrap_err().code, ErrorCode::InvalidArgument);
+    }
+}
@@ -0,0 +1,202 @@
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::FileShareError;
+
+/// Multicast group servers listen for discovery requests on
+pub const DISCOVERY_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 42, 98);
+/// Default UDP port servers listen for discovery requests on
+pub const DISCOVERY_PORT: u16 = 34250;
+
+/// Sent by clients looking for servers
+const REQUEST: &str = "FILE_SHARE_DISCOVER";
+/// Start of the reply a server sends, formated like `REPLY address name`
+const REPLY: &str = "FILE_SHARE_SERVER";
+
+/// A server that replied to a discovery request
+#[derive(Debug, Clone, PartialEq)]
+pub struct Discovered {
+    /// Name of the server from its configuration
+    pub name: String,
+    /// Address the server accepts connections on
+    pub address: SocketAddr,
+}
+
+/// Start a thread that replies to discovery requests on the given UDP port, telling clients the name of the server and the address
+/// (out of the addresses it listens on) they can connect to
+pub fn respond(name: String, port: u16, listeners: Vec<SocketAddr>) -> Result<(), FileShareError> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?;
+
+    // Requests are sent to the multicast group and broadcast, without a multicast route only broadcast requests are received
+    if let Err(error) = socket.join_multicast_v4(&DISCOVERY_GROUP, &Ipv4Addr::UNSPECIFIED) {
+        eprintln!("Failed to join discovery multicast group: {error}");
+    }
+
+    thread::spawn(move || {
+        let mut buf = [0; 512];
+
+        loop {
+            let (len, peer) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(error) => {
+                    eprintln!("Failed to receive discovery request: {error}");
+                    continue;
+                }
+            };
+
+            // Ignore anything that is not a discovery request
+            if &buf[..len] != REQUEST.as_bytes() {
+                continue;
+            }
+
+            // Dont tell clients about addresses they cant reach
+            let address = match advertised(&listeners, peer) {
+                Some(address) => address,
+                None => continue,
+            };
+
+            if let Err(error) = socket.send_to(format!("{REPLY} {address} {name}").as_bytes(), peer) {
+                eprintln!("Failed to reply to discovery request from {peer}: {error}");
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Returns the address a client sending a request from peer should connect to. This is the listener on the address the request
+/// arrived on, loopback listeners are only advertised to clients on the same machine
+fn advertised(listeners: &[SocketAddr], peer: SocketAddr) -> Option<SocketAddr> {
+    // The address the server sends replies to peer from is the address of the interface the request arrived on
+    let local = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .and_then(|socket| {
+            socket.connect(peer)?;
+            socket.local_addr()
+        })
+        .ok()?
+        .ip();
+    let reachable: Vec<SocketAddr> = listeners.iter()
+        .copied()
+        .filter(|listener| peer.ip().is_loopback() || !listener.ip().is_loopback())
+        .collect();
+
+    if let Some(listener) = reachable.iter().find(|listener| listener.ip() == local) {
+        return Some(*listener);
+    }
+    if let Some(mut listener) = reachable.iter().copied().find(|listener| listener.ip().is_unspecified()) {
+        listener.set_ip(local);
+        return Some(listener);
+    }
+
+    reachable.first().copied()
+}
+
+/// Look for servers on the local network by sending a discovery request to the given UDP port, then collecting replies until the
+/// timeout runs out
+pub fn discover(port: u16, timeout: Duration) -> Result<Vec<Discovered>, FileShareError> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_broadcast(true)?;
+    socket.set_multicast_loop_v4(true)?;
+
+    // Send to the multicast group and broadcast address, and to loopback so servers on this machine are found even if the network
+    // has no route for either. Failing to send to one of them is fine as long as one of them works
+    let targets = [DISCOVERY_GROUP, Ipv4Addr::BROADCAST, Ipv4Addr::LOCALHOST];
+    let sent = targets.iter()
+        .filter(|target| socket.send_to(REQUEST.as_bytes(), (**target, port)).is_ok())
+        .count();
+
+    if sent == 0 {
+        return Err(io::Error::new(io::ErrorKind::NetworkUnreachable, "Failed to send discovery request").into());
+    }
+
+    let mut servers = Vec::new();
+    let mut buf = [0; 512];
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        socket.set_read_timeout(Some(remaining))?;
+
+        let (len, peer) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            // Timed out
+            Err(_) => break,
+        };
+
+        // Parse the reply
+        let reply = String::from_utf8_lossy(&buf[..len]);
+        let mut parts = reply.splitn(3, ' ');
+
+        if parts.next() != Some(REPLY) {
+            continue;
+        }
+        let (mut address, name) = match (parts.next().and_then(|address| address.parse::<SocketAddr>().ok()), parts.next()) {
+            (Some(address), Some(name)) => (address, name.to_string()),
+            _ => continue,
+        };
+
+        // Servers listening on every interface can be reached on the address the reply came from
+        if address.ip().is_unspecified() {
+            address.set_ip(peer.ip());
+        }
+
+        let server = Discovered {
+            name,
+            address,
+        };
+
+        // The same server may reply once for every request it received
+        if !servers.contains(&server) {
+            servers.push(server);
+        }
+    }
+
+    Ok(servers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a UDP port that is free right now
+    fn free_port() -> u16 {
+        UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap().port()
+    }
+
+    #[test]
+    fn discover_finds_responder() {
+        let port = free_port();
+        let listener: SocketAddr = "127.0.0.1:34999".parse().unwrap();
+
+        respond("test".to_string(), port, vec![listener]).unwrap();
+
+        let servers = discover(port, Duration::from_millis(500)).unwrap();
+
+        assert!(servers.contains(&Discovered { name: "test".to_string(), address: listener }));
+    }
+
+    #[test]
+    fn loopback_only_advertised_to_loopback() {
+        let loopback: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let unspecified: SocketAddr = "0.0.0.0:4001".parse().unwrap();
+        let local_peer: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let lan_peer: SocketAddr = "192.0.2.1:5000".parse().unwrap();
+
+        assert_eq!(advertised(&[loopback], local_peer), Some(loopback));
+        assert_eq!(advertised(&[loopback, unspecified], local_peer), Some(loopback));
+
+        // A peer on the network gets the unspecified listener with the address of the interface that reaches it
+        if let Some(address) = advertised(&[loopback, unspecified], lan_peer) {
+            assert_eq!(address.port(), 4001);
+            assert!(!address.ip().is_loopback());
+        }
+        assert_eq!(advertised(&[loopback], lan_peer), None);
+    }
+}
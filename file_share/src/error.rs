@@ -0,0 +1,230 @@
+use std::{fmt, io};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::limits::Blocked;
+
+/// Why the server failed to run a command, sent along with error responses so clients can tell failures apart without reading the
+/// text. Codes are sent as their index in CODES, new codes are added to the end of it and older clients decode them as Unknown
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The file, version, link or trashed file does not exist
+    NotFound,
+    /// A file is already where the command would put one
+    AlreadyExists,
+    /// The client has not logged in, failed to log in, or is not allowed to run the command
+    PermissionDenied,
+    /// Storing the file would go over the user or server quota
+    QuotaExceeded,
+    /// The file is larger than the server accepts
+    TooLarge,
+    /// The path leaves the storage root or is empty
+    InvalidPath,
+    /// An argument other than the path is not valid, like a version number or rate
+    InvalidArgument,
+    /// The server has no space left to store the file
+    StorageFull,
+    /// The client is rate limited or banned, or the file changed while it was being sent. Trying again later may work
+    Busy,
+    /// Anything else, like a storage backend failing
+    Internal,
+    /// A code this version does not know, sent by a newer server
+    Unknown,
+}
+
+/// Codes in the order of their index on the wire, Unknown is not sent as itself so it has no index
+const CODES: [ErrorCode; 10] = [
+    ErrorCode::NotFound,
+    ErrorCode::AlreadyExists,
+    ErrorCode::PermissionDenied,
+    ErrorCode::QuotaExceeded,
+    ErrorCode::TooLarge,
+    ErrorCode::InvalidPath,
+    ErrorCode::InvalidArgument,
+    ErrorCode::StorageFull,
+    ErrorCode::Busy,
+    ErrorCode::Internal,
+];
+
+impl Serialize for ErrorCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // Unknown codes are only received, if one is passed on it stays unknown
+        let index = CODES.iter().position(|code| code == self).map_or(u32::MAX, |index| index as u32);
+
+        serializer.serialize_u32(index)
+    }
+}
+
+impl<'de> Deserialize<'de> for ErrorCode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<ErrorCode, D::Error> {
+        let index = u32::deserialize(deserializer)?;
+
+        Ok(CODES.get(index as usize).copied().unwrap_or(ErrorCode::Unknown))
+    }
+}
+
+impl ErrorCode {
+    /// Returns the exit status a client should exit with after a command failed with this code, 1 is left for errors on the
+    /// client itself
+    pub fn exit_status(&self) -> i32 {
+        match self {
+            ErrorCode::NotFound => 2,
+            ErrorCode::AlreadyExists => 3,
+            ErrorCode::PermissionDenied => 4,
+            ErrorCode::QuotaExceeded => 5,
+            ErrorCode::TooLarge => 6,
+            ErrorCode::InvalidPath => 7,
+            ErrorCode::InvalidArgument => 8,
+            ErrorCode::StorageFull => 9,
+            ErrorCode::Busy => 10,
+            ErrorCode::Internal => 11,
+            ErrorCode::Unknown => 12,
+        }
+    }
+    /// Returns the code for an I/O error from its kind
+    fn of_io(error: &io::Error) -> ErrorCode {
+        match error.kind() {
+            io::ErrorKind::NotFound => ErrorCode::NotFound,
+            io::ErrorKind::AlreadyExists => ErrorCode::AlreadyExists,
+            io::ErrorKind::PermissionDenied => ErrorCode::PermissionDenied,
+            io::ErrorKind::StorageFull => ErrorCode::StorageFull,
+            io::ErrorKind::FileTooLarge => ErrorCode::TooLarge,
+            io::ErrorKind::InvalidFilename => ErrorCode::InvalidPath,
+            _ => ErrorCode::Internal,
+        }
+    }
+}
+
+/// An error with the code the client receives for it
+#[derive(Debug)]
+pub struct ServerError {
+    pub code: ErrorCode,
+    message: String,
+}
+
+impl ServerError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> ServerError {
+        ServerError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+/// Errors returned by the public functions of the crate
+#[derive(Debug)]
+pub enum FileShareError {
+    /// A command could not be parsed, like an unknown command type or the wrong amount of arguments
+    Parse(String),
+    /// The configuration could not be read, or is missing the section that was asked for
+    Config(String),
+    /// Reading or writing a file or stream failed
+    Io(io::Error),
+    /// The other end sent data that does not follow the protocol, like a frame header that is not a length
+    Protocol(String),
+    /// A share could not be serialized, or the data received is not a share
+    Serialization(bincode::Error),
+    /// The server failed to run a command, the code tells why
+    Server(ServerError),
+    /// The server rate limited or banned the client
+    Blocked(Blocked),
+    /// A TLS certificate or key could not be used, or the TLS connection could not be set up
+    Tls(rustls::Error),
+}
+
+impl FileShareError {
+    /// Returns the code the client receives for the error if it happens while the server runs a command
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            FileShareError::Parse(_) => ErrorCode::InvalidArgument,
+            FileShareError::Io(error) => ErrorCode::of_io(error),
+            FileShareError::Server(error) => error.code,
+            FileShareError::Blocked(_) => ErrorCode::Busy,
+            FileShareError::Config(_) | FileShareError::Protocol(_) | FileShareError::Serialization(_) | FileShareError::Tls(_) => {
+                ErrorCode::Internal
+            }
+        }
+    }
+}
+
+impl fmt::Display for FileShareError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FileShareError::Parse(message) => write!(f, "Parse error: {message}"),
+            FileShareError::Config(message) => write!(f, "{message}"),
+            FileShareError::Io(error) => write!(f, "{error}"),
+            FileShareError::Protocol(message) => write!(f, "Protocol error: {message}"),
+            FileShareError::Serialization(error) => write!(f, "Serialization error: {error}"),
+            FileShareError::Server(error) => write!(f, "{error}"),
+            FileShareError::Blocked(blocked) => write!(f, "{blocked}"),
+            FileShareError::Tls(error) => write!(f, "TLS error: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for FileShareError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FileShareError::Io(error) => Some(error),
+            FileShareError::Serialization(error) => Some(error),
+            FileShareError::Tls(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for FileShareError {
+    fn from(error: io::Error) -> FileShareError {
+        FileShareError::Io(error)
+    }
+}
+
+impl From<bincode::Error> for FileShareError {
+    fn from(error: bincode::Error) -> FileShareError {
+        FileShareError::Serialization(error)
+    }
+}
+
+impl From<ServerError> for FileShareError {
+    fn from(error: ServerError) -> FileShareError {
+        FileShareError::Server(error)
+    }
+}
+
+impl From<Blocked> for FileShareError {
+    fn from(blocked: Blocked) -> FileShareError {
+        FileShareError::Blocked(blocked)
+    }
+}
+
+impl From<rustls::Error> for FileShareError {
+    fn from(error: rustls::Error) -> FileShareError {
+        FileShareError::Tls(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_are_sent_as_their_index() {
+        for (index, code) in CODES.iter().enumerate() {
+            // The index is sent the way the derived enum sent its variant, so older peers still decode it
+            assert_eq!(bincode::serialize(code).unwrap(), bincode::serialize(&(index as u32)).unwrap());
+            assert_eq!(bincode::deserialize::<ErrorCode>(&bincode::serialize(code).unwrap()).unwrap(), *code);
+        }
+
+        // Codes added by newer servers are unknown
+        assert_eq!(bincode::deserialize::<ErrorCode>(&bincode::serialize(&10u32).unwrap()).unwrap(), ErrorCode::Unknown);
+        assert_eq!(bincode::deserialize::<ErrorCode>(&bincode::serialize(&ErrorCode::Unknown).unwrap()).unwrap(), ErrorCode::Unknown);
+    }
+}
@@ -0,0 +1,101 @@
+use std::{
+    fmt,
+    io::{Read, Write},
+    sync::{mpsc::{self, Receiver, Sender}, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{read_frame, server::Session, storage, write_frame, FileShareError};
+
+/// A change to the files in storage, pushed to the clients that subscribed to the directory it happened in
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Event {
+    Created(String),
+    Modified(String),
+    Deleted(String),
+    Renamed { from: String, to: String },
+}
+
+impl Event {
+    /// Returns the event for a file that was written to path, existed tells if it replaced a file
+    pub fn written(path: &str, existed: bool) -> Event {
+        match existed {
+            true => Event::Modified(storage::key(path)),
+            false => Event::Created(storage::key(path)),
+        }
+    }
+    /// Returns true if the event happened inside dir, an empty dir contains every file
+    fn is_inside(&self, dir: &str) -> bool {
+        let inside = |path: &str| dir.is_empty() || path.starts_with(&format!("{dir}/"));
+
+        match self {
+            Event::Created(path) | Event::Modified(path) | Event::Deleted(path) => inside(path),
+            Event::Renamed { from, to } => inside(from) || inside(to),
+        }
+    }
+    /// Write the event to the given stream, it is framed the same way shares are
+    pub fn write_to_stream(&self, stream: &mut impl Write) -> Result<(), FileShareError> {
+        write_frame(stream, &bincode::serialize(self)?)
+    }
+    /// Read an event that was written to the given stream
+    pub fn read_from_stream(stream: &mut impl Read) -> Result<Event, FileShareError> {
+        Ok(bincode::deserialize(&read_frame(stream, None)?)?)
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::Created(path) => write!(f, "created {path}"),
+            Event::Modified(path) => write!(f, "modified {path}"),
+            Event::Deleted(path) => write!(f, "deleted {path}"),
+            Event::Renamed { from, to } => write!(f, "renamed {from} to {to}"),
+        }
+    }
+}
+
+/// A connection that subscribed to the events in a directory
+struct Subscriber {
+    /// Id of the session that subscribed, it is not sent the changes it made itself
+    session: u64,
+    dir: String,
+    sender: Sender<Event>,
+}
+
+/// Delivers the changes made to storage to the connections that subscribed to them
+#[derive(Default)]
+pub struct Events {
+    subscribers: Mutex<Vec<Subscriber>>,
+}
+
+impl Events {
+    /// Subscribe a session to the changes other sessions make inside dir, returns the receiver the events arrive on
+    pub fn subscribe(&self, session: &Session, dir: &str) -> Receiver<Event> {
+        let (sender, receiver) = mpsc::channel();
+        let dir = storage::key(dir).trim_end_matches('/').to_string();
+
+        self.subscribers.lock().unwrap().push(Subscriber {
+            session: session.id(),
+            dir: if dir == "." { String::new() } else { dir },
+            sender,
+        });
+
+        receiver
+    }
+    /// Send an event to every subscriber of the directory it happened in, except the session that caused it. Subscribers whose
+    /// connection has closed are removed
+    pub fn publish(&self, session: &Session, event: Event) {
+        if session.events_muted() {
+            return;
+        }
+
+        self.subscribers.lock().unwrap().retain(|subscriber| {
+            if subscriber.session == session.id() || !event.is_inside(&subscriber.dir) {
+                return true;
+            }
+
+            subscriber.sender.send(event.clone()).is_ok()
+        });
+    }
+}
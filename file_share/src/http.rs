@@ -0,0 +1,664 @@
+use std::{io::{self, BufRead, BufReader, Read, Write}, ops::Range};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use percent_encoding::percent_decode_str;
+
+use crate::{
+    connection::Transport, server::{ServerState, Session}, limits::Blocked, storage, error::ServerError, CommandType, ErrorCode,
+    FileShareError, Location, Share, ShareCommand,
+};
+
+/// Files are served under this path, `/files` itself lists them
+const FILES_PATH: &str = "/files";
+/// Page served at `/` when the web UI is enabled, it lists, uploads and downloads files through the `/files` endpoints
+const UI_PAGE: &str = include_str!("ui.html");
+/// Size of the pieces response bodies are written in
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Longest request line or header line accepted
+const MAX_LINE_LENGTH: u64 = 8 * 1024;
+/// Most headers accepted in a single request
+const MAX_HEADERS: usize = 100;
+
+/// A request read from the client, without its body
+pub(crate) struct Request {
+    pub(crate) method: String,
+    /// Path of the request without the query
+    pub(crate) path: String,
+    /// HTTP/1.1 keeps connections open by default, HTTP/1.0 closes them
+    http_11: bool,
+    /// Headers of the request, the names are lowercase
+    headers: Vec<(String, String)>,
+    /// The body of the request has not been read yet, the connection must be closed after responding since the next request
+    /// cant be found
+    pub(crate) body_pending: bool,
+}
+
+impl Request {
+    /// Returns the value of the first header with the given (lowercase) name
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+    /// Returns true if the client wants the connection to stay open after the response
+    fn keep_alive(&self) -> bool {
+        match self.header("connection").map(str::to_ascii_lowercase).as_deref() {
+            Some("close") => false,
+            Some("keep-alive") => true,
+            _ => self.http_11,
+        }
+    }
+}
+
+/// The body of a response
+enum Body {
+    /// Data that is already in memory
+    Data(Vec<u8>),
+    /// The given amount of bytes read from a reader while they are sent, so files dont have to fit in memory
+    Reader(Box<dyn Read + Send>, u64),
+}
+
+impl Body {
+    /// Returns the length of the body in bytes
+    fn len(&self) -> u64 {
+        match self {
+            Body::Data(data) => data.len() as u64,
+            Body::Reader(_, len) => *len,
+        }
+    }
+}
+
+/// A response to send to the client
+pub(crate) struct Response {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Body,
+}
+
+impl Response {
+    /// Returns true if the status is a 2xx success
+    pub(crate) fn succeeded(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+    /// Create a response sending the whole body
+    pub(crate) fn new(status: u16, content_type: &str, body: Vec<u8>) -> Response {
+        Response {
+            status,
+            headers: vec![("Content-Type", content_type.to_string())],
+            body: Body::Data(body),
+        }
+    }
+    /// Create a response sending len bytes read from reader as the body
+    pub(crate) fn stream(status: u16, content_type: &str, reader: Box<dyn Read + Send>, len: u64) -> Response {
+        Response {
+            status,
+            headers: vec![("Content-Type", content_type.to_string())],
+            body: Body::Reader(reader, len),
+        }
+    }
+    /// Create a response without a body
+    pub(crate) fn empty(status: u16) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Body::Data(Vec::new()),
+        }
+    }
+    /// Create a response with an error message as the body
+    pub(crate) fn error(status: u16, message: &str) -> Response {
+        Response::new(status, "text/plain; charset=utf-8", format!("{message}\n").into_bytes())
+    }
+    /// Create a response asking the client to log in with basic auth
+    pub(crate) fn unauthorized(message: &str) -> Response {
+        Response::error(401, message).header("WWW-Authenticate", String::from("Basic realm=\"file_share\""))
+    }
+    /// Create a response telling the client it sent too many requests, and when it may try again
+    pub(crate) fn blocked(blocked: &Blocked) -> Response {
+        Response::error(429, &blocked.to_string()).header("Retry-After", blocked.retry_after.to_string())
+    }
+    /// Create a response for an error returned while executing a command, the status is picked by the code of the error
+    pub(crate) fn for_error(error: &FileShareError) -> Response {
+        if let FileShareError::Blocked(blocked) = error {
+            return Response::blocked(blocked);
+        }
+
+        let status = match error.code() {
+            ErrorCode::NotFound => 404,
+            ErrorCode::AlreadyExists => 409,
+            ErrorCode::PermissionDenied => 403,
+            ErrorCode::QuotaExceeded | ErrorCode::TooLarge => 413,
+            ErrorCode::InvalidPath | ErrorCode::InvalidArgument => 400,
+            ErrorCode::StorageFull => 507,
+            ErrorCode::Busy => 429,
+            ErrorCode::Internal | ErrorCode::Unknown => 500,
+        };
+
+        Response::error(status, &error.to_string())
+    }
+    /// Add a header to the response
+    pub(crate) fn header(mut self, name: &'static str, value: String) -> Response {
+        self.headers.push((name, value));
+        self
+    }
+}
+
+/// Handles a single request and returns the response to it, the body of the request (if any) is read from the stream
+pub(crate) type Handler<T> = fn(&mut Request, &mut BufReader<T>, &mut Session, &ServerState) -> Response;
+
+/// Handle a client of the HTTP gateway. Requests are served until the client closes the connection, every request runs through the
+/// same Share execution (and so the same storage, limits and auth checks) as the native protocol
+pub fn handle_client(stream: impl Transport, session: Session, state: &ServerState) {
+    serve(state.bandwidth().throttle(stream), session, state, handle_request)
+}
+
+/// Read requests from the client and answer them with handler until the client closes the connection
+pub(crate) fn serve<T: Transport>(stream: T, mut session: Session, state: &ServerState, handler: Handler<T>) {
+    let mut stream = BufReader::new(stream);
+
+    loop {
+        let mut request = match read_request(&mut stream) {
+            Ok(Some(request)) => request,
+            // The client closed the connection
+            Ok(None) => return,
+            Err(error) => {
+                let _ = write_response(stream.get_mut(), Response::error(400, &error.to_string()), false, true);
+                return;
+            }
+        };
+
+        // Browsers and most tools send the credentials with every request
+        let response = match state.limits().check(&session) {
+            Err(blocked) => Response::blocked(&blocked),
+            Ok(_) => match request.header("authorization").map(|credentials| login(credentials, &mut session, state)) {
+                Some(Err(FileShareError::Blocked(blocked))) => Response::blocked(&blocked),
+                Some(Err(error)) => Response::unauthorized(&error.to_string()),
+                _ => handler(&mut request, &mut stream, &mut session, state),
+            },
+        };
+        let close = !request.keep_alive() || request.body_pending;
+
+        if let Err(error) = write_response(stream.get_mut(), response, request.method == "HEAD", close) {
+            eprintln!("Failed to write HTTP response: {error}");
+            return;
+        }
+
+        if close {
+            return;
+        }
+    }
+}
+
+/// Read the request line and headers of the next request, returns None if the client closed the connection
+fn read_request(stream: &mut impl BufRead) -> Result<Option<Request>, FileShareError> {
+    let line = match read_line(stream)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+
+    // The request line is formated like `METHOD target HTTP/1.1`
+    let mut parts = line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) => (method, target, version),
+        _ => return Err(FileShareError::Protocol(String::from("Invalid request line"))),
+    };
+
+    if !version.starts_with("HTTP/1.") {
+        return Err(FileShareError::Protocol(format!("Unsupported HTTP version: {version}")));
+    }
+
+    let mut headers = Vec::new();
+
+    loop {
+        let line = read_line(stream)?
+            .ok_or_else(|| FileShareError::Protocol(String::from("Connection closed while reading headers")))?;
+
+        // Headers end with an empty line
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(FileShareError::Protocol(String::from("Too many headers")));
+        }
+
+        let (name, value) = line.split_once(':').ok_or_else(|| FileShareError::Protocol(String::from("Invalid header")))?;
+        headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+    }
+
+    let mut request = Request {
+        method: method.to_string(),
+        path: target.split_once('?').map_or(target, |(path, _)| path).to_string(),
+        http_11: version == "HTTP/1.1",
+        headers,
+        body_pending: false,
+    };
+
+    request.body_pending = request.header("transfer-encoding").is_some()
+        || request.header("content-length").is_some_and(|length| length != "0");
+
+    Ok(Some(request))
+}
+
+/// Read a single line without its line ending, returns None at the end of the stream
+fn read_line(stream: &mut impl BufRead) -> Result<Option<String>, FileShareError> {
+    let mut line = String::new();
+
+    if stream.take(MAX_LINE_LENGTH).read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') {
+        return Err(FileShareError::Protocol(String::from("Line is too long")));
+    }
+
+    Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+/// Route a request to its handler
+fn handle_request<T: Transport>(request: &mut Request, stream: &mut BufReader<T>, session: &mut Session, state: &ServerState) -> Response {
+    // The web UI is a single page
+    if request.path == "/" && state.config().web_ui() {
+        return match request.method.as_str() {
+            "GET" | "HEAD" => ui(session),
+            _ => Response::error(405, "Method not allowed").header("Allow", String::from("GET, HEAD")),
+        };
+    }
+
+    let path = match request.path.strip_prefix(FILES_PATH) {
+        Some("" | "/") => None,
+        Some(path) if path.starts_with('/') => match decode_path(&path[1..]) {
+            Ok(path) => Some(path),
+            Err(error) => return Response::error(400, &error.to_string()),
+        },
+        _ => return Response::error(404, "Not found"),
+    };
+
+    match (request.method.as_str(), path) {
+        ("GET" | "HEAD", None) => catalog(session, state),
+        ("GET" | "HEAD", Some(path)) => get(request, &path, session, state),
+        ("PUT", Some(path)) => put(request, stream, &path, session, state),
+        ("DELETE", Some(path)) => delete(&path, session, state),
+        (_, None) => Response::error(405, "Method not allowed").header("Allow", String::from("GET, HEAD")),
+        (_, Some(_)) => Response::error(405, "Method not allowed").header("Allow", String::from("GET, HEAD, PUT, DELETE")),
+    }
+}
+
+/// Log the session in with the credentials of a basic auth header
+fn login(credentials: &str, session: &mut Session, state: &ServerState) -> Result<(), FileShareError> {
+    let invalid = |message| ServerError::new(ErrorCode::PermissionDenied, message);
+
+    let encoded = credentials.strip_prefix("Basic ").ok_or_else(|| invalid("Only basic auth is supported"))?;
+    let decoded = STANDARD.decode(encoded.trim()).ok().and_then(|decoded| String::from_utf8(decoded).ok());
+    let (user, password) = decoded.as_ref()
+        .and_then(|decoded| decoded.split_once(':'))
+        .ok_or_else(|| invalid("Invalid basic auth credentials"))?;
+
+    session.login(state, user, password)
+}
+
+/// Percent decode the path of a file, paths leaving the storage root are rejected
+pub(crate) fn decode_path(path: &str) -> Result<String, FileShareError> {
+    let path = percent_decode_str(path)
+        .decode_utf8()
+        .map_err(|_| ServerError::new(ErrorCode::InvalidPath, "Path is not valid UTF-8"))?
+        .to_string();
+
+    storage::check_path(&path)?;
+
+    Ok(path)
+}
+
+/// Run a command the same way the native protocol does, returning the executed share
+pub(crate) fn execute(
+    command_type: CommandType,
+    args: Vec<String>,
+    file: Option<Vec<u8>>,
+    session: &mut Session,
+    state: &ServerState,
+) -> Result<Share, Response> {
+    let mut share = Share::new(ShareCommand::new(command_type, args), Location::Server);
+
+    if let Some(file) = file {
+        share.set_file(file);
+    }
+
+    share.execute_on_server(state, session).map_err(|error| Response::for_error(&error))?;
+
+    Ok(share)
+}
+
+/// Send the web UI, clients that must log in are asked to before the page loads so its requests are authorized
+fn ui(session: &Session) -> Response {
+    if let Err(error) = session.authorize(&CommandType::Catalog) {
+        return Response::unauthorized(&error.to_string());
+    }
+
+    Response::new(200, "text/html; charset=utf-8", UI_PAGE.as_bytes().to_vec())
+}
+
+/// List the files in storage, one per line
+fn catalog(session: &mut Session, state: &ServerState) -> Response {
+    if let Err(error) = session.authorize(&CommandType::Catalog) {
+        return Response::unauthorized(&error.to_string());
+    }
+
+    match execute(CommandType::Catalog, Vec::new(), None, session, state) {
+        Ok(share) => Response::new(200, "text/plain; charset=utf-8", share.text_data().unwrap_or_default().as_bytes().to_vec()),
+        Err(response) => response,
+    }
+}
+
+/// Send a file, or the part of it asked for with a Range header. The file is streamed from storage instead of running RECEIVE,
+/// which would read all of it into memory first
+pub(crate) fn get(request: &Request, path: &str, session: &mut Session, state: &ServerState) -> Response {
+    if let Err(error) = session.authorize(&CommandType::Receive) {
+        return Response::unauthorized(&error.to_string());
+    }
+
+    let len = match state.storage().size_of(path) {
+        Ok(Some(len)) => len,
+        Ok(None) => return Response::error(404, &format!("{path} does not exist")),
+        Err(error) => return Response::error(500, &error.to_string()),
+    };
+    let range = match request.header("range").map(|range| parse_range(range, len)) {
+        Some(Ok(range)) => range,
+        Some(Err(())) => return Response::error(416, "Range not satisfiable").header("Content-Range", format!("bytes */{len}")),
+        None => None,
+    };
+
+    let reader = match state.storage().reader(path, range.as_ref().map_or(0, |range| range.start)) {
+        Ok(reader) => reader,
+        Err(error) => return Response::for_error(&error),
+    };
+
+    match range {
+        Some(range) => Response::stream(206, "application/octet-stream", reader, range.end - range.start)
+            .header("Accept-Ranges", String::from("bytes"))
+            .header("Content-Range", format!("bytes {}-{}/{len}", range.start, range.end - 1)),
+        None => Response::stream(200, "application/octet-stream", reader, len).header("Accept-Ranges", String::from("bytes")),
+    }
+}
+
+/// Store the body of the request as a file. The body is kept in memory until it is stored like the file of a share, so an upload
+/// can use up to max_file_size bytes of memory
+pub(crate) fn put(request: &mut Request, stream: &mut BufReader<impl Transport>, path: &str, session: &mut Session, state: &ServerState) -> Response {
+    if let Err(error) = session.authorize(&CommandType::Upload) {
+        return Response::unauthorized(&error.to_string());
+    }
+
+    let chunked = request.header("transfer-encoding").is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"));
+    let length = match request.header("content-length").map(str::parse::<u64>) {
+        Some(Ok(length)) => Some(length),
+        Some(Err(_)) => return Response::error(400, "Invalid Content-Length"),
+        None if chunked => None,
+        None => return Response::error(411, "Content-Length required"),
+    };
+
+    // Reject the file before reading the body if its size is known and goes over any limits
+    if let Some(length) = length {
+        if let Err(error) = state.quotas().check(state.config(), state.storage(), session.user(), path, length) {
+            return Response::for_error(&error);
+        }
+    }
+
+    // The client waits for this before sending the body
+    if request.header("expect").is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue")) {
+        let writer = stream.get_mut();
+
+        if let Err(error) = writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").and_then(|_| writer.flush()) {
+            return Response::error(500, &error.to_string());
+        }
+    }
+
+    let body = match length {
+        Some(length) => read_body(stream, length, state.config().max_file_size()),
+        None => read_chunked_body(stream, state.config().max_file_size()),
+    };
+    let body = match body {
+        Ok(body) => body,
+        Err(response) => return response,
+    };
+    request.body_pending = false;
+
+    let existed = match state.storage().size_of(path) {
+        Ok(size) => size.is_some(),
+        Err(error) => return Response::error(500, &error.to_string()),
+    };
+
+    // The size of chunked bodies is only known now
+    if let Err(error) = state.quotas().check(state.config(), state.storage(), session.user(), path, body.len() as u64) {
+        return Response::for_error(&error);
+    }
+
+    match execute(CommandType::Upload, vec![path.to_string()], Some(body), session, state) {
+        Ok(_) if existed => Response::empty(204),
+        Ok(_) => Response::empty(201),
+        Err(response) => response,
+    }
+}
+
+/// Move a file into the trash
+pub(crate) fn delete(path: &str, session: &mut Session, state: &ServerState) -> Response {
+    if let Err(error) = session.authorize(&CommandType::Delete) {
+        return Response::unauthorized(&error.to_string());
+    }
+
+    match state.storage().size_of(path) {
+        Ok(Some(_)) => (),
+        Ok(None) => return Response::error(404, &format!("{path} does not exist")),
+        Err(error) => return Response::error(500, &error.to_string()),
+    }
+
+    match execute(CommandType::Delete, vec![path.to_string()], None, session, state) {
+        Ok(_) => Response::empty(204),
+        Err(response) => response,
+    }
+}
+
+/// Read a body of the given length a chunk at a time as it arrives, so memory is only used for data the client actually sent. The
+/// whole body is still returned in memory, limit bounds how much that is and bodies larger than it are rejected before any of it is
+/// read
+pub(crate) fn read_body(stream: &mut impl BufRead, length: u64, limit: Option<u64>) -> Result<Vec<u8>, Response> {
+    if let Some(limit) = limit.filter(|limit| length > *limit) {
+        return Err(Response::error(413, &format!("File is too large, the limit is {limit} bytes")));
+    }
+
+    let mut body = Vec::new();
+    let mut chunk = vec![0; CHUNK_SIZE];
+    let mut remaining = length;
+
+    while remaining > 0 {
+        let len = chunk.len().min(usize::try_from(remaining).unwrap_or(usize::MAX));
+
+        match stream.read(&mut chunk[..len]) {
+            Ok(0) => return Err(Response::error(400, "Connection closed while reading the body")),
+            Ok(read) => {
+                body.extend_from_slice(&chunk[..read]);
+                remaining -= read as u64;
+            }
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(Response::error(400, &error.to_string())),
+        }
+    }
+
+    Ok(body)
+}
+
+/// Read a body sent with chunked transfer encoding, bodies larger than limit are rejected
+pub(crate) fn read_chunked_body(stream: &mut impl BufRead, limit: Option<u64>) -> Result<Vec<u8>, Response> {
+    let mut body = Vec::new();
+    let invalid = |error: FileShareError| Response::error(400, &error.to_string());
+
+    loop {
+        // Every chunk starts with its size in hex, optionally followed by extensions
+        let line = read_line(stream)
+            .map_err(invalid)?
+            .ok_or_else(|| Response::error(400, "Connection closed while reading the body"))?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = u64::from_str_radix(size, 16).map_err(|_| Response::error(400, "Invalid chunk size"))?;
+
+        // The last chunk is empty and followed by trailers, which are ignored
+        if size == 0 {
+            while !read_line(stream).map_err(invalid)?.unwrap_or_default().is_empty() {}
+
+            return Ok(body);
+        }
+
+        // The chunk is read with what is left of the limit, so the body as a whole cant go over it
+        let left = limit.map(|limit| limit.saturating_sub(body.len() as u64));
+        body.extend(read_body(stream, size, left)?);
+
+        // Chunks end with a line ending
+        if !read_line(stream).map_err(invalid)?.is_some_and(|line| line.is_empty()) {
+            return Err(Response::error(400, "Invalid chunk"));
+        }
+    }
+}
+
+/// Parse a Range header for a body of len bytes. Returns the range to send, None if the whole body should be sent (multiple ranges
+/// and invalid headers are ignored) or an error if the range cant be satisfied
+fn parse_range(header: &str, len: u64) -> Result<Option<Range<u64>>, ()> {
+    let range = match header.strip_prefix("bytes=") {
+        Some(range) if !range.contains(',') => range.trim(),
+        _ => return Ok(None),
+    };
+    let (start, end) = match range.split_once('-') {
+        Some(range) => range,
+        None => return Ok(None),
+    };
+
+    // `-n` asks for the last n bytes
+    if start.is_empty() {
+        return match end.parse::<u64>() {
+            Ok(0) => Err(()),
+            Ok(_) if len == 0 => Err(()),
+            Ok(suffix) => Ok(Some(len.saturating_sub(suffix)..len)),
+            Err(_) => Ok(None),
+        };
+    }
+
+    let start = match start.parse::<u64>() {
+        Ok(start) => start,
+        Err(_) => return Ok(None),
+    };
+
+    if start >= len {
+        return Err(());
+    }
+
+    // `n-` asks for everything from n, `n-m` for bytes n to m (inclusive)
+    match end {
+        "" => Ok(Some(start..len)),
+        end => match end.parse::<u64>() {
+            // The end is clamped to the last byte first, so ends past the file dont overflow
+            Ok(end) if end >= start => Ok(Some(start..end.min(len - 1) + 1)),
+            _ => Ok(None),
+        },
+    }
+}
+
+/// Write a response to the client, the body is written a chunk at a time. The body is left out for HEAD requests
+fn write_response(stream: &mut impl Write, response: Response, head: bool, close: bool) -> io::Result<()> {
+    let mut header = format!("HTTP/1.1 {} {}\r\nContent-Length: {}\r\n", response.status, reason(response.status), response.body.len());
+
+    for (name, value) in &response.headers {
+        header.push_str(&format!("{name}: {value}\r\n"));
+    }
+    if close {
+        header.push_str("Connection: close\r\n");
+    }
+    header.push_str("\r\n");
+
+    stream.write_all(header.as_bytes())?;
+
+    if !head {
+        match response.body {
+            Body::Data(data) => {
+                for chunk in data.chunks(CHUNK_SIZE) {
+                    stream.write_all(chunk)?;
+                }
+            }
+            Body::Reader(reader, len) => {
+                let mut reader = reader.take(len);
+                let mut chunk = vec![0; CHUNK_SIZE];
+                let mut sent = 0;
+
+                loop {
+                    let read = match reader.read(&mut chunk) {
+                        Ok(0) => break,
+                        Ok(read) => read,
+                        Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(error) => return Err(error),
+                    };
+
+                    stream.write_all(&chunk[..read])?;
+                    sent += read as u64;
+                }
+
+                // The Content-Length promised more than was sent, the connection has to be closed
+                if sent < len {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "File ended before the whole body was sent"));
+                }
+            }
+        }
+    }
+
+    stream.flush()
+}
+
+/// Returns the reason phrase of a status code
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        206 => "Partial Content",
+        207 => "Multi-Status",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        413 => "Content Too Large",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        423 => "Locked",
+        429 => "Too Many Requests",
+        507 => "Insufficient Storage",
+        _ => "Internal Server Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_range("bytes=0-9", 100), Ok(Some(0..10)));
+        assert_eq!(parse_range("bytes=90-", 100), Ok(Some(90..100)));
+        assert_eq!(parse_range("bytes=-10", 100), Ok(Some(90..100)));
+        assert_eq!(parse_range("bytes=0-18446744073709551615", 100), Ok(Some(0..100)));
+        assert_eq!(parse_range("bytes=100-", 100), Err(()));
+        assert_eq!(parse_range("bytes=0-1,5-6", 100), Ok(None));
+        assert_eq!(parse_range("items=0-1", 100), Ok(None));
+    }
+
+    #[test]
+    fn bodies_over_the_limit_are_rejected_before_reading() {
+        let mut stream = io::Cursor::new(b"hello world".to_vec());
+
+        assert!(read_body(&mut stream, 11, Some(10)).is_err());
+        assert_eq!(stream.position(), 0);
+        assert_eq!(read_body(&mut stream, 5, Some(10)).ok(), Some(b"hello".to_vec()));
+        assert!(read_body(&mut stream, 100, None).is_err());
+    }
+
+    #[test]
+    fn chunked_bodies() {
+        let mut stream = io::Cursor::new(b"5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\n\r\n".to_vec());
+        assert_eq!(read_chunked_body(&mut stream, None).ok(), Some(b"hello world".to_vec()));
+
+        let mut stream = io::Cursor::new(b"5\r\nhello\r\nffffffffffffffff\r\n".to_vec());
+        assert!(read_chunked_body(&mut stream, Some(10)).is_err());
+    }
+}
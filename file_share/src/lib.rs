@@ -1,12 +1,36 @@
 #![feature(core_intrinsics)]
 
-use std::{process, fs::{File, self}, io::{Read, Write, BufReader, BufRead}, mem, net::TcpStream};
+use std::{process, fs::File, io::{self, Read, Write}, ops::RangeInclusive};
 
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
-mod config;
+pub mod config;
 pub use config::Config;
 
+pub mod backend;
+pub mod storage;
+pub mod quota;
+pub mod links;
+pub mod server;
+pub mod discovery;
+pub mod connection;
+pub mod tls;
+pub mod http;
+pub mod webdav;
+pub mod delta;
+pub mod events;
+pub mod mux;
+pub mod throttle;
+pub mod limits;
+pub mod error;
+pub use error::{ErrorCode, FileShareError};
+use server::{ServerState, Session};
+use connection::Transport;
+use delta::{Delta, Signatures};
+use events::Event;
+use error::ServerError;
+
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 /// Contains the type of the command
 pub enum CommandType {
@@ -18,29 +42,70 @@ pub enum CommandType {
     Upload,
     Receive,
     Catalog,
+    Versions,
+    Restore,
+    Delete,
+    Trash,
+    Undelete,
+    Login,
+    Quota,
+    Link,
+    Unlink,
+    Redeem,
+    Manifest,
+    Sync,
+    Watch,
+    Subscribe,
+    MUpload,
+    MReceive,
+    /// Switches the connection to multiplexing, the client sends it by itself so it is not parsed
+    Mux,
+    Throttle,
 }
 
 impl CommandType {
-    /// Returns true if the CommandType has an argument
-    fn has_arg(&self) -> bool {
-        if *self == CommandType::Exit ||
-           *self == CommandType::Help ||
-           *self == CommandType::Catalog 
-        {
-            false
-        } else {
-            true
+    /// Returns the minimum and maximum amount of arguments the CommandType takes
+    fn arg_count(&self) -> RangeInclusive<usize> {
+        match *self {
+            CommandType::Exit |
+            CommandType::Help |
+            CommandType::Catalog |
+            CommandType::Trash |
+            CommandType::Quota |
+            CommandType::Mux => 0..=0,
+
+            CommandType::Upload |
+            CommandType::Receive |
+            CommandType::Versions |
+            CommandType::Delete |
+            CommandType::Undelete |
+            CommandType::Unlink => 1..=1,
+
+            CommandType::Manifest |
+            CommandType::Subscribe => 0..=1,
+            CommandType::Throttle => 0..=2,
+            CommandType::Watch |
+            CommandType::MUpload |
+            CommandType::MReceive => 1..=2,
+            CommandType::Redeem => 1..=2,
+            CommandType::Sync => 2..=4,
+            CommandType::Link => 1..=3,
+
+            CommandType::Restore |
+            CommandType::Login => 2..=2,
         }
     }
     /// Returns true if the command runs on the client side
     pub fn is_client(&self) -> bool {
-        if *self == CommandType::Exit ||
-           *self == CommandType::Help
-        {
-            true
-        } else {
-            false
-        }
+        matches!(
+            self,
+            CommandType::Exit |
+            CommandType::Help |
+            CommandType::Sync |
+            CommandType::Watch |
+            CommandType::MUpload |
+            CommandType::MReceive
+        )
     }
 }
 
@@ -48,16 +113,16 @@ impl CommandType {
 /// Structure contains all data for a Command, the type of command and arguments provided with the command
 pub struct ShareCommand {
     command_type: CommandType,
-    arg: Option<String>,
+    args: Vec<String>,
 }
 
 impl ShareCommand {
     // TODO: allow ability to use different command parser
     /// Parse a &str into a ShareCommand structure
-    pub fn parse(command: &str) -> Result<ShareCommand, Box<dyn std::error::Error>> {
+    pub fn parse(command: &str) -> Result<ShareCommand, FileShareError> {
         // Check if the command is empty
         if command.is_empty() {
-            return Err("Parse error: Empty command".into());
+            return Err(FileShareError::Parse(String::from("Empty command")));
         }
 
         // Create an iterator over each word
@@ -71,48 +136,73 @@ impl ShareCommand {
             "UPLOAD" => CommandType::Upload,
             "RECEIVE" => CommandType::Receive,
             "CATALOG" => CommandType::Catalog,
+            "VERSIONS" => CommandType::Versions,
+            "RESTORE" => CommandType::Restore,
+            "DELETE" => CommandType::Delete,
+            "TRASH" => CommandType::Trash,
+            "UNDELETE" => CommandType::Undelete,
+            "LOGIN" => CommandType::Login,
+            "QUOTA" => CommandType::Quota,
+            "LINK" => CommandType::Link,
+            "UNLINK" => CommandType::Unlink,
+            "REDEEM" => CommandType::Redeem,
+            "MANIFEST" => CommandType::Manifest,
+            "SYNC" => CommandType::Sync,
+            "WATCH" => CommandType::Watch,
+            "SUBSCRIBE" => CommandType::Subscribe,
+            "MUPLOAD" => CommandType::MUpload,
+            "MRECEIVE" => CommandType::MReceive,
+            "THROTTLE" => CommandType::Throttle,
 
             unknown => {
-                return Err(
-                    format!(
-                        "Parse error: Unknow command type: {unknown}",
-                    ).into()
-                );
+                return Err(FileShareError::Parse(format!("Unknown command type: {unknown}")));
             }
         };
 
-        let arg: Option<String> = match command_tokens.next() {
-            // Command uses argument and the argument was found
-            Some(arg) if command_type.has_arg() => Some(arg.to_string()),
-            // Argument provided with command, but command does not use an argument
-            Some(_) if !command_type.has_arg() => {
-                return Err(
-                    format!(
-                        "Parse Error: {:?} does not have an argument",
-                        command_type,
-                    ).into()
-                );
-            }
-            // Command requires an argument
-            None if command_type.has_arg() => {
-                return Err("Parse error: No argument provided for command".into());
-            },
-            // Command does not require an argument
-            None if !command_type.has_arg() => None,
-
-            _ => return Err("Parse error: Unknown".into()),
-        };
+        // Collect the rest of the words as arguments
+        let args: Vec<String> = command_tokens.map(String::from).collect();
+        let arg_count = command_type.arg_count();
+
+        // Command requires an argument
+        if args.is_empty() && !arg_count.contains(&0) {
+            return Err(FileShareError::Parse(String::from("No argument provided for command")));
+        }
+        // Command requires more arguments than were provided
+        if args.len() < *arg_count.start() {
+            return Err(FileShareError::Parse(format!("{:?} requires {} arguments", command_type, arg_count.start())));
+        }
+        // Arguments provided with command, but command does not use that many arguments
+        if args.len() > *arg_count.end() {
+            return Err(FileShareError::Parse(format!("{:?} takes at most {} arguments", command_type, arg_count.end())));
+        }
 
         // Return parsed command
         Ok(ShareCommand { 
             command_type, 
-            arg, 
+            args, 
         })
     }
+    /// Create a command without parsing it, the amount of arguments is not checked so the caller must provide as many as the
+    /// CommandType takes
+    pub fn new(command_type: CommandType, args: Vec<String>) -> ShareCommand {
+        ShareCommand {
+            command_type,
+            args,
+        }
+    }
     /// Returns the CommandType of self
     pub fn command_type(&self) -> &CommandType {
         &self.command_type
     }
+    /// Returns all the arguments of the command
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+    /// Returns the argument at the given index, commands are checked to have the right amount of arguments while parsing so this
+    /// should only be called with an index that the CommandType takes
+    pub fn arg(&self, index: usize) -> &str {
+        &self.args[index]
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
@@ -130,6 +220,14 @@ pub struct Share {
 
     /// Contains file data
     file: Option<Vec<u8>>,
+    /// Contains the SHA-256 hash (as hex) of the file data, this lets the server skip receiving files it already has
+    content_hash: Option<String>,
+    /// Contains the size of the file data, this lets the server check limits before receiving the file
+    file_size: Option<u64>,
+    /// Contains the block signatures of the copy of the file the server has, sent in reply to an offer so the client can send a delta
+    signatures: Option<Signatures>,
+    /// Contains the changes to the copy of the file the server has, this is sent instead of the file data when it is smaller
+    delta: Option<Delta>,
     /// Contains text data, this is interpretted diferent ways depending on the
     /// CommandType. This can be file names, the file catalogue, etc.
     text_data: Option<String>,
@@ -145,28 +243,21 @@ impl Share {
         Share { 
             command, 
             file: None,
+            content_hash: None,
+            file_size: None,
+            signatures: None,
+            delta: None,
             text_data: None, 
             server_response: ServerResponse::new(),
             current_location
         }
     }
     /// Write self to the given stream, this handles all writing including sending the seperate header containing the size of self
-    pub fn write_to_stream(&mut self, stream: &mut TcpStream, current_location: Location) -> Result<(), Box<dyn std::error::Error>>{
+    pub fn write_to_stream(&mut self, stream: &mut impl Write, current_location: Location) -> Result<(), FileShareError> {
         // Convert the share to bytes so it can be written to the stream
         let share = bincode::serialize(self)?;
 
-        // Calculate the size (in bytes) of the struct
-        let content_len = mem::size_of_val(&share[..]);
-
-        // Send a header containing the content length and a newline
-        stream.write(
-        format!("{}\n",
-                content_len
-            ).as_bytes()
-        )?;
-
-        // Write the share to the stream
-        stream.write_all(&share[..])?;
+        write_frame(stream, &share)?;
 
         // Set the current_location
         self.current_location = current_location;
@@ -174,41 +265,115 @@ impl Share {
         Ok(())
     }
     /// Read data from the given stream, this handles all the reading of the sent Share struct. Returns a Result<T, E> containing the 
-    /// recieved Share struct on success. Returns a Result<T, E> containing a Box<dyn std::error::Error> on failure, this can mean many
-    /// things such as, failing to read the header, failing to parse the header, failing to read the send Share structure, and lastly
-    /// failing to deserialize the Share structure
-    pub fn read_from_stream(stream: &mut TcpStream, current_location: Location) -> Result<Share, Box<dyn std::error::Error>> {
-        // Create an empty buffer, this will be used to read the header
-        let mut share_len: String = String::new() ;
-        // Wrap the stream in a buf reader
-        let mut buf_reader = BufReader::new(stream);
-    
-        // Read header, the header is formated like `content_length\n`
-        buf_reader.read_line(&mut share_len)?;
-
-        // Parse the header into a usize
-        let share_len: usize = share_len.trim().parse()?;
-
-        // Create a new buffer that will store the bytes of the Share
-        let mut share_bytes = Vec::new();
-        // Resize the buffer to the share_len so we can call read_exact()
-        share_bytes.resize(share_len, 0);
-        
-        // Read all the bytes making up the send Share into the bufferr
-        buf_reader.read_exact(&mut share_bytes)?;
-
-        // Convert the bytes back into a Share
-        let mut share = bincode::deserialize::<Share>(&share_bytes[..])?;
+    /// recieved Share struct on success. Returns a Result<T, E> containing a FileShareError on failure, this can mean many things
+    /// such as, failing to read the header (Io), failing to parse the header (Protocol), failing to read the send Share structure (Io),
+    /// and lastly failing to deserialize the Share structure (Serialization)
+    pub fn read_from_stream(stream: &mut impl Read, current_location: Location) -> Result<Share, FileShareError> {
+        Share::read_limited(stream, current_location, None)
+    }
+    /// Read a share like read_from_stream, failing with a Protocol error before reading it if it is larger than max_len bytes
+    pub(crate) fn read_limited(stream: &mut impl Read, current_location: Location, max_len: Option<u64>) -> Result<Share, FileShareError> {
+        let share_bytes = read_frame(stream, max_len)?;
+
+        Share::from_bytes(&share_bytes, current_location)
+    }
+    /// Convert the bytes of a serialized share back into a Share
+    pub(crate) fn from_bytes(bytes: &[u8], current_location: Location) -> Result<Share, FileShareError> {
+        let mut share = bincode::deserialize::<Share>(bytes)?;
 
         // Set the current_location
         share.current_location = current_location;
 
         Ok(share)
     }
+    /// Offer the share to the server before sending any file data. Only the command and the content hash are sent, if the server
+    /// replies asking for the file data this returns None and the whole share should then be written to the stream. Otherwise the
+    /// server handled the command without needing the file data (or rejected it), and its response is returned. Shares without file
+    /// data are not offered and always return None. If the server has an older copy of the file it sends its block signatures, and a
+    /// delta is prepared when it is smaller than the file
+    pub fn offer(&mut self, stream: &mut impl Transport) -> Result<Option<Share>, FileShareError> {
+        // Send the share without the file data
+        let Some(offer) = self.offer_bytes()? else {
+            return Ok(None);
+        };
+        write_frame(stream, &offer)?;
+
+        stream.flush()?;
+
+        let response = Share::read_from_stream(stream, Location::Client)?;
+
+        match self.offer_accepted(&response) {
+            true => Ok(None),
+            false => Ok(Some(response)),
+        }
+    }
+    /// Send the share to the server and return the response the server sends back. The share is offered first, the file data is only
+    /// sent if the server needs it
+    pub fn exchange(&mut self, stream: &mut impl Transport) -> Result<Share, FileShareError> {
+        // The server handled the command without needing the file data
+        if let Some(response) = self.offer(stream)? {
+            return Ok(response);
+        }
+
+        // Write the share we prepared to the server/stream, only the delta is sent if there is one
+        write_frame(stream, &self.data_bytes()?)?;
+        self.current_location = Location::Client;
+
+        // Make sure all buffered contents reach there destination
+        stream.flush()?;
+
+        // Read in the response the server send, this can contain requested files, text data, etc.
+        Share::read_from_stream(stream, Location::Client)
+    }
+    /// Returns the share serialized without its file data so it can be offered to the server, or None if it has no file data
+    pub(crate) fn offer_bytes(&mut self) -> Result<Option<Vec<u8>>, FileShareError> {
+        self.delta = None;
+
+        if self.file.is_none() {
+            return Ok(None);
+        }
+
+        let file = self.file.take();
+        let offer = bincode::serialize(self);
+        self.file = file;
+
+        Ok(Some(offer?))
+    }
+    /// Returns true if the response to an offer asks for the file data. If the server has an older copy of the file it sends its
+    /// block signatures, and a delta is prepared when it is smaller than the file
+    pub(crate) fn offer_accepted(&mut self, response: &Share) -> bool {
+        if response.server_response.status != ServerResponseStatus::Continue {
+            return false;
+        }
+
+        if let (Some(signatures), Some(file)) = (response.signatures.as_ref(), self.file.as_ref()) {
+            let delta = Delta::compute(signatures, file);
+
+            if delta.data_size() < file.len() {
+                self.delta = Some(delta);
+            }
+        }
+
+        true
+    }
+    /// Returns the share serialized to be sent whole, only the delta is sent instead of the file data if there is one. The file is
+    /// kept so the share can be sent again whole
+    pub(crate) fn data_bytes(&mut self) -> Result<Vec<u8>, FileShareError> {
+        let file = match self.delta.is_some() {
+            true => self.file.take(),
+            false => None,
+        };
+        let data = bincode::serialize(self);
+        if file.is_some() {
+            self.file = file;
+        }
+
+        Ok(data?)
+    }
     /// Some commands may require this method to work properly, take the Upload command as an example, the Upload command is useless if
     /// there is no file loaded into self.file. Calling this method will prepare any data (like a file) into self. This method may also
     /// be used to handle commands before anything is sent
-    pub fn prepare_data(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn prepare_data(&mut self) -> Result<(), FileShareError> {
         match *self.command.command_type() {
             // We dont want Exit to be passed through the server before it can be executed
             CommandType::Exit => {
@@ -217,21 +382,40 @@ impl Share {
             // Help doesnt need any data from the server, but the sever can still view that you have ran help and return any additional
             // things.
             CommandType::Help => {
-                println!(
-                    "{}\n{}\n{}\n{}\n{}",
+                let help = [
                     "----- Help Guide -----",
                     "EXIT - Exit the client",
                     "UPLOAD [file] - Upload a file to the server",
                     "RECEIVE [file] - Receive a file from the server",
+                    "MUPLOAD [glob|@list] [remote dir] - Upload every file matching a glob or listed in a file, several at once",
+                    "MRECEIVE [glob|@list] [local dir] - Receive every file on the server matching a glob or listed in a file, several at once",
                     "CATALOG - Receive a list of files from the server",
-                );
+                    "VERSIONS [file] - Receive a list of the previous versions of a file on the server",
+                    "RESTORE [file] [version] - Replace a file on the server with one of its previous versions",
+                    "DELETE [file] - Move a file on the server into the trash",
+                    "TRASH - Receive a list of files in the servers trash",
+                    "UNDELETE [file] - Move the most recently deleted version of a file out of the trash",
+                    "LOGIN [user] [password] - Log in to the server",
+                    "QUOTA - Receive how much space you have used on the server and how much remains",
+                    "LINK [file] [ttl] [downloads] - Create a link anyone can download a file with, for ttl (like 30m, 12h, 7d) or downloads",
+                    "UNLINK [link] - Revoke a link",
+                    "REDEEM [link] [file] - Download the file a link is for, saving it as file",
+                    "MANIFEST [dir] - Receive the hash, size and path of every file in a directory on the server",
+                    "SYNC [local dir] [remote dir] [push|pull|both] [delete] - Transfer the files that changed, delete extra files with delete",
+                    "WATCH [local dir] [remote dir] - Upload files in a local directory as they are created or changed, until enter is pressed",
+                    "THROTTLE [global|client] [rate] - Show the bandwidth limits, or set one in bytes per second (0 is unlimited), admins only",
+                    "SUBSCRIBE [dir] - Print the changes other clients make to files in a directory on the server, until enter is pressed",
+                ];
+
+                println!("{}", help.join("\n"));
             }
             // Load file into vector
             CommandType::Upload if self.current_location == Location::Client => {
-                let mut file = File::open(self.command.arg.as_ref().unwrap())?;
+                let mut file = File::open(self.command.arg(0))?;
+                let mut data = Vec::new();
+                file.read_to_end(&mut data)?;
 
-                self.file = Some(Vec::new());
-                file.read_to_end(&mut self.file.as_mut().unwrap())?;
+                self.load_file(data);
             },  
 
             _ => eprintln!("Nothing to prepare"),
@@ -239,14 +423,21 @@ impl Share {
 
         Ok(())
     }
-    /// Execute the command
-    pub fn execute(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+    /// Execute the command on the client side
+    pub fn execute(&mut self) -> Result<(), FileShareError> {
         // If we are executing on the client side print the server response
         if self.current_location == Location::Client {
-            println!("Server says: {:?}. STATUS: {:?}", self.server_response.text, self.server_response.status);
+            match self.server_response.code {
+                Some(code) => println!(
+                    "Server says: {:?}. STATUS: {:?} ({code:?})",
+                    self.server_response.text,
+                    self.server_response.status,
+                ),
+                None => println!("Server says: {:?}. STATUS: {:?}", self.server_response.text, self.server_response.status),
+            }
         }
-        // If the server reports an error dont execute the command
-        if self.server_response.status == ServerResponseStatus::Error {
+        // If the server reports an error (or blocked the command) dont execute the command
+        if matches!(self.server_response.status, ServerResponseStatus::Error | ServerResponseStatus::Blocked { .. }) {
             return Ok(());
         }
 
@@ -254,39 +445,312 @@ impl Share {
         match *self.command.command_type() {
             // Received a file from the server; Move file inside memory to storage
             CommandType::Receive if self.current_location == Location::Client => {
-                let mut file = File::create(self.command.arg.as_ref().unwrap())?;
+                let mut file = File::create(self.command.arg(0))?;
 
-                file.write_all(&self.file.as_ref().unwrap())?;
+                file.write_all(self.file.as_ref().unwrap())?;
+            }
+            // Received the file a link is for, text_data contains its path on the server
+            CommandType::Redeem if self.current_location == Location::Client => {
+                let name = match self.command.args.get(1) {
+                    Some(name) => name.as_str(),
+                    None => self.text_data.as_deref().unwrap_or_default().rsplit('/').next().unwrap_or_default(),
+                };
+                let mut file = File::create(name)?;
+
+                file.write_all(self.file.as_ref().unwrap())?;
+                println!("Saved {name}");
+            }
+            // Print text_data containing a list of files the server has, a list of versions of a file, or the files in the trash
+            CommandType::Catalog |
+            CommandType::Versions |
+            CommandType::Trash |
+            CommandType::Quota |
+            CommandType::Link |
+            CommandType::Manifest |
+            CommandType::Throttle if self.current_location == Location::Client => {
+                println!("{}", self.text_data.as_ref().unwrap());
             }
-            // Send a file to the client; Move file inside storage to memory
-            CommandType::Receive if self.current_location == Location::Server => {
-                let mut file = File::open(self.command.arg.as_ref().unwrap())?;
 
-                self.file = Some(Vec::new());
-                file.read_to_end(&mut self.file.as_mut().unwrap())?;
+            _ => (),
+        }
+
+        Ok(())
+    }
+    /// Execute the command on the server side, all files are read from and written to the storage of the server state. The session
+    /// is the state of the connection the share was received on
+    pub fn execute_on_server(&mut self, state: &ServerState, session: &mut Session) -> Result<(), FileShareError> {
+        session.authorize(self.command.command_type())?;
+
+        // Shares are made by the client, so the arguments are counted again before any of them are used
+        let command_type = self.command.command_type();
+        let arg_count = command_type.arg_count();
+        if !arg_count.contains(&self.command.args.len()) {
+            return Err(FileShareError::Parse(format!("{command_type:?} takes {} to {} arguments", arg_count.start(), arg_count.end())));
+        }
+
+        // Commands on a file are rejected if its path leaves the storage root or points into a hidden directory
+        if matches!(
+            self.command.command_type(),
+            CommandType::Upload |
+            CommandType::Receive |
+            CommandType::Versions |
+            CommandType::Restore |
+            CommandType::Delete |
+            CommandType::Undelete |
+            CommandType::Link
+        ) {
+            storage::check_path(self.command.arg(0))?;
+        }
+
+        let storage = state.storage();
+        let quotas = state.quotas();
+
+        // Received a delta from the client; Rebuild the file from the copy in storage
+        if let Some(delta) = self.delta.take() {
+            if *self.command.command_type() != CommandType::Upload {
+                return Err(ServerError::new(ErrorCode::InvalidArgument, "Only uploads can carry a delta").into());
             }
-            // Send a file to the client; Move file inside storage to memory
-            CommandType::Upload if self.current_location == Location::Server => {
-                let mut file = File::create(self.command.arg.as_ref().unwrap())?;
 
-                file.write_all(&self.file.as_ref().unwrap())?;
+            let size = self.file_size.ok_or(ServerError::new(ErrorCode::InvalidArgument, "Delta sent without the file size"))?;
+            quotas.check(state.config(), storage, session.user(), self.command.arg(0), size)?;
+
+            let file = delta.apply(&storage.read(self.command.arg(0))?, size)?;
+
+            if self.content_hash.as_ref() != Some(&hex::encode(Sha256::digest(&file))) {
+                return Err(ServerError::new(ErrorCode::Busy, "File changed on the server during the upload, upload it again").into());
             }
-            // Print text_data containing a list of files the server has
-            CommandType::Catalog if self.current_location == Location::Client => {
-                println!("{}", self.text_data.as_ref().unwrap());
+
+            self.file = Some(file);
+        }
+
+        match *self.command.command_type() {
+            // Send a file to the client; Move file inside storage to memory
+            CommandType::Receive => {
+                self.file = Some(storage.read(self.command.arg(0))?);
+            }
+            // Received a file from the client; Move file inside memory to storage
+            CommandType::Upload if self.file.is_some() => {
+                let size = self.file.as_ref().unwrap().len() as u64;
+                // Held until the file is recorded, an error on the way drops it
+                let _reservation = quotas.reserve(state.config(), storage, session.user(), self.command.arg(0), size)?;
+
+                let existed = storage.size_of(self.command.arg(0))?.is_some();
+                storage.upload(self.command.arg(0), self.file.as_ref().unwrap(), session.user())?;
+                quotas.record(storage, session.user(), self.command.arg(0))?;
+                state.events().publish(session, Event::written(self.command.arg(0), existed));
+
+                // The client already has the file, dont send it back
+                self.file = None;
+            }
+            // Received an offer from the client; Reference the file if storage already has its content, otherwise ask for the file
+            CommandType::Upload => {
+                // Reject the file before it is sent if it would go over any limits
+                if let Some(size) = self.file_size {
+                    quotas.check(state.config(), storage, session.user(), self.command.arg(0), size)?;
+                }
+
+                let existed = storage.size_of(self.command.arg(0))?.is_some();
+                let linked = match self.content_hash.as_ref() {
+                    Some(hash) => storage.link(self.command.arg(0), hash, session.user())?,
+                    None => false,
+                };
+
+                if linked {
+                    quotas.record(storage, session.user(), self.command.arg(0))?;
+                    state.events().publish(session, Event::written(self.command.arg(0), existed));
+                } else {
+                    self.server_response.status = ServerResponseStatus::Continue;
+                    self.server_response.text = Some(String::from("Send the file"));
+
+                    // Let the client send only the blocks that changed if there is an older copy of the file
+                    let size = storage.size_of(self.command.arg(0))?.unwrap_or(0);
+                    if state.config().delta() && size >= delta::MIN_DELTA_SIZE {
+                        self.signatures = Some(Signatures::compute(&storage.read(self.command.arg(0))?));
+                    }
+                }
             }
             // Load text_data with a list of files the server has
-            CommandType::Catalog if self.current_location == Location::Server => {
-                let paths = fs::read_dir(".")?;
-                self.text_data = Some(String::new());
-
-                for path in paths {
-                    self.text_data.as_mut()
-                        .unwrap()
-                        .push_str(
-                            format!("{}\n", path?.path().display()).as_str().clone()
-                        );
+            CommandType::Catalog => {
+                self.text_data = Some(storage.catalog()?);
+            }
+            // Load text_data with a list of the kept versions of a file
+            CommandType::Versions => {
+                let versions = storage.versions(self.command.arg(0))?;
+                let mut text_data = String::new();
+
+                if versions.is_empty() {
+                    text_data.push_str(&format!("No previous versions of {}", self.command.arg(0)));
+                }
+
+                for version in versions {
+                    text_data.push_str(
+                        &format!(
+                            "{}: replaced {} ago, {} bytes\n",
+                            version.number,
+                            storage::format_age(storage::now().saturating_sub(version.time)),
+                            version.size,
+                        )
+                    );
+                }
+
+                self.text_data = Some(text_data);
+            }
+            // Replace a file with one of its previous versions
+            CommandType::Restore => {
+                let version = self.command.arg(1).parse()
+                    .map_err(|_| ServerError::new(ErrorCode::InvalidArgument, format!("Invalid version: {}", self.command.arg(1))))?;
+
+                storage.restore(self.command.arg(0), version)?;
+                quotas.record(storage, session.user(), self.command.arg(0))?;
+                state.events().publish(session, Event::Modified(storage::key(self.command.arg(0))));
+            }
+            // Move a file into the trash
+            CommandType::Delete => {
+                storage.delete(self.command.arg(0))?;
+                quotas.forget(storage, self.command.arg(0))?;
+                state.events().publish(session, Event::Deleted(storage::key(self.command.arg(0))));
+            }
+            // Load text_data with a list of the files in the trash
+            CommandType::Trash => {
+                let entries = storage.trash()?;
+                let mut text_data = String::new();
+
+                if entries.is_empty() {
+                    text_data.push_str("The trash is empty");
+                }
+
+                for entry in entries {
+                    text_data.push_str(
+                        &format!(
+                            "{}: deleted {} ago, {} bytes\n",
+                            entry.path,
+                            storage::format_age(storage::now().saturating_sub(entry.time)),
+                            entry.size,
+                        )
+                    );
+                }
+
+                self.text_data = Some(text_data);
+            }
+            // Move a file out of the trash
+            CommandType::Undelete => {
+                storage.undelete(self.command.arg(0))?;
+                quotas.record(storage, session.user(), self.command.arg(0))?;
+                state.events().publish(session, Event::Created(storage::key(self.command.arg(0))));
+            }
+            // Log the connection in as a user
+            CommandType::Login => {
+                session.login(state, self.command.arg(0), self.command.arg(1))?;
+            }
+            // Change a bandwidth limit, text_data is loaded with the limits
+            CommandType::Throttle => {
+                if !state.config().is_admin(session.user()) {
+                    return Err(ServerError::new(ErrorCode::PermissionDenied, "Only admins can change bandwidth limits").into());
+                }
+
+                let bandwidth = state.bandwidth();
+
+                if let Some(scope) = self.command.args.first() {
+                    let rate = self.command.args.get(1).ok_or(ServerError::new(ErrorCode::InvalidArgument, "Missing rate"))?;
+                    let rate = rate.parse().map_err(|_| ServerError::new(ErrorCode::InvalidArgument, format!("Invalid rate: {rate}")))?;
+
+                    match scope.as_str() {
+                        "global" => bandwidth.set_global_rate(rate),
+                        "client" => bandwidth.set_client_rate(rate),
+                        _ => return Err(ServerError::new(
+                            ErrorCode::InvalidArgument,
+                            format!("Unknown bandwidth limit: {scope}, use global or client"),
+                        ).into()),
+                    }
+                }
+
+                self.text_data = Some(format!(
+                    "global: {}, client: {}",
+                    throttle::format_rate(bandwidth.global_rate()),
+                    throttle::format_rate(bandwidth.client_rate()),
+                ));
+            }
+            // Switch the connection to multiplexing, this happens once the response is sent
+            CommandType::Mux => (),
+            // Subscribe the connection to the changes other clients make, the events are pushed once the response is sent
+            CommandType::Subscribe => {
+                let dir = self.command.args.first().map_or("", String::as_str);
+                let events = state.events().subscribe(session, dir);
+
+                session.set_subscription(events);
+            }
+            // Load text_data with the hash, size and path of every file in a directory, one file per line
+            CommandType::Manifest => {
+                let dir = self.command.args.first().map_or("", |dir| dir.trim_start_matches("./").trim_matches('/'));
+                let dir = if dir == "." { "" } else { dir };
+                let mut text_data = String::new();
+
+                for (path, size) in storage.files()? {
+                    if !dir.is_empty() && !path.starts_with(&format!("{dir}/")) {
+                        continue;
+                    }
+
+                    if let Some(hash) = storage.hash_of(&path)? {
+                        text_data.push_str(&format!("{hash} {size} {path}\n"));
+                    }
                 }
+
+                self.text_data = Some(text_data);
+            }
+            // Load text_data with the token of a new link to a file
+            CommandType::Link => {
+                if storage.size_of(self.command.arg(0))?.is_none() {
+                    return Err(ServerError::new(ErrorCode::NotFound, format!("{} does not exist", self.command.arg(0))).into());
+                }
+
+                let ttl = match self.command.args.get(1) {
+                    Some(ttl) => Some(
+                        storage::parse_age(ttl).ok_or_else(|| ServerError::new(ErrorCode::InvalidArgument, format!("Invalid ttl: {ttl}")))?
+                    ),
+                    None => None,
+                };
+                let max_downloads = match self.command.args.get(2) {
+                    Some(downloads) => match downloads.parse::<u64>() {
+                        Ok(downloads) if downloads > 0 => Some(downloads),
+                        _ => return Err(
+                            ServerError::new(ErrorCode::InvalidArgument, format!("Invalid amount of downloads: {downloads}")).into()
+                        ),
+                    },
+                    None => None,
+                };
+
+                self.text_data = Some(state.links().create(storage, self.command.arg(0), ttl, max_downloads)?);
+            }
+            // Revoke a link
+            CommandType::Unlink => {
+                state.links().revoke(storage, self.command.arg(0))?;
+            }
+            // Send the file a link is for, text_data is loaded with its path
+            CommandType::Redeem => {
+                let path = state.links().redeem(storage, self.command.arg(0))?;
+
+                self.file = Some(storage.read(&path)?);
+                self.text_data = Some(path);
+            }
+            // Load text_data with how much space the user has used and how much remains
+            CommandType::Quota => {
+                let user = session.user();
+                let used = quotas.usage(user);
+                let mut text_data = match state.config().user_quota(user) {
+                    Some(quota) => format!(
+                        "{user}: {used} of {quota} bytes used, {} bytes remaining\n",
+                        quota.saturating_sub(used),
+                    ),
+                    None => format!("{user}: {used} bytes used, no quota\n"),
+                };
+
+                let total = quotas.total_usage();
+                text_data.push_str(&match quotas.global() {
+                    Some(quota) => format!("server: {total} of {quota} bytes used, {} bytes remaining", quota.saturating_sub(total)),
+                    None => format!("server: {total} bytes used, no quota"),
+                });
+
+                self.text_data = Some(text_data);
             }
 
             _ => (),
@@ -294,27 +758,126 @@ impl Share {
 
         Ok(())
     }
+    /// Returns the CommandType of the command in the share
+    pub fn command_type(&self) -> &CommandType {
+        self.command.command_type()
+    }
+    /// Load the file data of an upload, along with its hash and size so it can be offered before the data is sent
+    pub fn load_file(&mut self, file: Vec<u8>) {
+        self.content_hash = Some(hex::encode(Sha256::digest(&file)));
+        self.file_size = Some(file.len() as u64);
+        self.file = Some(file);
+    }
+    /// Set the file data of the share
+    pub fn set_file(&mut self, file: Vec<u8>) {
+        self.file = Some(file);
+    }
+    /// Take the file data out of the share
+    pub fn take_file(&mut self) -> Option<Vec<u8>> {
+        self.file.take()
+    }
+    /// Returns the text data of the share
+    pub fn text_data(&self) -> Option<&str> {
+        self.text_data.as_deref()
+    }
+    /// Returns the text of the server response
+    pub fn server_text(&self) -> Option<&str> {
+        self.server_response.text.as_deref()
+    }
+    /// Returns true if the server reported that the command succeeded
+    pub fn succeeded(&self) -> bool {
+        self.server_response.status == ServerResponseStatus::Success
+    }
+    /// Returns the code of the error the command failed with, or None if it did not fail
+    pub fn error_code(&self) -> Option<ErrorCode> {
+        self.server_response.code
+    }
+    /// Returns the seconds to wait before sending more commands if the server blocked the command
+    pub fn retry_after(&self) -> Option<u64> {
+        match self.server_response.status {
+            ServerResponseStatus::Blocked { retry_after } => Some(retry_after),
+            _ => None,
+        }
+    }
     /// Set the server error response
-    pub fn set_error_response(&mut self, error: Box<dyn std::error::Error>) {
-        self.server_response.status = ServerResponseStatus::Error;
+    pub fn set_error_response(&mut self, error: FileShareError) {
+        self.server_response.status = match &error {
+            FileShareError::Blocked(blocked) => ServerResponseStatus::Blocked { retry_after: blocked.retry_after },
+            _ => ServerResponseStatus::Error,
+        };
+        self.server_response.code = Some(error.code());
         // Convert the error to a string
         self.server_response.text = Some(error.to_string());
     }
 }
 
+/// Write a frame to the given stream, a header containing the content length and a newline followed by the content
+pub(crate) fn write_frame(stream: &mut impl Write, content: &[u8]) -> Result<(), FileShareError> {
+    // Send a header containing the content length and a newline
+    stream.write_all(format!("{}\n", content.len()).as_bytes())?;
+
+    // Write the content to the stream
+    stream.write_all(content)?;
+
+    Ok(())
+}
+
+/// Read a frame written by write_frame, returning its content. The header is read a byte at a time so nothing past the frame is
+/// read, the server may push more frames right after it. Frames longer than max_len are rejected before their content is read
+pub(crate) fn read_frame(stream: &mut impl Read, max_len: Option<u64>) -> Result<Vec<u8>, FileShareError> {
+    // Read header, the header is formated like `content_length\n`
+    let mut header = Vec::new();
+    let mut byte = [0];
+
+    loop {
+        stream.read_exact(&mut byte)?;
+
+        match byte[0] {
+            b'\n' => break,
+            _ if header.len() >= 20 => return Err(FileShareError::Protocol(String::from("Frame header is too long"))),
+            byte => header.push(byte),
+        }
+    }
+
+    // Parse the header into a usize
+    let content_len: usize = String::from_utf8(header)
+        .ok()
+        .and_then(|header| header.trim().parse().ok())
+        .ok_or_else(|| FileShareError::Protocol(String::from("Frame header is not a length")))?;
+
+    if let Some(max_len) = max_len.filter(|max_len| content_len as u64 > *max_len) {
+        return Err(FileShareError::Protocol(format!("Frame of {content_len} bytes is larger than the limit of {max_len} bytes")));
+    }
+
+    // Read all the bytes making up the content, the buffer grows as they arrive instead of trusting the header with an allocation
+    let mut content = Vec::new();
+    stream.take(content_len as u64).read_to_end(&mut content)?;
+
+    if content.len() < content_len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed while reading a frame").into());
+    }
+
+    Ok(content)
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 /// Contains the status of the server
 enum ServerResponseStatus {
     Error,
     Success,
+    /// The server needs the file data of an offered share
+    Continue,
+    /// The client sent too many commands or failed to log in too often, it should wait retry_after seconds before sending more
+    Blocked { retry_after: u64 },
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-/// Contains the servers status, and an Option<String> that contains text or None
+/// Contains the servers status, an Option<String> that contains text or None, and the code of the error if the command failed
 struct ServerResponse {
     status: ServerResponseStatus,
 
     text: Option<String>,
+    code: Option<ErrorCode>,
 }
 
 impl ServerResponse {
@@ -323,6 +886,58 @@ impl ServerResponse {
         ServerResponse {
             status: ServerResponseStatus::Success,
             text: Some(String::from("OK")),
+            code: None,
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_round_trip() {
+        let mut stream = Vec::new();
+        write_frame(&mut stream, b"hello").unwrap();
+        write_frame(&mut stream, b"").unwrap();
+
+        let mut stream = stream.as_slice();
+        assert_eq!(read_frame(&mut stream, None).unwrap(), b"hello");
+        assert_eq!(read_frame(&mut stream, None).unwrap(), b"");
+    }
+
+    #[test]
+    fn frame_lengths_are_not_trusted() {
+        // A header claiming far more than is sent fails once the stream ends, instead of allocating the claimed length
+        let mut stream: &[u8] = b"18446744073709551615\nshort";
+        assert!(matches!(read_frame(&mut stream, None), Err(FileShareError::Io(error)) if error.kind() == io::ErrorKind::UnexpectedEof));
+
+        let mut stream: &[u8] = b"123456789012345678901\n";
+        assert!(matches!(read_frame(&mut stream, None), Err(FileShareError::Protocol(_))));
+        let mut stream: &[u8] = b"12a\n";
+        assert!(matches!(read_frame(&mut stream, None), Err(FileShareError::Protocol(_))));
+
+        let mut stream: &[u8] = b"6\nhello!";
+        assert!(matches!(read_frame(&mut stream, Some(5)), Err(FileShareError::Protocol(_))));
+        let mut stream: &[u8] = b"6\nhello!";
+        assert_eq!(read_frame(&mut stream, Some(6)).unwrap(), b"hello!");
+    }
+
+    #[test]
+    fn shares_are_checked_before_their_arguments_are_used() {
+        let config: Config = toml::from_str("[server]\nthread_count = 1\nips = []\nstorage = \"memory\"").unwrap();
+        let state = ServerState::build(config.server().unwrap()).unwrap();
+        let mut session = Session::new();
+        state.storage().write(".meta/secret", &[0; 100_000]).unwrap();
+
+        // Missing arguments are an error instead of a panic
+        let mut share = Share::new(ShareCommand::new(CommandType::Receive, Vec::new()), Location::Server);
+        assert!(matches!(share.execute_on_server(&state, &mut session), Err(FileShareError::Parse(_))));
+
+        // A delta on anything but an upload is rejected before its path is read
+        let mut share = Share::new(ShareCommand::new(CommandType::Manifest, vec![String::from(".meta/secret")]), Location::Server);
+        share.delta = Some(Delta::compute(&Signatures::compute(&[0; 100_000]), &[0; 100_000]));
+        share.file_size = Some(100_000);
+        let error = share.execute_on_server(&state, &mut session).unwrap_err();
+        assert_eq!(error.code(), ErrorCode::InvalidArgument);
+    }
+}
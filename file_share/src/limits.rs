@@ -0,0 +1,282 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{config, server::{Session, ANONYMOUS}};
+
+/// Length of the window commands are counted in, the rate limits are per window
+const WINDOW: Duration = Duration::from_secs(60);
+/// Once this many clients are tracked, clients with nothing left to remember are forgotten
+const PRUNE_AT: usize = 1024;
+
+/// Error returned when a client is blocked, shares that fail with it get the Blocked status so clients know to wait before trying
+/// again
+#[derive(Debug)]
+pub struct Blocked {
+    /// Seconds until the client may try again
+    pub retry_after: u64,
+    reason: &'static str,
+}
+
+impl fmt::Display for Blocked {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}, try again in {}s", self.reason, self.retry_after)
+    }
+}
+
+impl std::error::Error for Blocked {}
+
+/// What a record is kept for
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+enum Key {
+    /// Every connection from an ip
+    Ip(IpAddr),
+    /// Every connection logged in as a user
+    User(String),
+    /// Attempts to log in as a user, banning these blocks logging in as the user without logging out the sessions that already have
+    Login(String),
+}
+
+struct Record {
+    /// Start of the current window and the amount of commands received in it
+    window: Instant,
+    commands: u64,
+    /// Time of the first failure that still counts and the amount of failures since
+    first_failure: Instant,
+    failures: u64,
+    banned_until: Option<Instant>,
+}
+
+impl Record {
+    fn new(now: Instant) -> Record {
+        Record {
+            window: now,
+            commands: 0,
+            first_failure: now,
+            failures: 0,
+            banned_until: None,
+        }
+    }
+    /// Returns true if the record still limits its client
+    fn active(&self, now: Instant, ban_time: Duration) -> bool {
+        self.banned_until.is_some_and(|until| until > now)
+            || now.duration_since(self.window) < WINDOW
+            || (self.failures > 0 && now.duration_since(self.first_failure) < ban_time)
+    }
+}
+
+/// Limits how many commands each ip and user may send per minute, and bans ips and user names for a while after too many failed
+/// logins
+pub struct Limits {
+    rate_limit: u64,
+    user_rate_limit: u64,
+    max_login_failures: u64,
+    ban_time: Duration,
+
+    records: Mutex<HashMap<Key, Record>>,
+}
+
+impl Limits {
+    pub fn new(config: &config::Server) -> Limits {
+        Limits {
+            rate_limit: config.rate_limit(),
+            user_rate_limit: config.user_rate_limit(),
+            max_login_failures: config.max_login_failures(),
+            ban_time: Duration::from_secs(config.ban_time()),
+            records: Mutex::new(HashMap::new()),
+        }
+    }
+    /// Count a command from the session, returns an error if its ip is banned or it went over a rate limit
+    pub fn check(&self, session: &Session) -> Result<(), Blocked> {
+        self.check_at(session, Instant::now())
+    }
+    /// Returns an error if logging in as the user is banned
+    pub fn check_login(&self, user: &str) -> Result<(), Blocked> {
+        self.check_login_at(user, Instant::now())
+    }
+    /// Count a failed login as user from the session, the ip of the session and the user name are banned once either has too many
+    /// failures within the ban time
+    pub fn record_failure(&self, session: &Session, user: &str) {
+        self.record_failure_at(session, user, Instant::now())
+    }
+    /// Count a command from the session received at now, see check
+    fn check_at(&self, session: &Session, now: Instant) -> Result<(), Blocked> {
+        let mut keys = Vec::new();
+
+        if let Some(address) = session.address() {
+            keys.push((Key::Ip(address), self.rate_limit));
+        }
+        // Clients that did not log in are only limited by their ip
+        if session.user() != ANONYMOUS {
+            keys.push((Key::User(session.user().to_string()), self.user_rate_limit));
+        }
+
+        let mut records = self.records.lock().unwrap();
+
+        for (key, limit) in keys {
+            let record = records.entry(key).or_insert_with(|| Record::new(now));
+
+            if let Some(until) = record.banned_until.filter(|until| *until > now) {
+                return Err(Blocked {
+                    retry_after: seconds(until - now),
+                    reason: "Banned after too many failed logins",
+                });
+            }
+
+            if now.duration_since(record.window) >= WINDOW {
+                record.window = now;
+                record.commands = 0;
+            }
+            record.commands += 1;
+
+            if limit != 0 && record.commands > limit {
+                return Err(Blocked {
+                    retry_after: seconds(WINDOW.saturating_sub(now.duration_since(record.window))),
+                    reason: "Too many requests",
+                });
+            }
+        }
+
+        self.prune(&mut records, now);
+
+        Ok(())
+    }
+    /// Returns an error if logging in as the user is banned at now
+    fn check_login_at(&self, user: &str, now: Instant) -> Result<(), Blocked> {
+        let records = self.records.lock().unwrap();
+
+        match records.get(&Key::Login(user.to_string())).and_then(|record| record.banned_until) {
+            Some(until) if until > now => Err(Blocked {
+                retry_after: seconds(until - now),
+                reason: "Logging in as this user is blocked after too many failed logins",
+            }),
+            _ => Ok(()),
+        }
+    }
+    /// Count a failed login as user from the session at now, see record_failure
+    fn record_failure_at(&self, session: &Session, user: &str, now: Instant) {
+        let mut keys = vec![Key::Login(user.to_string())];
+
+        if let Some(address) = session.address() {
+            keys.push(Key::Ip(address));
+        }
+
+        let mut records = self.records.lock().unwrap();
+
+        for key in keys {
+            let record = records.entry(key.clone()).or_insert_with(|| Record::new(now));
+
+            if now.duration_since(record.first_failure) >= self.ban_time {
+                record.first_failure = now;
+                record.failures = 0;
+            }
+            record.failures += 1;
+
+            if self.max_login_failures != 0 && record.failures >= self.max_login_failures {
+                eprintln!("Banned {key:?} for {}s after {} failed logins", self.ban_time.as_secs(), record.failures);
+
+                record.banned_until = Some(now + self.ban_time);
+                record.failures = 0;
+            }
+        }
+    }
+    /// Forget the clients that are no longer limited, so clients that come and go dont use up memory
+    fn prune(&self, records: &mut HashMap<Key, Record>, now: Instant) {
+        if records.len() >= PRUNE_AT {
+            records.retain(|_, record| record.active(now, self.ban_time));
+        }
+    }
+}
+
+/// Returns a duration in whole seconds, rounded up so clients dont try again too early
+fn seconds(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    /// Returns limits using the given lines of server configuration
+    fn limits(settings: &str) -> Limits {
+        let config: Config = toml::from_str(&format!("[server]\nthread_count = 1\nips = []\n{settings}")).unwrap();
+
+        Limits::new(&config.server().unwrap())
+    }
+
+    /// Returns a session of a client connected from the given ip
+    fn from(ip: &str) -> Session {
+        Session::for_listener(None, Some(ip.parse().unwrap()))
+    }
+
+    #[test]
+    fn commands_are_limited_per_ip_for_a_minute() {
+        let limits = limits("rate_limit = 3");
+        let start = Instant::now();
+        let (client, other) = (from("10.0.0.1"), from("10.0.0.2"));
+
+        for _ in 0..3 {
+            limits.check_at(&client, start).unwrap();
+        }
+        let blocked = limits.check_at(&client, start + Duration::from_secs(20)).unwrap_err();
+        assert_eq!(blocked.retry_after, 40);
+        limits.check_at(&other, start).unwrap();
+
+        // The next window starts a minute after the first command of the last one
+        limits.check_at(&client, start + WINDOW).unwrap();
+    }
+
+    #[test]
+    fn commands_are_limited_per_user() {
+        let limits = limits("user_rate_limit = 2");
+        let start = Instant::now();
+
+        // Both sessions are the same user, without an ip
+        limits.check_at(&Session::for_peer(1000), start).unwrap();
+        limits.check_at(&Session::for_peer(1000), start).unwrap();
+        assert!(limits.check_at(&Session::for_peer(1000), start).is_err());
+        limits.check_at(&Session::for_peer(1001), start).unwrap();
+    }
+
+    #[test]
+    fn failed_logins_ban_the_ip_and_user_until_the_ban_time_is_over() {
+        let limits = limits("max_login_failures = 3\nban_time = 300");
+        let start = Instant::now();
+        let client = from("10.0.0.1");
+
+        for i in 0..3 {
+            limits.check_login_at("alice", start).unwrap();
+            limits.record_failure_at(&client, "alice", start + Duration::from_secs(i));
+        }
+
+        let banned = limits.check_at(&client, start + Duration::from_secs(2)).unwrap_err();
+        assert_eq!(banned.retry_after, 300);
+        assert!(limits.check_login_at("alice", start + Duration::from_secs(2)).is_err());
+        // Other clients can still log in as other users
+        limits.check_at(&from("10.0.0.2"), start).unwrap();
+        limits.check_login_at("bob", start).unwrap();
+
+        let over = start + Duration::from_secs(302);
+        limits.check_at(&client, over).unwrap();
+        limits.check_login_at("alice", over).unwrap();
+    }
+
+    #[test]
+    fn failures_only_count_within_the_ban_time() {
+        let limits = limits("max_login_failures = 3\nban_time = 300");
+        let start = Instant::now();
+        let client = from("10.0.0.1");
+
+        limits.record_failure_at(&client, "alice", start);
+        limits.record_failure_at(&client, "alice", start);
+        limits.record_failure_at(&client, "alice", start + Duration::from_secs(300));
+
+        limits.check_at(&client, start + Duration::from_secs(300)).unwrap();
+        limits.check_login_at("alice", start + Duration::from_secs(300)).unwrap();
+    }
+}
@@ -0,0 +1,237 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{config, storage::{self, Storage}, error::{ErrorCode, ServerError}, FileShareError};
+
+/// Name of the metadata file the links are saved in
+const LINKS_META: &str = "links";
+/// Name of the metadata file the generated signing secret is saved in
+const SECRET_META: &str = "link-secret";
+
+/// A link that lets anyone holding its token download a single file
+#[derive(Serialize, Deserialize)]
+struct Link {
+    /// Path of the file the link is for
+    path: String,
+    /// Time (in seconds since the unix epoch) the link stops working
+    expires: u64,
+    /// Amount of downloads left, or None if the link can be used until it expires
+    downloads_left: Option<u64>,
+}
+
+/// Keeps track of the download links that have been created. Tokens are formated like `id.expires.signature`, the signature is an
+/// HMAC of the id and expiry time so tokens cant be made up or changed
+pub struct Links {
+    secret: Vec<u8>,
+    /// How long (in seconds) links work for when no ttl is given
+    default_ttl: u64,
+
+    /// Map of link ids to links
+    links: Mutex<HashMap<String, Link>>,
+}
+
+impl Links {
+    /// Load the links from storage. Tokens are signed with the secret in the server configuration, or with a secret generated the
+    /// first time the server runs
+    pub fn build(config: &config::Server, storage: &Storage) -> Result<Links, FileShareError> {
+        let secret = match config.link_secret() {
+            Some(secret) => secret.as_bytes().to_vec(),
+            None => match storage.load_meta(SECRET_META)? {
+                Some(secret) => secret,
+                None => {
+                    let secret = storage::random_bytes(32)?;
+                    storage.save_meta(SECRET_META, &secret)?;
+
+                    secret
+                }
+            },
+        };
+
+        let links = match storage.load_meta(LINKS_META)? {
+            Some(data) => bincode::deserialize(&data)?,
+            None => HashMap::new(),
+        };
+
+        Ok(Links {
+            secret,
+            default_ttl: config.link_ttl(),
+            links: Mutex::new(links),
+        })
+    }
+    /// Create a link for the file at path that works for ttl seconds (or the default ttl), and optionally only for a limited amount
+    /// of downloads. Returns the token of the link
+    pub fn create(
+        &self,
+        storage: &Storage,
+        path: &str,
+        ttl: Option<u64>,
+        max_downloads: Option<u64>,
+    ) -> Result<String, FileShareError> {
+        let ttl = ttl.unwrap_or(self.default_ttl);
+        let expires = storage::now()
+            .checked_add(ttl)
+            .ok_or(ServerError::new(ErrorCode::InvalidArgument, format!("Link ttl is too long: {ttl} seconds")))?;
+        let id = hex::encode(storage::random_bytes(16)?);
+
+        self.links.lock().unwrap().insert(
+            id.clone(),
+            Link {
+                path: storage::key(path),
+                expires,
+                downloads_left: max_downloads,
+            },
+        );
+        self.save(storage)?;
+
+        Ok(format!("{id}.{expires}.{}", self.sign(&id, expires)))
+    }
+    /// Use a link, returning the path of the file it is for. Links that run out of downloads are removed
+    pub fn redeem(&self, storage: &Storage, token: &str) -> Result<String, FileShareError> {
+        let id = self.verify(token)?;
+        let mut links = self.links.lock().unwrap();
+
+        let link = links.get_mut(&id).ok_or(ServerError::new(ErrorCode::NotFound, "Link does not exist or was revoked"))?;
+        if link.expires <= storage::now() {
+            return Err(ServerError::new(ErrorCode::NotFound, "Link has expired").into());
+        }
+
+        let path = link.path.clone();
+
+        if let Some(downloads_left) = link.downloads_left.as_mut() {
+            *downloads_left -= 1;
+
+            if *downloads_left == 0 {
+                links.remove(&id);
+            }
+        }
+
+        drop(links);
+        self.save(storage)?;
+
+        Ok(path)
+    }
+    /// Revoke a link so its token stops working
+    pub fn revoke(&self, storage: &Storage, token: &str) -> Result<(), FileShareError> {
+        let id = self.verify(token)?;
+
+        if self.links.lock().unwrap().remove(&id).is_none() {
+            return Err(ServerError::new(ErrorCode::NotFound, "Link does not exist or was already revoked").into());
+        }
+
+        self.save(storage)
+    }
+    /// Check the signature of a token, returning the id of the link
+    fn verify(&self, token: &str) -> Result<String, FileShareError> {
+        let invalid = || ServerError::new(ErrorCode::InvalidArgument, "Invalid link");
+        let mut parts = token.split('.');
+
+        let (id, expires, signature) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(id), Some(expires), Some(signature), None) => (id, expires, signature),
+            _ => return Err(invalid().into()),
+        };
+        let expires: u64 = expires.parse().map_err(|_| invalid())?;
+
+        let mut mac = self.mac();
+        mac.update(format!("{id}.{expires}").as_bytes());
+        mac.verify_slice(&hex::decode(signature).map_err(|_| invalid())?).map_err(|_| invalid())?;
+
+        Ok(id.to_string())
+    }
+    /// Returns the signature of a link as hex
+    fn sign(&self, id: &str, expires: u64) -> String {
+        let mut mac = self.mac();
+        mac.update(format!("{id}.{expires}").as_bytes());
+
+        hex::encode(mac.finalize().into_bytes())
+    }
+    fn mac(&self) -> Hmac<Sha256> {
+        Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC can take a key of any size")
+    }
+    /// Save the links to storage, expired links are dropped
+    fn save(&self, storage: &Storage) -> Result<(), FileShareError> {
+        let mut links = self.links.lock().unwrap();
+        let now = storage::now();
+        links.retain(|_, link| link.expires > now);
+
+        storage.save_meta(LINKS_META, &bincode::serialize(&*links)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    /// Returns a server configuration keeping files in memory, and its storage
+    fn storage() -> (config::Server, Storage) {
+        let config: Config = toml::from_str("[server]\nthread_count = 1\nips = []\nstorage = \"memory\"").unwrap();
+        let config = config.server().unwrap();
+        let storage = Storage::build(&config).unwrap();
+
+        (config, storage)
+    }
+
+    #[test]
+    fn links_are_redeemed_until_they_run_out() {
+        let (config, storage) = storage();
+        let links = Links::build(&config, &storage).unwrap();
+
+        let token = links.create(&storage, "./dir/a.txt", None, None).unwrap();
+        assert_eq!(links.redeem(&storage, &token).unwrap(), "dir/a.txt");
+        assert_eq!(links.redeem(&storage, &token).unwrap(), "dir/a.txt");
+
+        let token = links.create(&storage, "a.txt", Some(60), Some(2)).unwrap();
+        assert_eq!(links.redeem(&storage, &token).unwrap(), "a.txt");
+        assert_eq!(links.redeem(&storage, &token).unwrap(), "a.txt");
+        assert_eq!(links.redeem(&storage, &token).unwrap_err().code(), ErrorCode::NotFound);
+
+        // Links and the generated secret are kept in storage
+        let token = links.create(&storage, "b.txt", None, Some(1)).unwrap();
+        let reloaded = Links::build(&config, &storage).unwrap();
+        assert_eq!(reloaded.redeem(&storage, &token).unwrap(), "b.txt");
+    }
+
+    #[test]
+    fn expired_and_revoked_links_stop_working() {
+        let (config, storage) = storage();
+        let links = Links::build(&config, &storage).unwrap();
+
+        let token = links.create(&storage, "a.txt", Some(0), None).unwrap();
+        assert_eq!(links.redeem(&storage, &token).unwrap_err().code(), ErrorCode::NotFound);
+
+        let token = links.create(&storage, "a.txt", None, None).unwrap();
+        links.revoke(&storage, &token).unwrap();
+        assert_eq!(links.redeem(&storage, &token).unwrap_err().code(), ErrorCode::NotFound);
+        assert_eq!(links.revoke(&storage, &token).unwrap_err().code(), ErrorCode::NotFound);
+
+        assert_eq!(links.create(&storage, "a.txt", Some(u64::MAX), None).unwrap_err().code(), ErrorCode::InvalidArgument);
+    }
+
+    #[test]
+    fn tokens_cant_be_changed() {
+        let (config, storage) = storage();
+        let links = Links::build(&config, &storage).unwrap();
+        let token = links.create(&storage, "a.txt", Some(60), None).unwrap();
+        let (id, rest) = token.split_once('.').unwrap();
+        let (expires, signature) = rest.split_once('.').unwrap();
+
+        // Pushing the expiry back breaks the signature
+        let later = expires.parse::<u64>().unwrap() + 3600;
+        let changed = [
+            format!("{id}.{later}.{signature}"),
+            format!("{id}.{expires}.{}", "0".repeat(64)),
+            format!("{id}.{expires}"),
+        ];
+        for token in changed {
+            assert_eq!(links.redeem(&storage, &token).unwrap_err().code(), ErrorCode::InvalidArgument);
+        }
+
+        // Tokens signed with another secret are not accepted either
+        let other = "[server]\nthread_count = 1\nips = []\nstorage = \"memory\"\nlink_secret = \"other\"";
+        let other = Links::build(&toml::from_str::<Config>(other).unwrap().server().unwrap(), &storage).unwrap();
+        assert_eq!(other.redeem(&storage, &token).unwrap_err().code(), ErrorCode::InvalidArgument);
+    }
+}
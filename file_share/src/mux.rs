@@ -0,0 +1,402 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    connection::Transport,
+    read_frame,
+    server::{ServerState, Session},
+    error::{ErrorCode, ServerError},
+    write_frame, CommandType, FileShareError, Location, Share, ShareCommand,
+};
+
+/// Largest amount of share data sent in one frame, shares are split into chunks this size so a large transfer cant hold up the
+/// other streams
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Largest frame read on a multiplexed connection, a chunk and the few bytes describing it
+const MAX_FRAME: u64 = CHUNK_SIZE as u64 + 1024;
+
+/// Most streams open on a connection at once, a stream is open from its first chunk until the last chunk of its response was sent
+const MAX_STREAMS: usize = 16;
+
+/// Most share data the server buffers for the streams it is still receiving, the client does not send shares larger than this
+const MAX_BUFFERED: usize = 256 * 1024 * 1024;
+
+/// How long the server waits for a command to finish before answering a frame that carried no data, so a client waiting on a
+/// response does not ask for it in a tight loop
+const POLL_WAIT: Duration = Duration::from_millis(50);
+
+/// Stream id of frames that carry no data, the client sends them to ask for response data and the server replies with them when it
+/// has none
+const NO_STREAM: u32 = 0;
+
+/// A chunk of a share sent on a multiplexed connection. Every frame the client sends is answered by exactly one frame from the
+/// server, so neither side has to read and write at the same time
+#[derive(Serialize, Deserialize)]
+struct Frame {
+    /// Id of the stream the chunk belongs to, every command sent gets its own stream
+    stream: u32,
+    /// True if this is the last chunk of the share
+    last: bool,
+    data: Vec<u8>,
+}
+
+impl Frame {
+    fn empty() -> Frame {
+        Frame {
+            stream: NO_STREAM,
+            last: false,
+            data: Vec::new(),
+        }
+    }
+    fn write(&self, stream: &mut impl Transport) -> Result<(), FileShareError> {
+        write_frame(stream, &bincode::serialize(self)?)?;
+        stream.flush()?;
+
+        Ok(())
+    }
+    fn read(stream: &mut impl Transport) -> Result<Frame, FileShareError> {
+        Ok(bincode::deserialize(&read_frame(stream, Some(MAX_FRAME))?)?)
+    }
+}
+
+/// Shares waiting to be sent, a chunk is taken from each stream in turn
+#[derive(Default)]
+struct Outgoing {
+    /// Streams with data left to send, and how much of it was already sent
+    streams: VecDeque<(u32, Vec<u8>, usize)>,
+}
+
+impl Outgoing {
+    fn push(&mut self, stream: u32, data: Vec<u8>) {
+        self.streams.push_back((stream, data, 0));
+    }
+    fn is_empty(&self) -> bool {
+        self.streams.is_empty()
+    }
+    fn len(&self) -> usize {
+        self.streams.len()
+    }
+    /// Returns the next chunk to send, or an empty frame if there is nothing to send
+    fn next_frame(&mut self) -> Frame {
+        let Some((stream, data, sent)) = self.streams.pop_front() else {
+            return Frame::empty();
+        };
+
+        let end = data.len().min(sent + CHUNK_SIZE);
+        let frame = Frame {
+            stream,
+            last: end == data.len(),
+            data: data[sent..end].to_vec(),
+        };
+
+        // Move the stream to the back so the other streams get their turn first
+        if end < data.len() {
+            self.streams.push_back((stream, data, end));
+        }
+
+        frame
+    }
+}
+
+/// A share sent on a stream of a multiplexed connection
+struct Request {
+    share: Share,
+    /// Amount of bytes sent for the share, or 0 while it waits for the stream to be opened
+    size: usize,
+    /// Response data received so far
+    response: Vec<u8>,
+}
+
+/// Client side of a multiplexed connection, several commands can be in flight on it at once
+pub struct Multiplexer<T: Transport> {
+    stream: T,
+    next_id: u32,
+    outgoing: Outgoing,
+    /// Shares that have not received their response yet, by the id of their stream
+    requests: HashMap<u32, Request>,
+    /// Streams waiting to be opened until the server has room for them, with the data to send on them
+    waiting: VecDeque<(u32, Vec<u8>)>,
+}
+
+impl<T: Transport> Multiplexer<T> {
+    /// Switch a connection to multiplexing, every share is sent through the multiplexer after this
+    pub fn start(mut stream: T) -> Result<Multiplexer<T>, FileShareError> {
+        let mut share = Share::new(ShareCommand::new(CommandType::Mux, Vec::new()), Location::Client);
+        let response = share.exchange(&mut stream)?;
+
+        if !response.succeeded() {
+            let code = response.error_code().unwrap_or(ErrorCode::Internal);
+
+            return Err(ServerError::new(code, response.server_text().unwrap_or("The server refused to multiplex")).into());
+        }
+
+        Ok(Multiplexer {
+            stream,
+            next_id: NO_STREAM + 1,
+            outgoing: Outgoing::default(),
+            requests: HashMap::new(),
+            waiting: VecDeque::new(),
+        })
+    }
+    /// Queue a share to be sent, returns the id of the stream its response arrives on. Shares with file data are offered first like
+    /// on a connection that is not multiplexed, the data (or a delta) is only sent if the server needs it
+    pub fn send(&mut self, mut share: Share) -> Result<u32, FileShareError> {
+        if bincode::serialized_size(&share)? > MAX_BUFFERED as u64 {
+            let message = "Share is too large to send on a multiplexed connection";
+
+            return Err(io::Error::new(io::ErrorKind::FileTooLarge, message).into());
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let data = match share.offer_bytes()? {
+            Some(offer) => offer,
+            None => share.data_bytes()?,
+        };
+        self.requests.insert(id, Request { share, size: 0, response: Vec::new() });
+        self.waiting.push_back((id, data));
+
+        Ok(id)
+    }
+    /// Returns the amount of shares that have not received their response yet
+    pub fn pending(&self) -> usize {
+        self.requests.len()
+    }
+    /// Send the next chunk (or ask for response data if there is nothing to send) and read the reply. Returns the id of the stream
+    /// and its response if the reply completed one
+    pub fn poll(&mut self) -> Result<Option<(u32, Share)>, FileShareError> {
+        self.open_waiting();
+
+        self.outgoing.next_frame().write(&mut self.stream)?;
+        let frame = Frame::read(&mut self.stream)?;
+
+        if frame.stream == NO_STREAM {
+            return Ok(None);
+        }
+
+        let request = self.requests.get_mut(&frame.stream)
+            .ok_or_else(|| FileShareError::Protocol(String::from("Server replied on a stream that does not exist")))?;
+        request.response.extend_from_slice(&frame.data);
+
+        if !frame.last {
+            return Ok(None);
+        }
+
+        let Some(mut request) = self.requests.remove(&frame.stream) else {
+            return Ok(None);
+        };
+        let response = Share::from_bytes(&request.response, Location::Client)?;
+
+        // The server asked for the file data of an offered share, it is sent on the same stream before anything that is waiting
+        if request.share.offer_accepted(&response) {
+            let data = request.share.data_bytes()?;
+
+            self.requests.insert(frame.stream, Request { share: request.share, size: 0, response: Vec::new() });
+            self.waiting.push_front((frame.stream, data));
+
+            return Ok(None);
+        }
+
+        Ok(Some((frame.stream, response)))
+    }
+    /// Open the waiting streams the server has room for, the server only holds MAX_STREAMS streams and MAX_BUFFERED bytes of them
+    fn open_waiting(&mut self) {
+        while let Some((_, data)) = self.waiting.front() {
+            let open = self.requests.values().filter(|request| request.size > 0).count();
+            let buffered: usize = self.requests.values().map(|request| request.size).sum();
+
+            if open >= MAX_STREAMS || (open > 0 && buffered + data.len() > MAX_BUFFERED) {
+                return;
+            }
+
+            let Some((id, data)) = self.waiting.pop_front() else {
+                return;
+            };
+            if let Some(request) = self.requests.get_mut(&id) {
+                request.size = data.len();
+            }
+            self.outgoing.push(id, data);
+        }
+    }
+}
+
+/// Server side of a multiplexed connection. Every frame received is answered with the next chunk of the responses waiting to be
+/// sent, a share is executed on its own thread once all of its chunks arrived so a slow command does not hold up the other streams
+pub(crate) fn serve(mut stream: impl Transport, session: &mut Session, state: &ServerState) {
+    let (sender, finished) = mpsc::channel();
+
+    thread::scope(|scope| {
+        // Data received so far for the streams that are still being received, and the total amount of it
+        let mut incoming: HashMap<u32, Vec<u8>> = HashMap::new();
+        let mut buffered = 0;
+        // Amount of streams that are executing
+        let mut running = 0;
+        let mut outgoing = Outgoing::default();
+
+        loop {
+            let frame = match Frame::read(&mut stream) {
+                Ok(frame) => frame,
+                Err(error) => {
+                    eprintln!("{error}");
+                    return;
+                }
+            };
+            let id = frame.stream;
+
+            if id != NO_STREAM {
+                if !incoming.contains_key(&id) && incoming.len() + running + outgoing.len() >= MAX_STREAMS {
+                    eprintln!("Client opened more than {MAX_STREAMS} streams");
+                    return;
+                }
+
+                buffered += frame.data.len();
+                if buffered > MAX_BUFFERED {
+                    eprintln!("Client sent more than {MAX_BUFFERED} bytes of unfinished shares");
+                    return;
+                }
+
+                incoming.entry(id).or_default().extend_from_slice(&frame.data);
+            }
+
+            if id != NO_STREAM && frame.last {
+                let data = incoming.remove(&id).unwrap_or_default();
+                buffered -= data.len();
+
+                let share = match Share::from_bytes(&data, Location::Server) {
+                    Ok(share) => share,
+                    Err(error) => {
+                        eprintln!("{error}");
+                        return;
+                    }
+                };
+
+                // Logging in changes the session the later commands run with, so it runs on the session instead of a copy of it
+                if *share.command_type() == CommandType::Login {
+                    let _ = sender.send((id, execute(share, session, state)));
+                } else {
+                    let sender = sender.clone();
+                    let mut session = session.fork();
+
+                    scope.spawn(move || {
+                        let _ = sender.send((id, execute(share, &mut session, state)));
+                    });
+                }
+                running += 1;
+            }
+
+            // A frame without data is answered once a command finishes (or after a moment), so the client isnt busy asking
+            let waited = match id == NO_STREAM && outgoing.is_empty() && running > 0 {
+                true => finished.recv_timeout(POLL_WAIT).ok(),
+                false => None,
+            };
+
+            for (id, response) in waited.into_iter().chain(finished.try_iter()) {
+                running -= 1;
+
+                match response {
+                    Ok(response) => outgoing.push(id, response),
+                    Err(error) => {
+                        eprintln!("{error}");
+                        return;
+                    }
+                }
+            }
+
+            if let Err(error) = outgoing.next_frame().write(&mut stream) {
+                eprintln!("Failed to write to stream: {error}");
+                return;
+            }
+        }
+    });
+}
+
+/// Execute a share received on a multiplexed connection, returns the response to send back
+fn execute(mut share: Share, session: &mut Session, state: &ServerState) -> Result<Vec<u8>, bincode::Error> {
+    // These change what the connection carries, which a single stream cant do
+    let result = match share.command_type() {
+        CommandType::Mux | CommandType::Subscribe => Err(ServerError::new(ErrorCode::InvalidArgument, "Command cant be multiplexed").into()),
+        _ => match state.limits().check(session) {
+            Ok(_) => share.execute_on_server(state, session),
+            Err(blocked) => Err(blocked.into()),
+        },
+    };
+
+    if let Err(error) = result {
+        share.set_error_response(error);
+    }
+
+    bincode::serialize(&share)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, connection::pipe, server};
+
+    /// Poll the multiplexer until every share got its response, returns the responses by stream id
+    fn finish(mux: &mut Multiplexer<crate::connection::Pipe>) -> HashMap<u32, Share> {
+        let mut responses = HashMap::new();
+
+        while mux.pending() > 0 {
+            if let Some((id, response)) = mux.poll().unwrap() {
+                responses.insert(id, response);
+            }
+        }
+
+        responses
+    }
+
+    fn share(command_type: CommandType, path: &str, file: Option<Vec<u8>>) -> Share {
+        let mut share = Share::new(ShareCommand::new(command_type, vec![path.to_string()]), Location::Client);
+
+        if let Some(file) = file {
+            share.load_file(file);
+        }
+
+        share
+    }
+
+    #[test]
+    fn streams_run_alongside_each_other() {
+        let (client, server) = pipe();
+        let handle = thread::spawn(move || {
+            let config: Config = toml::from_str("[server]\nthread_count = 1\nips = []\nstorage = \"memory\"").unwrap();
+
+            server::handle_client(server, server::Session::new(), &ServerState::build(config.server().unwrap()).unwrap())
+        });
+        let mut mux = Multiplexer::start(client).unwrap();
+
+        // More shares than the server holds streams for, the rest wait for a stream to finish
+        let file = |index: u32| (0..100_000u32).map(|byte| (byte * index % 251) as u8).collect::<Vec<u8>>();
+        for index in 0..MAX_STREAMS as u32 * 2 {
+            mux.send(share(CommandType::Upload, &format!("{index}.bin"), Some(file(index)))).unwrap();
+        }
+        assert!(finish(&mut mux).values().all(Share::succeeded));
+
+        // A changed file is offered and sent as a delta on its stream
+        let mut changed = file(3);
+        changed[50_000] ^= 0xff;
+        mux.send(share(CommandType::Upload, "3.bin", Some(changed.clone()))).unwrap();
+        assert!(finish(&mut mux).values().all(Share::succeeded));
+
+        // Streams run at the same time, so commands that depend on each other wait for the earlier ones to finish
+        let receive = mux.send(share(CommandType::Receive, "3.bin", None)).unwrap();
+        let missing = mux.send(share(CommandType::Receive, "missing.bin", None)).unwrap();
+
+        let mut responses = finish(&mut mux);
+        assert!(responses.get_mut(&receive).unwrap().take_file() == Some(changed));
+        assert_eq!(responses[&missing].error_code(), Some(ErrorCode::NotFound));
+
+        drop(mux);
+        handle.join().unwrap();
+    }
+}
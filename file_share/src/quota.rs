@@ -0,0 +1,288 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use crate::{config, storage::{self, Storage}, error::{ErrorCode, ServerError}, FileShareError};
+
+/// Name of the metadata directory the owners of files are saved in, the owner of each file is saved on its own at the path of the
+/// file inside it so uploads dont rewrite every owner
+const OWNERS_META: &str = "file-owners";
+
+/// Keeps track of how many bytes each user stores, and checks uploads against the quotas in the server configuration. Only the
+/// current files count, previous versions and files in the trash dont (they are removed after version_age and trash_age)
+pub struct Quotas {
+    /// Amount of bytes all users together may store
+    global: Option<u64>,
+    /// Largest file (in bytes) that may be uploaded
+    max_file_size: Option<u64>,
+
+    /// Owners of the stored files and the uploads that are being stored, both are behind one lock so checking an upload and
+    /// reserving its bytes happens at once
+    usage: Mutex<Usage>,
+}
+
+/// Bytes stored by and reserved for users
+#[derive(Default)]
+struct Usage {
+    /// Map of file paths to the user that owns the file and the size of the file
+    owners: HashMap<String, (String, u64)>,
+    /// Map of reservation ids to the user that is uploading and the size of the upload
+    reserved: HashMap<u64, (String, u64)>,
+    /// Id given to the next reservation
+    next_id: u64,
+}
+
+impl Usage {
+    /// Returns the amount of bytes the given user stores and has reserved, or all users if user is None
+    fn used(&self, user: Option<&str>) -> u64 {
+        let stored = self.owners.values().filter(|(owner, _)| user.is_none_or(|user| owner == user)).map(|(_, size)| size);
+        let reserved = self.reserved.values().filter(|(owner, _)| user.is_none_or(|user| owner == user)).map(|(_, size)| size);
+
+        stored.chain(reserved).sum()
+    }
+}
+
+/// Bytes reserved for an upload that is being stored, they count towards the quotas until the reservation is dropped. The file is
+/// recorded before the reservation is dropped, so its bytes are counted twice for a moment rather than not at all
+pub struct Reservation<'a> {
+    quotas: &'a Quotas,
+    id: u64,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.quotas.usage.lock().unwrap().reserved.remove(&self.id);
+    }
+}
+
+impl Quotas {
+    /// Load the owners of files from storage, files that were stored before quotas were tracked are not owned by any user but still
+    /// count towards the global quota
+    pub fn build(config: &config::Server, storage: &Storage) -> Result<Quotas, FileShareError> {
+        let mut owners = HashMap::new();
+
+        for (path, size) in storage.files()? {
+            let owner = match storage.load_meta(&format!("{OWNERS_META}/{path}"))? {
+                Some(owner) => String::from_utf8_lossy(&owner).into_owned(),
+                None => String::new(),
+            };
+
+            owners.insert(path, (owner, size));
+        }
+
+        // Drop the owners of files that no longer exist
+        for path in storage.list_meta(OWNERS_META)? {
+            if !owners.contains_key(&path) {
+                storage.delete_meta(&format!("{OWNERS_META}/{path}"))?;
+            }
+        }
+
+        Ok(Quotas {
+            global: config.quota(),
+            max_file_size: config.max_file_size(),
+            usage: Mutex::new(Usage { owners, ..Usage::default() }),
+        })
+    }
+    /// Returns the amount of bytes the given user stores, including uploads that are being stored
+    pub fn usage(&self, user: &str) -> u64 {
+        self.usage.lock().unwrap().used(Some(user))
+    }
+    /// Returns the amount of bytes all users together store, including uploads that are being stored
+    pub fn total_usage(&self) -> u64 {
+        self.usage.lock().unwrap().used(None)
+    }
+    /// Returns the amount of bytes all users together may store
+    pub fn global(&self) -> Option<u64> {
+        self.global
+    }
+    /// Check if the given user may store a file of size bytes at path, replacing the file at path frees up its size. Returns an error
+    /// describing the exceeded limit if not. Nothing is reserved, this is for rejecting a file before it is sent
+    pub fn check(
+        &self,
+        config: &config::Server,
+        storage: &Storage,
+        user: &str,
+        path: &str,
+        size: u64,
+    ) -> Result<(), FileShareError> {
+        self.check_usage(&self.usage.lock().unwrap(), config, storage, user, path, size)
+    }
+    /// Check if the given user may store a file of size bytes at path like `check`, and reserve the bytes until the returned
+    /// reservation is dropped so uploads stored at the same time cant go over the quotas together
+    pub fn reserve(
+        &self,
+        config: &config::Server,
+        storage: &Storage,
+        user: &str,
+        path: &str,
+        size: u64,
+    ) -> Result<Reservation<'_>, FileShareError> {
+        let mut usage = self.usage.lock().unwrap();
+        self.check_usage(&usage, config, storage, user, path, size)?;
+
+        let id = usage.next_id;
+        usage.next_id += 1;
+        usage.reserved.insert(id, (user.to_string(), size));
+
+        Ok(Reservation { quotas: self, id })
+    }
+    /// Check the given usage against the limits, the usage lock must be held
+    fn check_usage(
+        &self,
+        usage: &Usage,
+        config: &config::Server,
+        storage: &Storage,
+        user: &str,
+        path: &str,
+        size: u64,
+    ) -> Result<(), FileShareError> {
+        if let Some(max) = self.max_file_size {
+            if size > max {
+                return Err(ServerError::new(ErrorCode::TooLarge, format!("File is too large: {size} bytes, the limit is {max} bytes")).into());
+            }
+        }
+
+        let replaced = usage.owners.get(&storage::key(path));
+        let freed_total = replaced.map_or(0, |(_, size)| *size);
+        let freed_user = match replaced {
+            Some((owner, size)) if owner == user => *size,
+            _ => 0,
+        };
+
+        if let Some(quota) = config.user_quota(user) {
+            let used = usage.used(Some(user)) - freed_user;
+
+            if used.checked_add(size).is_none_or(|total| total > quota) {
+                return Err(ServerError::new(
+                    ErrorCode::QuotaExceeded,
+                    format!("Quota exceeded: {user} uses {used} of {quota} bytes, the file is {size} bytes"),
+                ).into());
+            }
+        }
+        if let Some(quota) = self.global {
+            let used = usage.used(None) - freed_total;
+
+            if used.checked_add(size).is_none_or(|total| total > quota) {
+                return Err(ServerError::new(
+                    ErrorCode::QuotaExceeded,
+                    format!("Server quota exceeded: {used} of {quota} bytes used, the file is {size} bytes"),
+                ).into());
+            }
+        }
+        if let Some(available) = storage.available_space()? {
+            // Reserved bytes are not on disk yet
+            let available = available.saturating_sub(usage.reserved.values().map(|(_, size)| size).sum());
+
+            if size > available {
+                return Err(ServerError::new(
+                    ErrorCode::StorageFull,
+                    format!("Not enough disk space: {available} bytes available, the file is {size} bytes"),
+                ).into());
+            }
+        }
+
+        Ok(())
+    }
+    /// Record that the given user now owns the file at path, the file must already be in storage
+    pub fn record(&self, storage: &Storage, user: &str, path: &str) -> Result<(), FileShareError> {
+        let size = storage.size_of(path)?.unwrap_or(0);
+        let path = storage::key(path);
+
+        storage.save_meta(&format!("{OWNERS_META}/{path}"), user.as_bytes())?;
+        self.usage.lock().unwrap().owners.insert(path, (user.to_string(), size));
+
+        Ok(())
+    }
+    /// Record that the file at path no longer exists
+    pub fn forget(&self, storage: &Storage, path: &str) -> Result<(), FileShareError> {
+        let path = storage::key(path);
+
+        if self.usage.lock().unwrap().owners.remove(&path).is_some_and(|(owner, _)| !owner.is_empty()) {
+            storage.delete_meta(&format!("{OWNERS_META}/{path}"))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    /// Returns a server configuration keeping files in memory, with the given lines added to it
+    fn server(settings: &str) -> config::Server {
+        let config: Config = toml::from_str(&format!("[server]\nthread_count = 1\nips = []\nstorage = \"memory\"\n{settings}")).unwrap();
+
+        config.server().unwrap()
+    }
+
+    /// Returns the error code of a failed check
+    fn code(result: Result<(), FileShareError>) -> Option<ErrorCode> {
+        result.err().map(|error| error.code())
+    }
+
+    #[test]
+    fn uploads_are_checked_against_every_limit() {
+        let config = server("max_file_size = 100\nquota = 150\nuser_quota = 80\nuser_quotas = { bob = 120 }");
+        let storage = Storage::build(&config).unwrap();
+        let quotas = Quotas::build(&config, &storage).unwrap();
+
+        assert_eq!(code(quotas.check(&config, &storage, "alice", "a", 101)), Some(ErrorCode::TooLarge));
+        assert_eq!(code(quotas.check(&config, &storage, "alice", "a", 81)), Some(ErrorCode::QuotaExceeded));
+        assert!(quotas.check(&config, &storage, "bob", "b", 100).is_ok());
+
+        storage.upload("a", &[0; 80], "alice").unwrap();
+        quotas.record(&storage, "alice", "a").unwrap();
+        assert_eq!(quotas.usage("alice"), 80);
+
+        // Replacing a file frees up its size, but only for its owner
+        assert!(quotas.check(&config, &storage, "alice", "a", 80).is_ok());
+        assert_eq!(code(quotas.check(&config, &storage, "alice", "c", 1)), Some(ErrorCode::QuotaExceeded));
+        assert_eq!(code(quotas.check(&config, &storage, "bob", "b", 71)), Some(ErrorCode::QuotaExceeded));
+        assert!(quotas.check(&config, &storage, "bob", "a", 100).is_ok());
+
+        quotas.forget(&storage, "a").unwrap();
+        assert_eq!(quotas.total_usage(), 0);
+    }
+
+    #[test]
+    fn reserved_bytes_count_until_the_reservation_is_dropped() {
+        let config = server("user_quota = 100");
+        let storage = Storage::build(&config).unwrap();
+        let quotas = Quotas::build(&config, &storage).unwrap();
+
+        let reservation = quotas.reserve(&config, &storage, "alice", "a", 60).unwrap();
+        assert_eq!(quotas.usage("alice"), 60);
+        assert!(quotas.reserve(&config, &storage, "alice", "b", 60).is_err());
+        // Other users have their own quota
+        assert!(quotas.reserve(&config, &storage, "bob", "b", 60).is_ok());
+
+        drop(reservation);
+        assert_eq!(quotas.total_usage(), 0);
+        assert!(quotas.reserve(&config, &storage, "alice", "b", 60).is_ok());
+    }
+
+    #[test]
+    fn owners_are_reloaded_from_storage() {
+        let config = server("");
+        let storage = Storage::build(&config).unwrap();
+        let quotas = Quotas::build(&config, &storage).unwrap();
+
+        storage.upload("a", &[0; 10], "alice").unwrap();
+        quotas.record(&storage, "alice", "a").unwrap();
+        storage.upload("b", &[0; 20], "bob").unwrap();
+        quotas.record(&storage, "bob", "b").unwrap();
+        // Stored before quotas were tracked, so nobody owns it
+        storage.write("c", &[0; 30]).unwrap();
+
+        let reloaded = Quotas::build(&config, &storage).unwrap();
+        assert_eq!(reloaded.usage("alice"), 10);
+        assert_eq!(reloaded.usage("bob"), 20);
+        assert_eq!(reloaded.total_usage(), 60);
+
+        // The owner of a file that was deleted without the quotas knowing is dropped
+        storage.delete("a").unwrap();
+        let reloaded = Quotas::build(&config, &storage).unwrap();
+        assert_eq!(reloaded.usage("alice"), 0);
+        assert!(storage.list_meta(OWNERS_META).unwrap().iter().all(|path| path != "a"));
+    }
+}
@@ -0,0 +1,316 @@
+use std::{io::{self, Write}, net::IpAddr, sync::{atomic::{AtomicU64, Ordering}, mpsc::Receiver}, thread};
+
+use crate::{
+    config, storage::Storage, quota::Quotas, links::Links, events::{Event, Events}, throttle::Bandwidth, limits::Limits,
+    connection::Transport, mux, webdav::WebDav, error::{ErrorCode, ServerError},
+    CommandType, FileShareError, Share, Location,
+};
+
+/// Identity of clients that have not logged in
+pub const ANONYMOUS: &str = "anonymous";
+
+/// Id given to the next session
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// State shared between every connection to the server
+pub struct ServerState {
+    config: config::Server,
+    storage: Storage,
+    quotas: Quotas,
+    links: Links,
+    events: Events,
+    bandwidth: Bandwidth,
+    limits: Limits,
+    webdav: WebDav,
+}
+
+impl ServerState {
+    /// Create the storage and load the quota usage for the given server configuration
+    pub fn build(config: config::Server) -> Result<ServerState, FileShareError> {
+        let storage = Storage::build(&config)?;
+        let quotas = Quotas::build(&config, &storage)?;
+        let links = Links::build(&config, &storage)?;
+
+        let bandwidth = Bandwidth::new(config.bandwidth_limit(), config.client_bandwidth_limit());
+        let limits = Limits::new(&config);
+
+        Ok(ServerState {
+            config,
+            storage,
+            quotas,
+            links,
+            events: Events::default(),
+            bandwidth,
+            limits,
+            webdav: WebDav::default(),
+        })
+    }
+    pub fn config(&self) -> &config::Server {
+        &self.config
+    }
+    pub fn storage(&self) -> &Storage {
+        &self.storage
+    }
+    pub fn quotas(&self) -> &Quotas {
+        &self.quotas
+    }
+    pub fn links(&self) -> &Links {
+        &self.links
+    }
+    pub fn events(&self) -> &Events {
+        &self.events
+    }
+    pub fn bandwidth(&self) -> &Bandwidth {
+        &self.bandwidth
+    }
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+    pub fn webdav(&self) -> &WebDav {
+        &self.webdav
+    }
+}
+
+/// State of a single connection to the server
+#[derive(Default)]
+pub struct Session {
+    /// Identifies the session, events are not sent back to the session that caused them
+    id: u64,
+    /// Ip the client connected from, None for clients on the Unix socket
+    address: Option<IpAddr>,
+    /// Name of the user the client logged in as
+    user: Option<String>,
+
+    /// Clients must log in before running any other command
+    require_login: bool,
+    /// Users that may log in, or None if every user may
+    allowed_users: Option<Vec<String>>,
+
+    /// Events the connection subscribed to, once set the connection only receives events
+    subscription: Option<Receiver<Event>>,
+    /// Changes made while muted are not published, this lets a change made of several commands be published as one event
+    events_muted: bool,
+}
+
+impl Session {
+    /// Create a new session for a client that has not logged in
+    pub fn new() -> Session {
+        Session {
+            id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
+            ..Session::default()
+        }
+    }
+    /// Create a new session for a client that connected from address to a listener, using the auth settings of the listener
+    pub fn for_listener(listener: Option<&config::Listener>, address: Option<IpAddr>) -> Session {
+        match listener {
+            Some(listener) => Session {
+                address,
+                require_login: listener.require_login(),
+                allowed_users: listener.allowed_users().cloned(),
+                ..Session::new()
+            },
+            None => Session {
+                address,
+                ..Session::new()
+            },
+        }
+    }
+    /// Create a new session for a client that connected to the Unix socket, the client is logged in as `uid:<uid>` using the user
+    /// id the kernel reports for it
+    pub fn for_peer(uid: u32) -> Session {
+        Session {
+            user: Some(format!("uid:{uid}")),
+            ..Session::new()
+        }
+    }
+    /// Returns a copy of the session for running a command alongside others on a multiplexed connection, the copy is the same client
+    /// (same id, address and user) but does not share the subscription or muted events
+    pub(crate) fn fork(&self) -> Session {
+        Session {
+            id: self.id,
+            address: self.address,
+            user: self.user.clone(),
+            require_login: self.require_login,
+            allowed_users: self.allowed_users.clone(),
+            ..Session::default()
+        }
+    }
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+    pub fn address(&self) -> Option<IpAddr> {
+        self.address
+    }
+    /// Set the events the connection subscribed to, they are pushed to the client after the response to SUBSCRIBE
+    pub fn set_subscription(&mut self, events: Receiver<Event>) {
+        self.subscription = Some(events);
+    }
+    /// Stop or start publishing the changes made by this session
+    pub fn mute_events(&mut self, muted: bool) {
+        self.events_muted = muted;
+    }
+    pub fn events_muted(&self) -> bool {
+        self.events_muted
+    }
+    /// Returns the name of the user the client logged in as, or `anonymous`
+    pub fn user(&self) -> &str {
+        self.user.as_deref().unwrap_or(ANONYMOUS)
+    }
+    /// Log the client in as the given user, if the password matches the one in the server configuration. Failed logins are counted
+    /// so guessing passwords gets the client banned
+    pub fn login(&mut self, state: &ServerState, user: &str, password: &str) -> Result<(), FileShareError> {
+        state.limits().check_login(user)?;
+
+        let allowed = self.allowed_users.as_ref()
+            .is_none_or(|allowed_users| allowed_users.iter().any(|allowed| allowed == user));
+
+        match state.config().password(user) {
+            Some(expected) if allowed && expected == password => {
+                self.user = Some(user.to_string());
+
+                Ok(())
+            }
+            _ => {
+                state.limits().record_failure(self, user);
+
+                Err(ServerError::new(ErrorCode::PermissionDenied, "Invalid user name or password").into())
+            }
+        }
+    }
+    /// Check if the client may run a command, clients that must log in can only run LOGIN (and REDEEM, since links are for people
+    /// without an account, and MUX) until they have
+    pub fn authorize(&self, command_type: &CommandType) -> Result<(), ServerError> {
+        // Multiplexed commands are authorized one by one
+        let exempt = *command_type == CommandType::Login || *command_type == CommandType::Redeem || *command_type == CommandType::Mux;
+
+        if self.require_login && self.user.is_none() && !exempt {
+            return Err(ServerError::new(ErrorCode::PermissionDenied, "Log in before running any other command"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Only the official client will work for the most part so the server wont have
+/// to handle additional things like making sure your command was correct (this
+/// is checked on the official client)
+pub fn handle_client(stream: impl Transport + Send + 'static, mut session: Session, state: &ServerState) {
+    let mut stream = state.bandwidth().throttle(stream);
+
+    loop {
+        // Read data that was sent from client
+        let mut share = match Share::read_limited(&mut stream, Location::Server, state.config().max_share_size()) {
+            // Successful read
+            Ok(share) => share,
+            // The client closed the connection between commands
+            Err(FileShareError::Io(error)) if error.kind() == io::ErrorKind::UnexpectedEof => return,
+            // Invalid read
+            Err(error) => {
+                eprintln!("Failed to read share: {error}");
+                // Just returns since unofficial clients/requests wont be supported, no error is returned since with the official client
+                // these will be checked on the client side, I dont want the server to have to use extra CPU power to check this and
+                // return an error. This should not effect most people.
+                return;
+            }
+        };
+
+        // Execute the recieved command, unless the client sent too many
+        let result = match state.limits().check(&session) {
+            Ok(_) => share.execute_on_server(state, &mut session),
+            Err(blocked) => Err(blocked.into()),
+        };
+        if let Err(e) = result {
+            // If there was an error set the servers error response
+            share.set_error_response(e);
+        };
+
+        // Write share to stream since we executed the command and all the data needed is inside
+        match share.write_to_stream(&mut stream, Location::Server) {
+            Ok(_) => (),
+            Err(error) => {
+                eprintln!("Failed to write to stream: {error}");
+                return;
+            }
+        }
+
+        stream.flush().unwrap_or_else(|error| {
+            eprintln!("Failed to flush stream: {error}");
+        });
+
+        // The connection subscribed to events, from now on it only receives them. It can stay open for as long as the client wants,
+        // so it gets its own thread and the pool thread goes back to serving other clients
+        if let Some(events) = session.subscription.take() {
+            thread::spawn(move || push_events(stream, events));
+            return;
+        }
+
+        // The connection switched to multiplexing, from now on shares arrive in chunks
+        if *share.command_type() == CommandType::Mux && share.succeeded() {
+            mux::serve(stream, &mut session, state);
+            return;
+        }
+    }
+}
+
+/// Push events to a subscribed client until its connection closes. A closed connection is only noticed when the next event fails to
+/// send, until then the connection keeps its own thread (but no thread of the pool)
+fn push_events(mut stream: impl Transport, events: Receiver<Event>) {
+    for event in events {
+        let result = event.write_to_stream(&mut stream).and_then(|_| Ok(stream.flush()?));
+
+        if let Err(error) = result {
+            eprintln!("Stopped pushing events: {error}");
+            return;
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::Config;
+
+    use super::*;
+
+    fn state(settings: &str) -> ServerState {
+        let config = format!("[server]\nthread_count = 1\nips = []\nstorage = \"memory\"\n{settings}");
+
+        ServerState::build(toml::from_str::<Config>(&config).unwrap().server().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn listeners_that_require_login_only_allow_logging_in() {
+        let state = state(concat!(
+            "[server.users]\nalice = \"a\"\nbob = \"b\"\n",
+            "[[server.listeners]]\naddress = \"127.0.0.1:1\"\nrequire_login = true\nallowed_users = [\"alice\"]\n",
+        ));
+        let mut session = Session::for_listener(state.config().listener("127.0.0.1:1"), None);
+
+        assert!(session.authorize(&CommandType::Catalog).is_err());
+        for command_type in [CommandType::Login, CommandType::Redeem, CommandType::Mux] {
+            assert!(session.authorize(&command_type).is_ok(), "{command_type:?}");
+        }
+
+        // Only the allowed users can log in
+        assert!(session.login(&state, "bob", "b").is_err());
+        assert!(session.login(&state, "alice", "b").is_err());
+        assert!(session.authorize(&CommandType::Catalog).is_err());
+
+        session.login(&state, "alice", "a").unwrap();
+        assert!(session.authorize(&CommandType::Catalog).is_ok());
+
+        // Listeners without settings dont require logging in
+        let session = Session::for_listener(state.config().listener("127.0.0.1:2"), None);
+        assert!(session.authorize(&CommandType::Catalog).is_ok());
+    }
+
+    #[test]
+    fn unix_socket_clients_are_logged_in_as_their_uid() {
+        let state = state("admins = [\"uid:0\"]");
+        let session = Session::for_peer(1000);
+
+        assert_eq!(session.user(), "uid:1000");
+        assert_eq!(session.address(), None);
+        assert!(session.authorize(&CommandType::Catalog).is_ok());
+        assert!(!state.config().is_admin(session.user()));
+        assert!(state.config().is_admin(Session::for_peer(0).user()));
+    }
+}
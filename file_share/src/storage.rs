@@ -0,0 +1,863 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{self, Read},
+    sync::{Condvar, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::{config, backend::{self, StorageBackend}, error::{ErrorCode, ServerError}, FileShareError};
+
+/// Hidden directory (inside the storage root) that holds the previous versions of files
+pub const VERSIONS_DIR: &str = ".versions";
+/// Hidden directory (inside the storage root) that holds deleted and replaced files
+pub const TRASH_DIR: &str = ".trash";
+/// Hidden directory (inside the storage root) that holds the content addressed blobs when deduplication is enabled
+pub const BLOBS_DIR: &str = ".blobs";
+/// Hidden directory (inside the storage root) that holds files the server uses to keep track of things, like quota usage
+pub const META_DIR: &str = ".meta";
+/// The hidden directories, clients cant read or write anything inside them
+const HIDDEN_DIRS: &[&str] = &[VERSIONS_DIR, TRASH_DIR, BLOBS_DIR, META_DIR];
+
+/// Start of a file that references a blob instead of containing the data itself, formated like `BLOB_MAGIC hash size signature`
+const BLOB_MAGIC: &str = "file_share-blob";
+/// Name of the metadata file the secret references are signed with is saved in
+const BLOB_SECRET_META: &str = "blob-secret";
+/// Name of the metadata directory holding the users that uploaded the data of each blob, one file per blob with a user per line
+const BLOB_GRANTS_META: &str = "blob-grants";
+
+/// Handles all the files the server stores, this includes keeping previous versions of files that get replaced and moving deleted
+/// files into the trash. The files themselves are stored in a StorageBackend
+pub struct Storage {
+    backend: Box<dyn StorageBackend>,
+
+    /// Amount of previous versions kept for each file
+    version_count: Option<usize>,
+    /// Age (in seconds) after which a previous version is removed
+    version_age: Option<u64>,
+    /// Age (in seconds) after which a file in the trash is removed
+    trash_age: Option<u64>,
+
+    /// Store the data of files once per unique content in the blobs directory, files then reference the blob they contain
+    dedup: bool,
+    /// Secret references are signed with, so files uploaded by clients that look like a reference are never resolved. None if
+    /// deduplication was never enabled
+    blob_secret: Option<Vec<u8>>,
+    /// Held while changing the reference count of a blob
+    blob_lock: Mutex<()>,
+
+    /// Entries being archived, as `store/path`. Only one file of a path is archived into a store at a time so every entry gets its
+    /// own number
+    archiving: Mutex<HashSet<String>>,
+    /// Notified when an entry is no longer being archived
+    archived: Condvar,
+}
+
+/// Marks an entry as being archived until dropped, see Storage::archiving
+struct Archiving<'a> {
+    storage: &'a Storage,
+    name: String,
+}
+
+impl Drop for Archiving<'_> {
+    fn drop(&mut self) {
+        self.storage.archiving.lock().unwrap().remove(&self.name);
+        self.storage.archived.notify_all();
+    }
+}
+
+/// Paths and sizes of files in storage
+pub type Files = Vec<(String, u64)>;
+
+/// Information about a single entry in the versions store or the trash
+pub struct Entry {
+    /// Path of the file the entry was created from
+    pub path: String,
+    /// Number of the entry, the first entry of a file is 1
+    pub number: u64,
+    /// Time (in seconds since the unix epoch) the file was replaced or deleted
+    pub time: u64,
+    /// Size of the entry in bytes
+    pub size: u64,
+}
+
+impl Storage {
+    /// Create a new Storage using the backend and retention settings from the server configuration
+    pub fn build(config: &config::Server) -> Result<Storage, FileShareError> {
+        let mut storage = Storage {
+            backend: backend::build(config)?,
+            version_count: config.version_count(),
+            version_age: config.version_age(),
+            trash_age: config.trash_age(),
+            dedup: config.dedup(),
+            blob_secret: None,
+            blob_lock: Mutex::new(()),
+            archiving: Mutex::new(HashSet::new()),
+            archived: Condvar::new(),
+        };
+
+        // The secret is kept after deduplication gets disabled, so the references written before still resolve
+        storage.blob_secret = match storage.load_meta(BLOB_SECRET_META)? {
+            Some(secret) => Some(secret),
+            None if storage.dedup => {
+                let secret = random_bytes(32)?;
+                storage.save_meta(BLOB_SECRET_META, &secret)?;
+
+                Some(secret)
+            }
+            None => None,
+        };
+
+        Ok(storage)
+    }
+    /// Returns true if previous versions of files are kept when they get replaced
+    pub fn versioning(&self) -> bool {
+        self.version_count.is_some() || self.version_age.is_some()
+    }
+    /// Read the file at path into memory
+    pub fn read(&self, path: &str) -> Result<Vec<u8>, FileShareError> {
+        self.open(&key(path))
+    }
+    /// Open the file at path for reading from offset onwards, so files can be sent without reading all of them into memory
+    pub fn reader(&self, path: &str, offset: u64) -> Result<Box<dyn Read + Send>, FileShareError> {
+        let path = key(path);
+
+        let reader = match self.reference_of(&path)? {
+            Some((hash, _)) => self.backend.reader(&blob_path(&hash), offset)?,
+            None => self.backend.reader(&path, offset)?,
+        };
+
+        Ok(reader)
+    }
+    /// Write data to the file at path, if the file already exists the old file is moved into the versions store (or the trash if
+    /// versioning is disabled)
+    pub fn write(&self, path: &str, data: &[u8]) -> Result<(), FileShareError> {
+        let path = key(path);
+
+        self.set_aside(&path)?;
+        self.put(&path, data, None)
+    }
+    /// Write data a user uploaded to the file at path like `write`, the user may then link other files to the same data
+    pub fn upload(&self, path: &str, data: &[u8], user: &str) -> Result<(), FileShareError> {
+        let path = key(path);
+
+        self.set_aside(&path)?;
+        self.put(&path, data, Some(user))
+    }
+    /// Make the file at path reference the blob with the given hash, the same way `upload` would if it was given data with that
+    /// hash. Returns false (and writes nothing) if deduplication is disabled, the blob does not exist or the user never uploaded
+    /// its data, a hash alone doesnt prove the user has the data
+    pub fn link(&self, path: &str, hash: &str, user: &str) -> Result<bool, FileShareError> {
+        if !self.dedup || !is_hash(hash) || !self.granted(hash, user)? {
+            return Ok(false);
+        }
+        let path = key(path);
+
+        self.set_aside(&path)?;
+
+        // The blob may have been removed while the old file was set aside, it is checked again while nothing can remove it
+        let _lock = self.blob_lock.lock().unwrap();
+        let size = match self.backend.stat(&blob_path(hash))? {
+            Some(metadata) => metadata.size,
+            None => return Ok(false),
+        };
+        self.reference(&path, hash, size)?;
+
+        Ok(true)
+    }
+    /// Move the file at path into the trash
+    pub fn delete(&self, path: &str) -> Result<(), FileShareError> {
+        let path = key(path);
+
+        if self.backend.stat(&path)?.is_none() {
+            return Err(ServerError::new(ErrorCode::NotFound, format!("{path} does not exist")).into());
+        }
+
+        self.archive(TRASH_DIR, &path)
+    }
+    /// Returns a list of the files in storage, one per line. The versions store, the trash and other hidden directories are hidden
+    /// from the list
+    pub fn catalog(&self) -> Result<String, FileShareError> {
+        let mut catalog = String::new();
+
+        for path in self.paths()? {
+            catalog.push_str(&format!("./{path}\n"));
+        }
+
+        Ok(catalog)
+    }
+    /// Returns the paths and sizes of all the files in storage, without the hidden directories
+    pub fn files(&self) -> Result<Files, FileShareError> {
+        let mut files = Vec::new();
+
+        for path in self.paths()? {
+            let size = self.size(&path)?;
+
+            files.push((path, size));
+        }
+
+        Ok(files)
+    }
+    /// Returns the paths and sizes of all the files inside the directory dir (every file if dir is empty)
+    pub fn files_in(&self, dir: &str) -> Result<Files, FileShareError> {
+        let dir = key(dir);
+
+        if dir.is_empty() {
+            return self.files();
+        }
+
+        let mut files = Vec::new();
+
+        for path in self.backend.list(&format!("{dir}/"))? {
+            let size = self.size(&path)?;
+
+            files.push((path, size));
+        }
+
+        Ok(files)
+    }
+    /// Returns the paths and sizes of the files directly inside the directory dir, and the paths of the directories directly inside
+    /// it. The hidden directories are left out of the root
+    pub fn children(&self, dir: &str) -> Result<(Files, Vec<String>), FileShareError> {
+        let (paths, mut dirs) = self.backend.list_dir(&key(dir))?;
+        let mut files = Vec::new();
+
+        for path in paths {
+            let size = self.size(&path)?;
+
+            files.push((path, size));
+        }
+        dirs.retain(|dir| !HIDDEN_DIRS.contains(&dir.as_str()));
+
+        Ok((files, dirs))
+    }
+    /// Returns the size of the file at path, or None if it does not exist
+    pub fn size_of(&self, path: &str) -> Result<Option<u64>, FileShareError> {
+        let path = key(path);
+
+        match self.backend.stat(&path)? {
+            Some(_) => Ok(Some(self.size(&path)?)),
+            None => Ok(None),
+        }
+    }
+    /// Returns the SHA-256 hash (as hex) of the content of the file at path, or None if it does not exist. Files referencing a blob
+    /// are not read since the reference contains the hash
+    pub fn hash_of(&self, path: &str) -> Result<Option<String>, FileShareError> {
+        let path = key(path);
+
+        if self.backend.stat(&path)?.is_none() {
+            return Ok(None);
+        }
+
+        match self.reference_of(&path)? {
+            Some((hash, _)) => Ok(Some(hash)),
+            None => Ok(Some(hex::encode(Sha256::digest(self.backend.open(&path)?)))),
+        }
+    }
+    /// Returns the time (in seconds since the unix epoch) the file at path was last modified, or None if it does not exist
+    pub fn modified_of(&self, path: &str) -> Result<Option<u64>, FileShareError> {
+        Ok(self.backend.stat(&key(path))?.map(|metadata| metadata.modified))
+    }
+    /// Returns the amount of bytes that can still be stored, or None if the backend cant tell
+    pub fn available_space(&self) -> Result<Option<u64>, FileShareError> {
+        Ok(self.backend.available_space()?)
+    }
+    /// Read the metadata file with the given name, or None if it does not exist yet
+    pub fn load_meta(&self, name: &str) -> Result<Option<Vec<u8>>, FileShareError> {
+        let path = format!("{META_DIR}/{name}");
+
+        match self.backend.stat(&path)? {
+            Some(_) => Ok(Some(self.backend.open(&path)?)),
+            None => Ok(None),
+        }
+    }
+    /// Write the metadata file with the given name
+    pub fn save_meta(&self, name: &str, data: &[u8]) -> Result<(), FileShareError> {
+        Ok(self.backend.create(&format!("{META_DIR}/{name}"), data)?)
+    }
+    /// Delete the metadata file with the given name
+    pub fn delete_meta(&self, name: &str) -> Result<(), FileShareError> {
+        Ok(self.backend.delete(&format!("{META_DIR}/{name}"))?)
+    }
+    /// Returns the names of all metadata files inside the metadata directory dir, relative to dir
+    pub fn list_meta(&self, dir: &str) -> Result<Vec<String>, FileShareError> {
+        let prefix = format!("{META_DIR}/{dir}/");
+
+        Ok(self.backend.list(&prefix)?.into_iter().map(|path| path[prefix.len()..].to_string()).collect())
+    }
+    /// Returns all the kept versions of the file at path, sorted from oldest to newest
+    pub fn versions(&self, path: &str) -> Result<Vec<Entry>, FileShareError> {
+        self.entries(VERSIONS_DIR, &key(path))
+    }
+    /// Replace the file at path with a previous version of it, the current file is kept as a new version
+    pub fn restore(&self, path: &str, number: u64) -> Result<(), FileShareError> {
+        let version = self.versions(path)?
+            .into_iter()
+            .find(|version| version.number == number)
+            .ok_or(ServerError::new(ErrorCode::NotFound, format!("Version {number} of {path} does not exist")))?;
+
+        // Read the version before archiving the current file, since archiving may remove old versions
+        let data = self.open(&entry_path(VERSIONS_DIR, &version))?;
+
+        self.write(path, &data)
+    }
+    /// Returns all the files in the trash, sorted by path then from oldest to newest
+    pub fn trash(&self) -> Result<Vec<Entry>, FileShareError> {
+        let mut entries = Vec::new();
+
+        for path in self.entry_paths(TRASH_DIR)? {
+            entries.append(&mut self.entries(TRASH_DIR, &path)?);
+        }
+
+        Ok(entries)
+    }
+    /// Move the most recently trashed file at path out of the trash
+    pub fn undelete(&self, path: &str) -> Result<(), FileShareError> {
+        let path = key(path);
+
+        if self.backend.stat(&path)?.is_some() {
+            return Err(ServerError::new(ErrorCode::AlreadyExists, format!("{path} already exists, delete it before undeleting")).into());
+        }
+
+        let entry = self.entries(TRASH_DIR, &path)?
+            .pop()
+            .ok_or(ServerError::new(ErrorCode::NotFound, format!("{path} is not in the trash")))?;
+
+        Ok(self.backend.rename(&entry_path(TRASH_DIR, &entry), &path)?)
+    }
+    /// Remove everything in the trash and versions store that is older than the configured ages
+    pub fn purge(&self) -> Result<(), FileShareError> {
+        if let Some(age) = self.trash_age {
+            for entry in self.trash()? {
+                if now().saturating_sub(entry.time) > age {
+                    self.remove(&entry_path(TRASH_DIR, &entry))?;
+                }
+            }
+        }
+
+        if self.version_age.is_some() {
+            for path in self.entry_paths(VERSIONS_DIR)? {
+                self.prune(&path)?;
+            }
+        }
+
+        Ok(())
+    }
+    /// Returns all the entries of the file at path in the given store, sorted from oldest to newest
+    fn entries(&self, store: &str, path: &str) -> Result<Vec<Entry>, FileShareError> {
+        let prefix = format!("{store}/{path}/");
+        let mut entries = Vec::new();
+
+        for entry in self.backend.list(&prefix)? {
+            // Entry files are named `number-time`, skip anything else (like entries of files inside a directory named path)
+            let (number, time) = match parse_entry_name(&entry[prefix.len()..]) {
+                Some(parts) => parts,
+                None => continue,
+            };
+
+            entries.push(Entry {
+                path: path.to_string(),
+                number,
+                time,
+                size: self.size(&entry)?,
+            });
+        }
+
+        entries.sort_by_key(|entry| entry.number);
+
+        Ok(entries)
+    }
+    /// Returns the paths of all files that have entries in the given store, sorted and without duplicates
+    fn entry_paths(&self, store: &str) -> Result<Vec<String>, FileShareError> {
+        let prefix = format!("{store}/");
+        let mut paths: Vec<String> = self.backend.list(&prefix)?
+            .iter()
+            .filter_map(|entry| entry[prefix.len()..].rsplit_once('/'))
+            .filter(|(_, name)| parse_entry_name(name).is_some())
+            // The directory holding an entry is named after the path of the file
+            .map(|(path, _)| path.to_string())
+            .collect();
+
+        paths.sort();
+        paths.dedup();
+
+        Ok(paths)
+    }
+    /// Move the file at path (if it exists) into the versions store, or the trash if versioning is disabled, so it can be replaced
+    fn set_aside(&self, path: &str) -> Result<(), FileShareError> {
+        if self.backend.stat(path)?.is_none() {
+            return Ok(());
+        }
+
+        if self.versioning() {
+            self.archive(VERSIONS_DIR, path)?;
+            self.prune(path)
+        } else {
+            self.archive(TRASH_DIR, path)
+        }
+    }
+    /// Move the file at path into the given store as a new entry
+    fn archive(&self, store: &str, path: &str) -> Result<(), FileShareError> {
+        // The number is picked from the entries there already, so it must be used before another file of path is archived
+        let _archiving = self.start_archiving(format!("{store}/{path}"));
+
+        let entries = self.entries(store, path)?;
+        let entry = Entry {
+            path: path.to_string(),
+            number: entries.last().map_or(1, |entry| entry.number + 1),
+            time: now(),
+            size: 0,
+        };
+
+        Ok(self.backend.rename(path, &entry_path(store, &entry))?)
+    }
+    /// Wait until no other file is being archived as the given entry, then mark it as being archived
+    fn start_archiving(&self, name: String) -> Archiving<'_> {
+        let mut archiving = self.archiving.lock().unwrap();
+
+        while archiving.contains(&name) {
+            archiving = self.archived.wait(archiving).unwrap();
+        }
+        archiving.insert(name.clone());
+
+        Archiving { storage: self, name }
+    }
+    /// Remove versions of the file at path that are over the configured count or age
+    fn prune(&self, path: &str) -> Result<(), FileShareError> {
+        let versions = self.entries(VERSIONS_DIR, path)?;
+        let over_count = match self.version_count {
+            Some(count) => versions.len().saturating_sub(count),
+            None => 0,
+        };
+
+        for (i, version) in versions.iter().enumerate() {
+            let too_old = match self.version_age {
+                Some(age) => now().saturating_sub(version.time) > age,
+                None => false,
+            };
+
+            // Versions are sorted oldest first, so the first `over_count` versions are the ones over the limit
+            if i < over_count || too_old {
+                self.remove(&entry_path(VERSIONS_DIR, version))?;
+            }
+        }
+
+        Ok(())
+    }
+    /// Returns the paths of all the files in storage, the hidden directories are skipped without listing what is inside them
+    fn paths(&self) -> Result<Vec<String>, FileShareError> {
+        let (mut paths, dirs) = self.backend.list_dir("")?;
+
+        for dir in dirs.iter().filter(|dir| !HIDDEN_DIRS.contains(&dir.as_str())) {
+            paths.append(&mut self.backend.list(&format!("{dir}/"))?);
+        }
+        paths.sort();
+
+        Ok(paths)
+    }
+    /// Read the file at path, resolving it if it references a blob
+    fn open(&self, path: &str) -> Result<Vec<u8>, FileShareError> {
+        let data = self.backend.open(path)?;
+
+        match self.parse_reference(&data) {
+            Some((hash, _)) => Ok(self.backend.open(&blob_path(&hash))?),
+            None => Ok(data),
+        }
+    }
+    /// Create the file at path containing data, if deduplication is enabled the data is stored as a blob and the file references it.
+    /// The user that uploaded the data (if any) is granted the blob
+    fn put(&self, path: &str, data: &[u8], user: Option<&str>) -> Result<(), FileShareError> {
+        if !self.dedup {
+            return Ok(self.backend.create(path, data)?);
+        }
+
+        let hash = hex::encode(Sha256::digest(data));
+
+        // The blob cant be removed between checking it exists and referencing it
+        let _lock = self.blob_lock.lock().unwrap();
+
+        if self.backend.stat(&blob_path(&hash))?.is_none() {
+            self.backend.create(&blob_path(&hash), data)?;
+        }
+        if let Some(user) = user {
+            self.grant(&hash, user)?;
+        }
+
+        self.reference(path, &hash, data.len() as u64)
+    }
+    /// Create the file at path referencing a blob, and count the reference. The blob lock must be held
+    fn reference(&self, path: &str, hash: &str, size: u64) -> Result<(), FileShareError> {
+        self.backend.create(&refs_path(hash), (self.refs(hash)? + 1).to_string().as_bytes())?;
+        Ok(self.backend.create(path, format!("{BLOB_MAGIC} {hash} {size} {}", self.sign(hash, size)?).as_bytes())?)
+    }
+    /// Delete the file at path, if it references a blob the reference is released and the blob is deleted once nothing references it
+    fn remove(&self, path: &str) -> Result<(), FileShareError> {
+        let hash = self.reference_of(path)?;
+
+        self.backend.delete(path)?;
+
+        if let Some((hash, _)) = hash {
+            let _lock = self.blob_lock.lock().unwrap();
+            let refs = self.refs(&hash)?.saturating_sub(1);
+
+            if refs == 0 {
+                self.backend.delete(&blob_path(&hash))?;
+                self.backend.delete(&refs_path(&hash))?;
+
+                if self.load_meta(&grants_name(&hash))?.is_some() {
+                    self.delete_meta(&grants_name(&hash))?;
+                }
+            } else {
+                self.backend.create(&refs_path(&hash), refs.to_string().as_bytes())?;
+            }
+        }
+
+        Ok(())
+    }
+    /// Returns the size of the data in the file at path, resolving it if it references a blob
+    fn size(&self, path: &str) -> Result<u64, FileShareError> {
+        match self.reference_of(path)? {
+            Some((_, size)) => Ok(size),
+            None => Ok(self.backend.stat(path)?.map_or(0, |metadata| metadata.size)),
+        }
+    }
+    /// Returns the hash and size of the blob the file at path references, or None if it is a normal file
+    fn reference_of(&self, path: &str) -> Result<Option<(String, u64)>, FileShareError> {
+        match self.backend.stat(path)? {
+            // References are small, dont read big files just to find out they are not one
+            Some(metadata) if metadata.size <= 256 => Ok(self.parse_reference(&self.backend.open(path)?)),
+            _ => Ok(None),
+        }
+    }
+    /// Returns the amount of files referencing the blob with the given hash
+    fn refs(&self, hash: &str) -> Result<u64, FileShareError> {
+        match self.backend.stat(&refs_path(hash))? {
+            Some(_) => String::from_utf8(self.backend.open(&refs_path(hash))?).ok()
+                .and_then(|refs| refs.trim().parse().ok())
+                .ok_or(ServerError::new(ErrorCode::Internal, format!("Reference count of blob {hash} is not a number")).into()),
+            None => Ok(0),
+        }
+    }
+    /// Returns true if the user uploaded the data of the blob with the given hash
+    fn granted(&self, hash: &str, user: &str) -> Result<bool, FileShareError> {
+        let grants = self.load_meta(&grants_name(hash))?.unwrap_or_default();
+
+        Ok(String::from_utf8_lossy(&grants).lines().any(|granted| granted == user))
+    }
+    /// Let the user link files to the blob with the given hash. The blob lock must be held
+    fn grant(&self, hash: &str, user: &str) -> Result<(), FileShareError> {
+        if self.granted(hash, user)? {
+            return Ok(());
+        }
+
+        let mut grants = self.load_meta(&grants_name(hash))?.unwrap_or_default();
+        grants.extend_from_slice(format!("{user}\n").as_bytes());
+
+        self.save_meta(&grants_name(hash), &grants)
+    }
+    /// Returns the signature (as hex) of a reference to the blob with the given hash and size
+    fn sign(&self, hash: &str, size: u64) -> Result<String, FileShareError> {
+        let missing = || ServerError::new(ErrorCode::Internal, "Blobs cant be referenced without a blob secret");
+        let mut mac = self.blob_mac().ok_or_else(missing)?;
+        mac.update(format!("{hash} {size}").as_bytes());
+
+        Ok(hex::encode(mac.finalize().into_bytes()))
+    }
+    fn blob_mac(&self) -> Option<Hmac<Sha256>> {
+        let secret = self.blob_secret.as_ref()?;
+
+        Some(Hmac::<Sha256>::new_from_slice(secret).expect("HMAC can take a key of any size"))
+    }
+    /// Parse the contents of a file referencing a blob into the hash and size of the blob. Only references signed by this storage are
+    /// accepted, anything else is the content of a normal file
+    fn parse_reference(&self, data: &[u8]) -> Option<(String, u64)> {
+        let reference = std::str::from_utf8(data).ok()?;
+        let mut parts = reference.split(' ');
+
+        if parts.next()? != BLOB_MAGIC {
+            return None;
+        }
+
+        let hash = parts.next().filter(|hash| is_hash(hash))?;
+        let size = parts.next()?.parse().ok()?;
+        let signature = hex::decode(parts.next()?).ok()?;
+
+        let mut mac = self.blob_mac()?;
+        mac.update(format!("{hash} {size}").as_bytes());
+        mac.verify_slice(&signature).ok()?;
+
+        Some((hash.to_string(), size))
+    }
+}
+
+/// Returns amount random bytes from the operating system
+pub(crate) fn random_bytes(amount: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![0; amount];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+
+    Ok(bytes)
+}
+
+/// Returns true if hash looks like a hex encoded SHA-256 hash
+fn is_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+/// Returns the path of the blob with the given hash
+fn blob_path(hash: &str) -> String {
+    format!("{BLOBS_DIR}/{hash}")
+}
+
+/// Returns the name of the metadata file holding the users granted the blob with the given hash
+fn grants_name(hash: &str) -> String {
+    format!("{BLOB_GRANTS_META}/{hash}")
+}
+
+/// Returns the path of the file counting the references to the blob with the given hash
+fn refs_path(hash: &str) -> String {
+    format!("{BLOBS_DIR}/{hash}.refs")
+}
+
+/// Convert a path sent by a client into the path used in the backend, `./file` and `/file` both become `file`
+pub(crate) fn key(path: &str) -> String {
+    path.trim_start_matches("./").trim_start_matches('/').to_string()
+}
+
+/// Check a path sent by a client, paths that are empty, leave the storage root or point into a hidden directory are rejected
+pub fn check_path(path: &str) -> Result<(), ServerError> {
+    let path = key(path);
+    let first = path.split('/').find(|segment| !segment.is_empty() && *segment != ".");
+
+    if first.is_none_or(|first| HIDDEN_DIRS.contains(&first)) || path.split('/').any(|segment| segment == "..") {
+        return Err(ServerError::new(ErrorCode::InvalidPath, format!("Invalid path: {path}")));
+    }
+
+    Ok(())
+}
+
+/// Returns the path of an entry file inside a store
+fn entry_path(store: &str, entry: &Entry) -> String {
+    format!("{store}/{}/{}-{}", entry.path, entry.number, entry.time)
+}
+
+/// Parse an entry file name formated like `number-time`
+fn parse_entry_name(name: &str) -> Option<(u64, u64)> {
+    let (number, time) = name.split_once('-')?;
+
+    Some((number.parse().ok()?, time.parse().ok()?))
+}
+
+/// Returns the current time in seconds since the unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+/// Parse an age like `30`, `30s`, `5m`, `3h` or `2d` into seconds, the opposite of format_age
+pub fn parse_age(age: &str) -> Option<u64> {
+    let (number, unit) = match age.find(|character: char| !character.is_ascii_digit()) {
+        Some(index) => age.split_at(index),
+        None => (age, "s"),
+    };
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return None,
+    };
+
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+/// Convert a unix timestamp to the (year, month, day) it falls on
+pub fn civil_date(seconds: u64) -> (i64, i64, i64) {
+    let days = (seconds / 86400) as i64;
+
+    // Convert days since the unix epoch to a civil date (Howard Hinnant's days_from_civil inverse)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+/// Format an amount of seconds into a short human readable age, like `5m` or `3d`
+pub fn format_age(seconds: u64) -> String {
+    match seconds {
+        0..=59 => format!("{seconds}s"),
+        60..=3599 => format!("{}m", seconds / 60),
+        3600..=86399 => format!("{}h", seconds / 3600),
+        _ => format!("{}d", seconds / 86400),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::*;
+    use crate::config::Config;
+
+    /// Returns a storage keeping files in memory, with the given lines added to the server configuration
+    fn storage(settings: &str) -> Storage {
+        let config = format!("[server]\nthread_count = 1\nips = []\nstorage = \"memory\"\n{settings}");
+        let config: Config = toml::from_str(&config).unwrap();
+
+        Storage::build(&config.server().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn blobs_are_counted_and_removed_with_their_last_reference() {
+        let storage = storage("dedup = true");
+        let hash = hex::encode(Sha256::digest(b"artifact"));
+
+        storage.upload("a.bin", b"artifact", "alice").unwrap();
+        storage.upload("b.bin", b"artifact", "bob").unwrap();
+        assert_eq!(storage.refs(&hash).unwrap(), 2);
+        assert_eq!(storage.read("b.bin").unwrap(), b"artifact");
+
+        // Removing one path keeps the blob for the other
+        storage.remove("a.bin").unwrap();
+        assert_eq!(storage.refs(&hash).unwrap(), 1);
+        assert_eq!(storage.read("b.bin").unwrap(), b"artifact");
+
+        // Removing the last one deletes the blob, its count and its grants
+        storage.remove("b.bin").unwrap();
+        assert!(storage.backend.stat(&blob_path(&hash)).unwrap().is_none());
+        assert!(storage.backend.stat(&refs_path(&hash)).unwrap().is_none());
+        assert!(storage.load_meta(&grants_name(&hash)).unwrap().is_none());
+    }
+
+    #[test]
+    fn only_users_that_uploaded_a_blob_can_link_it() {
+        let storage = storage("dedup = true");
+        let hash = hex::encode(Sha256::digest(b"secret"));
+
+        storage.upload("alice/secret.txt", b"secret", "alice").unwrap();
+
+        // Knowing the hash is not enough to get the content
+        assert!(!storage.link("mallory/secret.txt", &hash, "mallory").unwrap());
+        assert_eq!(storage.size_of("mallory/secret.txt").unwrap(), None);
+
+        assert!(storage.link("alice/copy.txt", &hash, "alice").unwrap());
+        assert_eq!(storage.read("alice/copy.txt").unwrap(), b"secret");
+        assert_eq!(storage.refs(&hash).unwrap(), 2);
+
+        // Blobs that dont exist are never linked
+        assert!(!storage.link("alice/missing.txt", &hex::encode(Sha256::digest(b"missing")), "alice").unwrap());
+    }
+
+    /// Returns the numbers and data of the kept versions of the file at path
+    fn kept(storage: &Storage, path: &str) -> Vec<(u64, Vec<u8>)> {
+        storage.versions(path).unwrap()
+            .iter()
+            .map(|version| (version.number, storage.open(&entry_path(VERSIONS_DIR, version)).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn versions_are_kept_up_to_version_count_and_restored() {
+        let storage = storage("version_count = 2");
+
+        for data in [b"1", b"2", b"3", b"4"] {
+            storage.write("a.txt", data).unwrap();
+        }
+        assert_eq!(storage.read("a.txt").unwrap(), b"4");
+        assert_eq!(kept(&storage, "a.txt"), [(2, b"2".to_vec()), (3, b"3".to_vec())]);
+
+        // Restoring keeps the replaced file as the newest version
+        storage.restore("a.txt", 2).unwrap();
+        assert_eq!(storage.read("a.txt").unwrap(), b"2");
+        assert_eq!(kept(&storage, "a.txt"), [(3, b"3".to_vec()), (4, b"4".to_vec())]);
+
+        let missing = storage.restore("a.txt", 2).unwrap_err();
+        assert_eq!(missing.code(), ErrorCode::NotFound);
+        // Versions are not listed as files
+        assert_eq!(storage.files().unwrap(), [(String::from("a.txt"), 1)]);
+    }
+
+    #[test]
+    fn versions_over_version_age_are_purged() {
+        let storage = storage("version_age = 60");
+
+        storage.write("a.txt", b"1").unwrap();
+        storage.write("a.txt", b"2").unwrap();
+        // An entry replaced long ago
+        storage.backend.create(&format!("{VERSIONS_DIR}/a.txt/0-0"), b"0").unwrap();
+        assert_eq!(kept(&storage, "a.txt").len(), 2);
+
+        storage.purge().unwrap();
+        assert_eq!(kept(&storage, "a.txt"), [(1, b"1".to_vec())]);
+    }
+
+    #[test]
+    fn files_of_a_path_are_archived_one_at_a_time() {
+        let storage = storage("");
+        storage.write("a.txt", b"1").unwrap();
+
+        let archiving = storage.start_archiving(format!("{TRASH_DIR}/a.txt"));
+        thread::scope(|scope| {
+            let deleted = scope.spawn(|| storage.delete("a.txt"));
+
+            // The delete waits until the entry it would number is no longer being archived
+            thread::sleep(Duration::from_millis(50));
+            assert!(!deleted.is_finished());
+            assert_eq!(storage.size_of("a.txt").unwrap(), Some(1));
+
+            drop(archiving);
+            deleted.join().unwrap().unwrap();
+        });
+
+        assert_eq!(storage.trash().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn deleted_and_replaced_files_go_to_the_trash() {
+        let storage = storage("trash_age = 60");
+
+        storage.write("a.txt", b"1").unwrap();
+        storage.write("a.txt", b"2").unwrap();
+        storage.delete("a.txt").unwrap();
+        assert_eq!(storage.size_of("a.txt").unwrap(), None);
+        assert_eq!(storage.delete("a.txt").unwrap_err().code(), ErrorCode::NotFound);
+
+        let entries: Vec<(String, u64)> = storage.trash().unwrap().into_iter().map(|entry| (entry.path, entry.number)).collect();
+        assert_eq!(entries, [(String::from("a.txt"), 1), (String::from("a.txt"), 2)]);
+
+        // The most recently trashed file comes back, and only if nothing took its place
+        storage.undelete("a.txt").unwrap();
+        assert_eq!(storage.read("a.txt").unwrap(), b"2");
+        assert_eq!(storage.undelete("a.txt").unwrap_err().code(), ErrorCode::AlreadyExists);
+        assert_eq!(storage.undelete("b.txt").unwrap_err().code(), ErrorCode::NotFound);
+
+        // Only entries over trash_age are purged
+        storage.backend.create(&format!("{TRASH_DIR}/b.txt/1-0"), b"old").unwrap();
+        storage.purge().unwrap();
+        let paths: Vec<String> = storage.trash().unwrap().into_iter().map(|entry| entry.path).collect();
+        assert_eq!(paths, ["a.txt"]);
+    }
+
+    #[test]
+    fn ages_are_parsed_with_their_unit() {
+        assert_eq!(parse_age("30"), Some(30));
+        assert_eq!(parse_age("30s"), Some(30));
+        assert_eq!(parse_age("5m"), Some(300));
+        assert_eq!(parse_age("3h"), Some(10800));
+        assert_eq!(parse_age("2d"), Some(172800));
+
+        for age in ["", "d", "5w", "5mm", "-5m", "99999999999999999999d", "999999999999999d"] {
+            assert_eq!(parse_age(age), None, "{age}");
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    io::{self, Read, Write},
+    sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Largest amount of bytes read or written at once on a throttled stream, smaller pieces keep the rate smooth
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Token bucket limiting how many bytes per second pass through it. The bucket holds up to a second worth of bytes, so short bursts
+/// are not slowed down
+pub struct Bucket {
+    /// Bytes per second, 0 means unlimited. The rate can be shared between buckets so changing it changes all of them
+    rate: Arc<AtomicU64>,
+    /// Bytes that can pass right away (negative once the bucket is in debt) and the last time it was refilled
+    state: Mutex<(f64, Instant)>,
+}
+
+impl Bucket {
+    /// Create a bucket with its own rate
+    pub fn new(rate: u64) -> Bucket {
+        Bucket::with_rate(Arc::new(AtomicU64::new(rate)))
+    }
+    /// Create a bucket using a shared rate
+    pub fn with_rate(rate: Arc<AtomicU64>) -> Bucket {
+        Bucket {
+            rate,
+            state: Mutex::new((0.0, Instant::now())),
+        }
+    }
+    pub fn rate(&self) -> u64 {
+        self.rate.load(Ordering::Relaxed)
+    }
+    pub fn set_rate(&self, rate: u64) {
+        self.rate.store(rate, Ordering::Relaxed);
+    }
+    /// Take amount bytes from the bucket, sleeping until the rate allows them
+    pub fn take(&self, amount: usize) {
+        // The lock is released before sleeping so other streams can take their share
+        let wait = self.take_at(amount, Instant::now());
+
+        thread::sleep(wait);
+    }
+    /// Take amount bytes from the bucket at now, returns how long to wait before they may pass
+    fn take_at(&self, amount: usize, now: Instant) -> Duration {
+        let rate = self.rate() as f64;
+        if rate == 0.0 {
+            return Duration::ZERO;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let (tokens, refilled) = &mut *state;
+
+        *tokens = (*tokens + now.saturating_duration_since(*refilled).as_secs_f64() * rate).min(rate);
+        *refilled = now;
+        *tokens -= amount as f64;
+
+        // Bytes taken while in debt wait for it to be paid off
+        match *tokens < 0.0 {
+            true => Duration::from_secs_f64(-*tokens / rate),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+/// A stream whose reads and writes are limited by one or more buckets, like one for the connection and one shared by every
+/// connection
+pub struct Throttled<T> {
+    inner: T,
+    buckets: Vec<Arc<Bucket>>,
+}
+
+impl<T> Throttled<T> {
+    pub fn new(inner: T, buckets: Vec<Arc<Bucket>>) -> Throttled<T> {
+        Throttled { inner, buckets }
+    }
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+    fn consume(&self, amount: usize) {
+        for bucket in &self.buckets {
+            bucket.take(amount);
+        }
+    }
+}
+
+impl<T: Read> Read for Throttled<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(CHUNK_SIZE);
+        let read = self.inner.read(&mut buf[..len])?;
+        self.consume(read);
+
+        Ok(read)
+    }
+}
+
+impl<T: Write> Write for Throttled<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(CHUNK_SIZE);
+        let written = self.inner.write(&buf[..len])?;
+        self.consume(written);
+
+        Ok(written)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Bandwidth limits of the server, one shared by every connection and one for each connection. Both can be changed while the server
+/// runs, connections that are already open follow the new limits
+pub struct Bandwidth {
+    global: Arc<Bucket>,
+    client_rate: Arc<AtomicU64>,
+}
+
+impl Bandwidth {
+    pub fn new(global_rate: u64, client_rate: u64) -> Bandwidth {
+        Bandwidth {
+            global: Arc::new(Bucket::new(global_rate)),
+            client_rate: Arc::new(AtomicU64::new(client_rate)),
+        }
+    }
+    /// Limit a connection to the client rate and the global rate
+    pub fn throttle<T>(&self, stream: T) -> Throttled<T> {
+        let client = Arc::new(Bucket::with_rate(self.client_rate.clone()));
+
+        Throttled::new(stream, vec![client, self.global.clone()])
+    }
+    pub fn global_rate(&self) -> u64 {
+        self.global.rate()
+    }
+    pub fn set_global_rate(&self, rate: u64) {
+        self.global.set_rate(rate);
+    }
+    pub fn client_rate(&self) -> u64 {
+        self.client_rate.load(Ordering::Relaxed)
+    }
+    pub fn set_client_rate(&self, rate: u64) {
+        self.client_rate.store(rate, Ordering::Relaxed);
+    }
+}
+
+/// Returns a rate as text, like `1000 bytes/s` or `unlimited`
+pub fn format_rate(rate: u64) -> String {
+    match rate {
+        0 => String::from("unlimited"),
+        rate => format!("{rate} bytes/s"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_refill_up_to_a_second_of_bytes() {
+        let bucket = Bucket::new(100);
+        let start = bucket.state.lock().unwrap().1;
+
+        // The bucket starts empty
+        assert_eq!(bucket.take_at(50, start), Duration::from_millis(500));
+
+        // Idle for long enough, a second worth of bytes passes at once but no more
+        let later = start + Duration::from_secs(10);
+        assert_eq!(bucket.take_at(100, later), Duration::ZERO);
+        assert_eq!(bucket.take_at(50, later), Duration::from_millis(500));
+        assert_eq!(bucket.take_at(50, later + Duration::from_millis(500)), Duration::from_millis(500));
+
+        bucket.set_rate(0);
+        assert_eq!(bucket.take_at(1_000_000, later), Duration::ZERO);
+    }
+
+    #[test]
+    fn connections_share_the_global_bucket() {
+        let bandwidth = Bandwidth::new(1000, 100);
+        let (first, second) = (bandwidth.throttle(Vec::<u8>::new()), bandwidth.throttle(Vec::<u8>::new()));
+        let start = bandwidth.global.state.lock().unwrap().1 + Duration::from_secs(10);
+
+        assert!(Arc::ptr_eq(&first.buckets[1], &second.buckets[1]));
+        assert!(!Arc::ptr_eq(&first.buckets[0], &second.buckets[0]));
+
+        // Each connection has a bucket of its own at the client rate, and takes from the global bucket
+        assert_eq!(first.buckets[0].take_at(100, start), Duration::ZERO);
+        assert_eq!(first.buckets[0].take_at(50, start), Duration::from_millis(500));
+        assert_eq!(second.buckets[0].take_at(100, start), Duration::ZERO);
+        assert_eq!(bandwidth.global.take_at(1000, start), Duration::ZERO);
+        assert_eq!(second.buckets[1].take_at(500, start), Duration::from_millis(500));
+
+        // Changing the rates changes them for open connections
+        bandwidth.set_client_rate(0);
+        bandwidth.set_global_rate(0);
+        assert_eq!(first.buckets[0].rate(), 0);
+        assert_eq!(second.buckets[1].rate(), 0);
+    }
+
+    #[test]
+    fn throttled_streams_pass_everything_in_chunks() {
+        let mut stream = Bandwidth::new(0, 0).throttle(Vec::new());
+        let data = vec![7; CHUNK_SIZE * 2 + 1];
+
+        assert_eq!(stream.write(&data).unwrap(), CHUNK_SIZE);
+        stream.write_all(&data[CHUNK_SIZE..]).unwrap();
+        assert!(*stream.get_ref() == data);
+
+        let mut read = Vec::new();
+        Throttled::new(data.as_slice(), Vec::new()).read_to_end(&mut read).unwrap();
+        assert!(read == data);
+    }
+}
@@ -0,0 +1,64 @@
+use std::{fs::File, io::BufReader, net::TcpStream, sync::Arc};
+
+use rustls::{pki_types::{CertificateDer, ServerName}, ClientConnection, RootCertStore, ServerConnection, StreamOwned};
+
+pub use rustls::{ClientConfig, ServerConfig};
+
+use crate::{connection::Connection, FileShareError};
+
+/// Create the TLS configuration for a listener from PEM files containing the certificate chain and private key
+pub fn server_config(cert: &str, key: &str) -> Result<Arc<ServerConfig>, FileShareError> {
+    let certs = load_certs(cert)?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key)?))?
+        .ok_or_else(|| FileShareError::Config(format!("No private key found in {key}")))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+
+    Ok(Arc::new(config))
+}
+
+/// Create the TLS configuration for a client that trusts the certificates in the given PEM file
+pub fn client_config(ca: &str) -> Result<Arc<ClientConfig>, FileShareError> {
+    let mut roots = RootCertStore::empty();
+
+    for cert in load_certs(ca)? {
+        roots.add(cert)?;
+    }
+
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    Ok(Arc::new(config))
+}
+
+/// Wrap the server side of a TCP stream in TLS, the handshake happens on the first read or write
+pub fn accept(stream: TcpStream, config: Arc<ServerConfig>) -> Result<Connection, FileShareError> {
+    let connection = ServerConnection::new(config)?;
+
+    Ok(Connection::TlsServer(Box::new(StreamOwned::new(connection, stream))))
+}
+
+/// Wrap the client side of a TCP stream in TLS, host is the name (or ip) the server certificate must be valid for. The handshake
+/// happens on the first read or write
+pub fn connect(stream: TcpStream, config: Arc<ClientConfig>, host: &str) -> Result<Connection, FileShareError> {
+    let name = ServerName::try_from(host.to_string())
+        .map_err(|error| FileShareError::Config(format!("Invalid server name {host}: {error}")))?;
+    let connection = ClientConnection::new(config, name)?;
+
+    Ok(Connection::TlsClient(Box::new(StreamOwned::new(connection, stream))))
+}
+
+/// Load every certificate in a PEM file
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, FileShareError> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))
+        .collect::<Result<Vec<CertificateDer>, _>>()?;
+
+    if certs.is_empty() {
+        return Err(FileShareError::Config(format!("No certificates found in {path}")));
+    }
+
+    Ok(certs)
+}
@@ -0,0 +1,976 @@
+use std::{collections::HashMap, io::BufReader, sync::Mutex};
+
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+use crate::{
+    connection::Transport,
+    events::Event,
+    http::{self, Request, Response},
+    server::{ServerState, Session},
+    storage,
+    CommandType, FileShareError,
+};
+
+/// Name of the meta file holding the collections created with MKCOL. Storage has no empty directories, collections that contain
+/// files exist without being listed here
+const COLLECTIONS_META: &str = "webdav-collections";
+/// Characters that are not percent encoded in hrefs
+const HREF_SET: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'_').remove(b'.').remove(b'~').remove(b'/');
+/// Methods the WebDAV frontend supports
+const ALLOWED_METHODS: &str = "OPTIONS, PROPFIND, GET, HEAD, PUT, DELETE, MKCOL, COPY, MOVE, LOCK, UNLOCK";
+/// Largest request body that is read, the bodies of PROPFIND and LOCK requests are small xml documents
+const MAX_XML_BODY: u64 = 1024 * 1024;
+/// Seconds locks last for, unless they are refreshed
+const LOCK_TIMEOUT: u64 = 3600;
+
+/// State of the WebDAV frontend shared between its connections
+#[derive(Default)]
+pub struct WebDav {
+    /// Held while changing the collections meta file
+    collections: Mutex<()>,
+    /// Locks taken with LOCK, by their token
+    locks: Mutex<HashMap<String, Lock>>,
+}
+
+/// A lock on a file or collection. Locks are only checked for WebDAV requests, the native protocol and the HTTP frontend dont know
+/// about them
+#[derive(Clone)]
+struct Lock {
+    /// Path of the locked file or collection
+    root: String,
+    /// User that took the lock, the token only unlocks the lock for them
+    user: String,
+    /// Shared locks can be held by several clients at once, exclusive locks by only one
+    shared: bool,
+    /// True if everything inside the locked collection is locked too (Depth infinity)
+    recursive: bool,
+    /// Time the lock expires at, in seconds since the unix epoch
+    expires: u64,
+}
+
+impl Lock {
+    /// Returns true if the lock covers the file or collection at path, or anything inside it if inside is set
+    fn covers(&self, path: &str, inside: bool) -> bool {
+        self.root == path
+            || (self.recursive && path.starts_with(&collection_prefix(&self.root)))
+            || (inside && self.root.starts_with(&collection_prefix(path)))
+    }
+}
+
+/// The files and collections in storage at and inside a path
+struct Tree {
+    /// Paths and sizes of the files
+    files: Vec<(String, u64)>,
+    /// Paths of the collections, the root collection is the empty path and is not included
+    collections: Vec<String>,
+}
+
+impl Tree {
+    /// List the file or collection at path and what is inside it, only the direct children unless recursive is set. Nothing outside
+    /// path is listed, so requests on a collection dont read the whole storage
+    fn load(state: &ServerState, path: &str, recursive: bool) -> Result<Tree, FileShareError> {
+        let storage = state.storage();
+        let prefix = collection_prefix(path);
+
+        let (mut files, mut collections) = match recursive {
+            true => (storage.files_in(path)?, Vec::new()),
+            false => storage.children(path)?,
+        };
+        if !path.is_empty() {
+            if let Some(size) = storage.size_of(path)? {
+                files.push((path.to_string(), size));
+            }
+        }
+
+        // Every directory a file or collection is in is a collection
+        let mut parents = Vec::new();
+        for child in files.iter().map(|(file, _)| file).chain(&collections) {
+            let mut child = child.as_str();
+
+            while let Some((parent, _)) = child.rsplit_once('/') {
+                parents.push(parent.to_string());
+                child = parent;
+            }
+        }
+        collections.append(&mut parents);
+
+        // Collections created with MKCOL dont exist in storage until a file is put in them
+        collections.extend(
+            load_collections(state)?
+                .into_iter()
+                .filter(|collection| collection == path || collection.starts_with(&prefix))
+        );
+
+        collections.sort();
+        collections.dedup();
+
+        Ok(Tree {
+            files,
+            collections,
+        })
+    }
+    fn is_collection(&self, path: &str) -> bool {
+        path.is_empty() || self.collections.iter().any(|collection| collection == path)
+    }
+    /// Returns the size of the file at path, or None if there is no file at path
+    fn file_size(&self, path: &str) -> Option<u64> {
+        self.files.iter().find(|(file, _)| file == path).map(|(_, size)| *size)
+    }
+    /// Returns the files and collections inside the collection at path, only the direct children unless recursive is set
+    fn children(&self, path: &str, recursive: bool) -> (Vec<&(String, u64)>, Vec<&String>) {
+        let prefix = collection_prefix(path);
+        let is_child = |child: &str| match child.strip_prefix(&prefix) {
+            Some(rest) => !rest.is_empty() && (recursive || !rest.contains('/')),
+            None => false,
+        };
+
+        (
+            self.files.iter().filter(|(file, _)| is_child(file)).collect(),
+            self.collections.iter().filter(|collection| is_child(collection)).collect(),
+        )
+    }
+}
+
+/// Handle a client of the WebDAV frontend. Requests are served until the client closes the connection, files are read and changed
+/// through the same commands (and so the same storage, limits and auth checks) as the native protocol
+pub fn handle_client(stream: impl Transport, session: Session, state: &ServerState) {
+    http::serve(state.bandwidth().throttle(stream), session, state, handle_request)
+}
+
+/// Route a request to its handler
+fn handle_request<T: Transport>(request: &mut Request, stream: &mut BufReader<T>, session: &mut Session, state: &ServerState) -> Response {
+    let path = match decode(&request.path) {
+        Ok(path) => path,
+        Err(error) => return Response::error(400, &error.to_string()),
+    };
+
+    match request.method.as_str() {
+        "OPTIONS" => Response::empty(200)
+            .header("DAV", String::from("1, 2"))
+            .header("Allow", String::from(ALLOWED_METHODS)),
+        "PROPFIND" => propfind(request, stream, &path, session, state),
+        "GET" | "HEAD" => get(request, &path, session, state),
+        "PUT" => put(request, stream, &path, session, state),
+        "DELETE" => delete(request, &path, session, state),
+        "MKCOL" => mkcol(request, &path, session, state),
+        "COPY" => transfer(request, &path, false, session, state),
+        "MOVE" => transfer(request, &path, true, session, state),
+        "LOCK" => lock(request, stream, &path, session, state),
+        "UNLOCK" => unlock(request, &path, session, state),
+        _ => Response::error(405, "Method not allowed").header("Allow", String::from(ALLOWED_METHODS)),
+    }
+}
+
+/// Percent decode the path of a request without its leading and trailing slashes, the root collection is the empty path
+fn decode(path: &str) -> Result<String, FileShareError> {
+    match path.trim_matches('/') {
+        "" => Ok(String::new()),
+        path => Ok(http::decode_path(path)?.trim_matches('/').to_string()),
+    }
+}
+
+/// Returns the prefix of the paths inside the collection at path
+fn collection_prefix(path: &str) -> String {
+    match path.is_empty() {
+        true => String::new(),
+        false => format!("{path}/"),
+    }
+}
+
+/// Returns the path of the collection the file or collection at path is in
+fn parent(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(parent, _)| parent)
+}
+
+/// Returns the collections created with MKCOL
+fn load_collections(state: &ServerState) -> Result<Vec<String>, FileShareError> {
+    match state.storage().load_meta(COLLECTIONS_META)? {
+        Some(data) => Ok(String::from_utf8_lossy(&data).lines().map(String::from).collect()),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Change the collections created with MKCOL
+fn update_collections(state: &ServerState, update: impl FnOnce(&mut Vec<String>)) -> Result<(), FileShareError> {
+    let _lock = state.webdav().collections.lock().unwrap();
+    let mut collections = load_collections(state)?;
+
+    update(&mut collections);
+    collections.sort();
+    collections.dedup();
+
+    state.storage().save_meta(COLLECTIONS_META, collections.join("\n").as_bytes())
+}
+
+/// Check if the client may run all the given commands
+fn authorize(session: &Session, command_types: &[CommandType]) -> Result<(), Response> {
+    for command_type in command_types {
+        session.authorize(command_type).map_err(|error| Response::unauthorized(&error.to_string()))?;
+    }
+
+    Ok(())
+}
+
+/// Returns the lock tokens sent in the If header of a request
+fn submitted_tokens(request: &Request) -> Vec<&str> {
+    request.header("if")
+        .map(|header| header.split('<').skip(1).filter_map(|rest| rest.split_once('>')).map(|(token, _)| token).collect())
+        .unwrap_or_default()
+}
+
+/// Check if the client may change the file or collection at path (and anything inside it if inside is set). Every lock on it must
+/// have its token (or, for shared locks, the token of another shared lock on the same path) in the If header, or the request is
+/// refused with 423
+fn check_locks(request: &Request, path: &str, inside: bool, session: &Session, state: &ServerState) -> Result<(), Response> {
+    let tokens = submitted_tokens(request);
+    let mut locks = state.webdav().locks.lock().unwrap();
+
+    let now = storage::now();
+    locks.retain(|_, lock| lock.expires > now);
+
+    let held = |root: &str| {
+        locks.iter().any(|(token, lock)| lock.root == root && lock.user == session.user() && tokens.contains(&token.as_str()))
+    };
+
+    match locks.values().find(|lock| lock.covers(path, inside) && !held(&lock.root)) {
+        Some(lock) => Err(Response::error(423, &format!("{} is locked", lock.root))),
+        None => Ok(()),
+    }
+}
+
+/// Drop the locks on the file or collection at path and anything inside it, once it was deleted or moved away
+fn release_locks(path: &str, state: &ServerState) {
+    let prefix = collection_prefix(path);
+
+    state.webdav().locks.lock().unwrap().retain(|_, lock| lock.root != path && !lock.root.starts_with(&prefix));
+}
+
+/// Load the tree at path, responding with an error if storage cant be listed
+fn load_tree(state: &ServerState, path: &str, recursive: bool) -> Result<Tree, Response> {
+    Tree::load(state, path, recursive).map_err(|error| Response::error(500, &error.to_string()))
+}
+
+/// Read the xml body of a request, an empty body is returned if the request has none
+fn read_xml_body(request: &mut Request, stream: &mut BufReader<impl Transport>) -> Result<String, Response> {
+    if !request.body_pending {
+        return Ok(String::new());
+    }
+
+    let body = match request.header("content-length").map(str::parse::<u64>) {
+        Some(Ok(length)) => http::read_body(stream, length, Some(MAX_XML_BODY))?,
+        Some(Err(_)) => return Err(Response::error(400, "Invalid Content-Length")),
+        None => http::read_chunked_body(stream, Some(MAX_XML_BODY))?,
+    };
+    request.body_pending = false;
+
+    String::from_utf8(body).map_err(|_| Response::error(400, "Body is not valid UTF-8"))
+}
+
+/// Describe the file or collection at path and, depending on the Depth header, what is inside it
+fn propfind(
+    request: &mut Request,
+    stream: &mut BufReader<impl Transport>,
+    path: &str,
+    session: &mut Session,
+    state: &ServerState,
+) -> Response {
+    // Depth infinity lists everything inside the collection, only that needs the whole tree under path. The properties asked for
+    // are ignored since all of them are always sent
+    let depth = request.header("depth").unwrap_or("infinity").to_string();
+    let tree = match authorize(session, &[CommandType::Catalog])
+        .and_then(|_| read_xml_body(request, stream))
+        .and_then(|_| load_tree(state, path, depth != "0" && depth != "1"))
+    {
+        Ok(tree) => tree,
+        Err(response) => return response,
+    };
+
+    let mut responses = String::new();
+
+    if tree.is_collection(path) {
+        responses.push_str(&entry(path, None, None));
+
+        let (files, collections) = match depth.as_str() {
+            "0" => (Vec::new(), Vec::new()),
+            "1" => tree.children(path, false),
+            _ => tree.children(path, true),
+        };
+
+        for collection in collections {
+            responses.push_str(&entry(collection, None, None));
+        }
+        for (file, size) in files {
+            let modified = state.storage().modified_of(file).ok().flatten();
+
+            responses.push_str(&entry(file, Some(*size), modified));
+        }
+    } else if let Some(size) = tree.file_size(path) {
+        let modified = state.storage().modified_of(path).ok().flatten();
+
+        responses.push_str(&entry(path, Some(size), modified));
+    } else {
+        return Response::error(404, &format!("{path} does not exist"));
+    }
+
+    Response::new(
+        207,
+        "application/xml; charset=utf-8",
+        format!("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n{responses}</D:multistatus>\n")
+            .into_bytes(),
+    )
+}
+
+/// Returns the PROPFIND response element of a file (with its size and modification time) or collection (without a size)
+fn entry(path: &str, size: Option<u64>, modified: Option<u64>) -> String {
+    let name = path.rsplit('/').next().unwrap_or_default();
+    let mut href = format!("/{}", utf8_percent_encode(path, HREF_SET));
+    let mut properties = format!("<D:displayname>{}</D:displayname>", escape(name));
+
+    match size {
+        Some(size) => {
+            properties.push_str(&format!(
+                "<D:resourcetype/><D:getcontentlength>{size}</D:getcontentlength>\
+                 <D:getcontenttype>application/octet-stream</D:getcontenttype>",
+            ));
+
+            if let Some(modified) = modified {
+                properties.push_str(&format!("<D:getlastmodified>{}</D:getlastmodified>", http_date(modified)));
+            }
+        }
+        None => {
+            properties.push_str("<D:resourcetype><D:collection/></D:resourcetype>");
+
+            if !path.is_empty() {
+                href.push('/');
+            }
+        }
+    }
+
+    format!(
+        "<D:response><D:href>{href}</D:href><D:propstat><D:prop>{properties}</D:prop>\
+         <D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>\n",
+    )
+}
+
+/// Escape the characters that have a meaning in XML
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Format a unix timestamp as an HTTP date, like `Sun, 06 Nov 1994 08:49:37 GMT`
+fn http_date(seconds: u64) -> String {
+    const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let (year, month, day) = storage::civil_date(seconds);
+    let time = seconds % 86400;
+
+    format!(
+        "{}, {day:02} {} {year} {:02}:{:02}:{:02} GMT",
+        // The unix epoch was on a thursday
+        WEEKDAYS[((seconds / 86400 + 4) % 7) as usize],
+        MONTHS[(month - 1) as usize],
+        time / 3600,
+        time % 3600 / 60,
+        time % 60,
+    )
+}
+
+/// Send a file, collections cant be downloaded
+fn get(request: &Request, path: &str, session: &mut Session, state: &ServerState) -> Response {
+    if path.is_empty() {
+        return Response::error(405, "Collections cant be downloaded");
+    }
+
+    http::get(request, path, session, state)
+}
+
+/// Store the body of the request as a file, the collection it is stored in must exist
+fn put(
+    request: &mut Request,
+    stream: &mut BufReader<impl Transport>,
+    path: &str,
+    session: &mut Session,
+    state: &ServerState,
+) -> Response {
+    let tree = match authorize(session, &[CommandType::Upload])
+        .and_then(|_| check_locks(request, path, false, session, state))
+        .and_then(|_| load_tree(state, parent(path), false))
+    {
+        Ok(tree) => tree,
+        Err(response) => return response,
+    };
+
+    if tree.is_collection(path) {
+        return Response::error(405, "A collection exists at this path");
+    }
+    if !tree.is_collection(parent(path)) {
+        return Response::error(409, &format!("Collection {} does not exist", parent(path)));
+    }
+
+    http::put(request, stream, path, session, state)
+}
+
+/// Move a file into the trash, or every file inside a collection
+fn delete(request: &Request, path: &str, session: &mut Session, state: &ServerState) -> Response {
+    let tree = match authorize(session, &[CommandType::Delete])
+        .and_then(|_| check_locks(request, path, true, session, state))
+        .and_then(|_| load_tree(state, path, true))
+    {
+        Ok(tree) => tree,
+        Err(response) => return response,
+    };
+
+    if path.is_empty() {
+        return Response::error(403, "The root collection cant be deleted");
+    }
+
+    let response = match (tree.file_size(path), tree.is_collection(path)) {
+        (Some(_), _) => http::delete(path, session, state),
+        (None, true) => match delete_collection(&tree, path, session, state) {
+            Ok(()) => Response::empty(204),
+            Err(response) => response,
+        },
+        (None, false) => return Response::error(404, &format!("{path} does not exist")),
+    };
+
+    if response.succeeded() {
+        release_locks(path, state);
+    }
+
+    response
+}
+
+/// Move every file inside the collection at path into the trash and forget the collections inside it
+fn delete_collection(tree: &Tree, path: &str, session: &mut Session, state: &ServerState) -> Result<(), Response> {
+    let (files, _) = tree.children(path, true);
+
+    for (file, _) in files {
+        http::execute(CommandType::Delete, vec![file.clone()], None, session, state)?;
+    }
+
+    let prefix = collection_prefix(path);
+
+    update_collections(state, |collections| {
+        collections.retain(|collection| collection != path && !collection.starts_with(&prefix))
+    })
+    .map_err(|error| Response::error(500, &error.to_string()))
+}
+
+/// Create an empty collection, the collection it is created in must exist
+fn mkcol(request: &Request, path: &str, session: &mut Session, state: &ServerState) -> Response {
+    let tree = match authorize(session, &[CommandType::Upload])
+        .and_then(|_| check_locks(request, path, false, session, state))
+        .and_then(|_| load_tree(state, parent(path), false))
+    {
+        Ok(tree) => tree,
+        Err(response) => return response,
+    };
+
+    // Bodies could describe what to create inside the collection, which is not supported
+    if request.body_pending {
+        return Response::error(415, "MKCOL with a body is not supported");
+    }
+    if tree.is_collection(path) || tree.file_size(path).is_some() {
+        return Response::error(405, &format!("{path} already exists"));
+    }
+    if !tree.is_collection(parent(path)) {
+        return Response::error(409, &format!("Collection {} does not exist", parent(path)));
+    }
+
+    match update_collections(state, |collections| collections.push(path.to_string())) {
+        Ok(()) => Response::empty(201),
+        Err(error) => Response::error(500, &error.to_string()),
+    }
+}
+
+/// Copy or move a file or collection to the path in the Destination header. Collections are copied with everything inside them,
+/// unless the Depth header is 0
+fn transfer(request: &Request, path: &str, moving: bool, session: &mut Session, state: &ServerState) -> Response {
+    let command_types: &[CommandType] = match moving {
+        true => &[CommandType::Receive, CommandType::Upload, CommandType::Delete],
+        false => &[CommandType::Receive, CommandType::Upload],
+    };
+    let tree = match authorize(session, command_types).and_then(|_| load_tree(state, path, true)) {
+        Ok(tree) => tree,
+        Err(response) => return response,
+    };
+
+    // The destination is an absolute URL or an absolute path
+    let destination = match request.header("destination") {
+        Some(destination) => destination.split_once("://").map_or(destination, |(_, rest)| {
+            rest.find('/').map_or("/", |start| &rest[start..])
+        }),
+        None => return Response::error(400, "Destination header required"),
+    };
+    let destination = match decode(destination) {
+        Ok(destination) => destination,
+        Err(error) => return Response::error(400, &error.to_string()),
+    };
+    // Moving changes the source, and whatever is at the destination is replaced
+    let locked = match moving {
+        true => check_locks(request, path, true, session, state),
+        false => Ok(()),
+    };
+    if let Err(response) = locked.and_then(|_| check_locks(request, &destination, true, session, state)) {
+        return response;
+    }
+
+    let overwrite = request.header("overwrite").is_none_or(|overwrite| !overwrite.eq_ignore_ascii_case("F"));
+    let shallow = !moving && request.header("depth") == Some("0");
+
+    let is_file = tree.file_size(path).is_some();
+    if path.is_empty() || (!is_file && !tree.is_collection(path)) {
+        return Response::error(404, &format!("{path} does not exist"));
+    }
+    if destination.is_empty() || destination == path || destination.starts_with(&collection_prefix(path)) {
+        return Response::error(403, "Cant copy or move a resource onto itself or into itself");
+    }
+
+    let target = match load_tree(state, parent(&destination), false) {
+        Ok(target) => target,
+        Err(response) => return response,
+    };
+    if !target.is_collection(parent(&destination)) {
+        return Response::error(409, &format!("Collection {} does not exist", parent(&destination)));
+    }
+
+    // Whatever is at the destination is replaced
+    let existed = target.file_size(&destination).is_some() || target.is_collection(&destination);
+    if existed && !overwrite {
+        return Response::error(412, &format!("{destination} already exists"));
+    }
+    if existed {
+        if let Err(response) = authorize(session, &[CommandType::Delete]).and_then(|_| remove(&destination, session, state)) {
+            return response;
+        }
+    }
+
+    let transferred = match is_file {
+        true => transfer_file(path, &destination, moving, session, state),
+        false => transfer_collection(&tree, path, &destination, moving, shallow, session, state),
+    };
+
+    if transferred.is_ok() && moving {
+        release_locks(path, state);
+    }
+
+    match transferred {
+        Ok(()) if existed => Response::empty(204),
+        Ok(()) => Response::empty(201),
+        Err(response) => response,
+    }
+}
+
+/// Move the file or every file inside the collection at path into the trash, so something else can take its place
+fn remove(path: &str, session: &mut Session, state: &ServerState) -> Result<(), Response> {
+    let tree = load_tree(state, path, true)?;
+
+    if tree.file_size(path).is_some() {
+        http::execute(CommandType::Delete, vec![path.to_string()], None, session, state)?;
+    }
+    if tree.is_collection(path) {
+        delete_collection(&tree, path, session, state)?;
+    }
+
+    Ok(())
+}
+
+/// Copy or move a file with the commands a native client would run: receive it, upload it to the destination and delete the
+/// original when moving. Subscribers see a single rename instead of a move
+fn transfer_file(from: &str, to: &str, moving: bool, session: &mut Session, state: &ServerState) -> Result<(), Response> {
+    let mut share = http::execute(CommandType::Receive, vec![from.to_string()], None, session, state)?;
+
+    if !moving {
+        http::execute(CommandType::Upload, vec![to.to_string()], share.take_file(), session, state)?;
+
+        return Ok(());
+    }
+
+    session.mute_events(true);
+    let moved = http::execute(CommandType::Upload, vec![to.to_string()], share.take_file(), session, state)
+        .and_then(|_| http::execute(CommandType::Delete, vec![from.to_string()], None, session, state));
+    session.mute_events(false);
+    moved?;
+
+    state.events().publish(session, Event::Renamed { from: storage::key(from), to: storage::key(to) });
+
+    Ok(())
+}
+
+/// Copy or move every file inside a collection, and the collections inside it, to the destination. Shallow copies only create the
+/// collection itself
+fn transfer_collection(
+    tree: &Tree,
+    from: &str,
+    to: &str,
+    moving: bool,
+    shallow: bool,
+    session: &mut Session,
+    state: &ServerState,
+) -> Result<(), Response> {
+    let from_prefix = collection_prefix(from);
+    let to_prefix = collection_prefix(to);
+    let (files, collections) = match shallow {
+        true => (Vec::new(), Vec::new()),
+        false => tree.children(from, true),
+    };
+
+    for (file, _) in files {
+        transfer_file(file, &format!("{to_prefix}{}", &file[from_prefix.len()..]), moving, session, state)?;
+    }
+
+    // Empty collections only exist in the collections meta file, copy them too
+    let mut created: Vec<String> = collections.iter().map(|collection| format!("{to_prefix}{}", &collection[from_prefix.len()..])).collect();
+    created.push(to.to_string());
+
+    update_collections(state, |collections| {
+        if moving {
+            collections.retain(|collection| collection != from && !collection.starts_with(&from_prefix));
+        }
+        collections.extend(created);
+    })
+    .map_err(|error| Response::error(500, &error.to_string()))
+}
+
+/// Lock a file or collection, WebDAV requests changing it then need the token of the lock in their If header. Locking a path that
+/// does not exist creates an empty file there, the way RFC 4918 asks for
+fn lock(
+    request: &mut Request,
+    stream: &mut BufReader<impl Transport>,
+    path: &str,
+    session: &mut Session,
+    state: &ServerState,
+) -> Response {
+    let (body, tree) = match authorize(session, &[CommandType::Upload])
+        .and_then(|_| read_xml_body(request, stream))
+        .and_then(|body| Ok((body, load_tree(state, parent(path), false)?)))
+    {
+        Ok(loaded) => loaded,
+        Err(response) => return response,
+    };
+
+    // Refreshing a lock sends no body, the token of the lock is in the If header instead
+    if body.trim().is_empty() {
+        return refresh_lock(request, path, session, state);
+    }
+
+    let exists = path.is_empty() || tree.file_size(path).is_some() || tree.is_collection(path);
+    if !exists && !tree.is_collection(parent(path)) {
+        return Response::error(409, &format!("Collection {} does not exist", parent(path)));
+    }
+
+    let token = match storage::random_bytes(16) {
+        Ok(bytes) => lock_token(&bytes),
+        Err(error) => return Response::error(500, &error.to_string()),
+    };
+    let lock = Lock {
+        root: path.to_string(),
+        user: session.user().to_string(),
+        shared: body.split("lockscope>").nth(1).is_some_and(|scope| scope.contains("shared")),
+        recursive: request.header("depth") != Some("0"),
+        expires: storage::now() + LOCK_TIMEOUT,
+    };
+    let response = |status| lock_response(status, &token, &lock).header("Lock-Token", format!("<{token}>"));
+
+    {
+        let mut locks = state.webdav().locks.lock().unwrap();
+
+        let now = storage::now();
+        locks.retain(|_, lock| lock.expires > now);
+
+        // Locks that overlap the new one conflict with it, unless both are shared
+        if let Some(other) = locks.values().find(|other| other.covers(path, lock.recursive) && !(other.shared && lock.shared)) {
+            return Response::error(423, &format!("{} is locked", other.root));
+        }
+
+        locks.insert(token.clone(), lock.clone());
+    }
+
+    if exists {
+        return response(200);
+    }
+
+    match http::execute(CommandType::Upload, vec![path.to_string()], Some(Vec::new()), session, state) {
+        Ok(_) => response(201),
+        Err(error) => {
+            state.webdav().locks.lock().unwrap().remove(&token);
+
+            error
+        }
+    }
+}
+
+/// Refresh the lock on path whose token is in the If header, so it lasts another LOCK_TIMEOUT seconds
+fn refresh_lock(request: &Request, path: &str, session: &Session, state: &ServerState) -> Response {
+    let tokens = submitted_tokens(request);
+    if tokens.is_empty() {
+        return Response::error(400, "Refreshing a lock needs its token in the If header");
+    }
+
+    let mut locks = state.webdav().locks.lock().unwrap();
+
+    let now = storage::now();
+    locks.retain(|_, lock| lock.expires > now);
+
+    let refreshed = locks.iter_mut()
+        .find(|(token, lock)| tokens.contains(&token.as_str()) && lock.user == session.user() && lock.covers(path, false));
+
+    match refreshed {
+        Some((token, lock)) => {
+            lock.expires = now + LOCK_TIMEOUT;
+
+            lock_response(200, token, lock)
+        }
+        None => Response::error(412, &format!("{path} is not locked with the given token")),
+    }
+}
+
+/// Describe a lock the way the response to LOCK does
+fn lock_response(status: u16, token: &str, lock: &Lock) -> Response {
+    let scope = match lock.shared {
+        true => "shared",
+        false => "exclusive",
+    };
+    let depth = match lock.recursive {
+        true => "infinity",
+        false => "0",
+    };
+
+    Response::new(
+        status,
+        "application/xml; charset=utf-8",
+        format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:prop xmlns:D=\"DAV:\"><D:lockdiscovery><D:activelock>\
+             <D:locktype><D:write/></D:locktype><D:lockscope><D:{scope}/></D:lockscope><D:depth>{depth}</D:depth>\
+             <D:timeout>Second-{}</D:timeout><D:locktoken><D:href>{}</D:href></D:locktoken>\
+             <D:lockroot><D:href>/{}</D:href></D:lockroot></D:activelock></D:lockdiscovery></D:prop>\n",
+            lock.expires.saturating_sub(storage::now()),
+            escape(token),
+            utf8_percent_encode(&lock.root, HREF_SET),
+        )
+        .into_bytes(),
+    )
+}
+
+/// Unlock a file or collection, the token of the lock is in the Lock-Token header
+fn unlock(request: &Request, path: &str, session: &Session, state: &ServerState) -> Response {
+    if let Err(response) = authorize(session, &[CommandType::Upload]) {
+        return response;
+    }
+
+    let token = match request.header("lock-token") {
+        Some(token) => token.trim().trim_start_matches('<').trim_end_matches('>'),
+        None => return Response::error(400, "Lock-Token header required"),
+    };
+
+    let mut locks = state.webdav().locks.lock().unwrap();
+    let unlocked = locks.get(token).is_some_and(|lock| lock.user == session.user() && lock.covers(path, false));
+
+    match unlocked {
+        true => {
+            locks.remove(token);
+
+            Response::empty(204)
+        }
+        false => Response::error(409, &format!("{path} is not locked with the given token")),
+    }
+}
+
+/// Format random bytes as a lock token, like `opaquelocktoken:f81d4fae-7dec-11d0-a765-00a0c91e6bf6`
+fn lock_token(bytes: &[u8]) -> String {
+    let hex = hex::encode(bytes);
+
+    format!("opaquelocktoken:{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::{BufRead, Read, Write}, thread};
+
+    use super::*;
+    use crate::{config::Config, connection::{self, Pipe}};
+
+    /// Start a WebDAV server storing files in memory, returns the client end of its connection
+    fn start() -> BufReader<Pipe> {
+        let (client, server) = connection::pipe();
+
+        thread::spawn(move || {
+            let config: Config = toml::from_str("[server]\nthread_count = 1\nips = []\nstorage = \"memory\"").unwrap();
+            let state = ServerState::build(config.server().unwrap()).unwrap();
+
+            handle_client(server, Session::new(), &state)
+        });
+
+        BufReader::new(client)
+    }
+
+    /// Send a request and return the status, headers and body of the response
+    fn send(stream: &mut BufReader<Pipe>, method: &str, path: &str, headers: &[&str], body: &str) -> (u16, Vec<String>, String) {
+        let mut request = format!("{method} {path} HTTP/1.1\r\nContent-Length: {}\r\n", body.len());
+        for header in headers {
+            request.push_str(&format!("{header}\r\n"));
+        }
+        request.push_str(&format!("\r\n{body}"));
+        stream.get_mut().write_all(request.as_bytes()).unwrap();
+
+        let mut line = String::new();
+        stream.read_line(&mut line).unwrap();
+        let status = line.split(' ').nth(1).unwrap().parse().unwrap();
+
+        let mut headers = Vec::new();
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).unwrap();
+
+            match line.trim_end() {
+                "" => break,
+                header => headers.push(header.to_string()),
+            }
+        }
+
+        let length = headers.iter()
+            .find_map(|header| header.strip_prefix("Content-Length: "))
+            .map_or(0, |length| length.parse().unwrap());
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).unwrap();
+
+        (status, headers, String::from_utf8(body).unwrap())
+    }
+
+    /// Returns the hrefs listed in a PROPFIND response
+    fn hrefs(body: &str) -> Vec<&str> {
+        body.split("<D:href>").skip(1).filter_map(|rest| rest.split_once("</D:href>")).map(|(href, _)| href).collect()
+    }
+
+    #[test]
+    fn propfind_lists_by_depth() {
+        let mut stream = start();
+
+        assert_eq!(send(&mut stream, "MKCOL", "/dir", &[], "").0, 201);
+        assert_eq!(send(&mut stream, "MKCOL", "/dir/empty", &[], "").0, 201);
+        assert_eq!(send(&mut stream, "PUT", "/dir/a.txt", &[], "hello").0, 201);
+        assert_eq!(send(&mut stream, "PUT", "/dir/sub/b.txt", &[], "").0, 409);
+        assert_eq!(send(&mut stream, "PUT", "/c.txt", &[], "c").0, 201);
+
+        let (status, _, body) = send(&mut stream, "PROPFIND", "/dir", &["Depth: 0"], "");
+        assert_eq!(status, 207);
+        assert_eq!(hrefs(&body), ["/dir/"]);
+
+        let (_, _, body) = send(&mut stream, "PROPFIND", "/dir/", &["Depth: 1"], "");
+        assert_eq!(hrefs(&body), ["/dir/", "/dir/empty/", "/dir/a.txt"]);
+        assert!(body.contains("<D:getcontentlength>5</D:getcontentlength>"));
+
+        // The hidden directories are never listed, and cant be reached directly
+        let (_, _, body) = send(&mut stream, "PROPFIND", "/", &[], "");
+        assert_eq!(hrefs(&body), ["/", "/dir/", "/dir/empty/", "/c.txt", "/dir/a.txt"]);
+        assert_eq!(send(&mut stream, "PROPFIND", "/.meta", &["Depth: 1"], "").0, 400);
+        assert_eq!(send(&mut stream, "GET", "/.meta/link-secret", &[], "").0, 400);
+
+        assert_eq!(send(&mut stream, "PROPFIND", "/missing", &["Depth: 0"], "").0, 404);
+    }
+
+    #[test]
+    fn copy_and_move() {
+        let mut stream = start();
+
+        assert_eq!(send(&mut stream, "MKCOL", "/src", &[], "").0, 201);
+        assert_eq!(send(&mut stream, "MKCOL", "/src/empty", &[], "").0, 201);
+        assert_eq!(send(&mut stream, "PUT", "/src/a.txt", &[], "hello").0, 201);
+
+        assert_eq!(send(&mut stream, "COPY", "/src/a.txt", &["Destination: http://localhost/b.txt"], "").0, 201);
+        assert_eq!(send(&mut stream, "GET", "/b.txt", &[], "").2, "hello");
+        assert_eq!(send(&mut stream, "GET", "/src/a.txt", &[], "").2, "hello");
+
+        // Overwrite F keeps what is at the destination
+        assert_eq!(send(&mut stream, "COPY", "/src/a.txt", &["Destination: /b.txt", "Overwrite: F"], "").0, 412);
+        assert_eq!(send(&mut stream, "COPY", "/src/a.txt", &["Destination: /b.txt"], "").0, 204);
+        assert_eq!(send(&mut stream, "COPY", "/src/a.txt", &["Destination: /none/b.txt"], "").0, 409);
+        assert_eq!(send(&mut stream, "COPY", "/src", &["Destination: /src/inner"], "").0, 403);
+
+        // Collections are copied with everything inside them, or alone with Depth 0
+        assert_eq!(send(&mut stream, "COPY", "/src", &["Destination: /deep"], "").0, 201);
+        assert_eq!(send(&mut stream, "COPY", "/src", &["Destination: /shallow", "Depth: 0"], "").0, 201);
+        let (_, _, body) = send(&mut stream, "PROPFIND", "/deep", &["Depth: 1"], "");
+        assert_eq!(hrefs(&body), ["/deep/", "/deep/empty/", "/deep/a.txt"]);
+        let (_, _, body) = send(&mut stream, "PROPFIND", "/shallow", &["Depth: 1"], "");
+        assert_eq!(hrefs(&body), ["/shallow/"]);
+
+        // Moving a collection onto a file replaces the file
+        assert_eq!(send(&mut stream, "MOVE", "/src", &["Destination: /b.txt"], "").0, 204);
+        assert_eq!(send(&mut stream, "PROPFIND", "/src", &["Depth: 0"], "").0, 404);
+        assert_eq!(send(&mut stream, "GET", "/b.txt/a.txt", &[], "").2, "hello");
+    }
+
+    #[test]
+    fn lock_and_unlock() {
+        let mut stream = start();
+        let body = "<?xml version=\"1.0\"?><D:lockinfo xmlns:D=\"DAV:\"><D:lockscope><D:exclusive/></D:lockscope>\
+                    <D:locktype><D:write/></D:locktype><D:owner>test</D:owner></D:lockinfo>";
+
+        assert!(send(&mut stream, "OPTIONS", "/", &[], "").1.contains(&String::from("DAV: 1, 2")));
+
+        // Locking a path that does not exist creates an empty file
+        let (status, headers, response) = send(&mut stream, "LOCK", "/new.txt", &[], body);
+        assert_eq!(status, 201);
+        assert!(response.contains("<D:exclusive/>"));
+        let token = headers.iter().find_map(|header| header.strip_prefix("Lock-Token: ")).unwrap().to_string();
+        assert!(token.starts_with("<opaquelocktoken:"));
+        assert_eq!(send(&mut stream, "GET", "/new.txt", &[], "").0, 200);
+
+        // Refreshing sends the token in the If header
+        let (status, _, response) = send(&mut stream, "LOCK", "/new.txt", &[&format!("If: ({token})")], "");
+        assert_eq!(status, 200);
+        assert!(response.contains(token.trim_matches(['<', '>'])));
+
+        assert_eq!(send(&mut stream, "LOCK", "/none/new.txt", &[], body).0, 409);
+        assert_eq!(send(&mut stream, "UNLOCK", "/new.txt", &[], "").0, 400);
+        assert_eq!(send(&mut stream, "UNLOCK", "/new.txt", &["Lock-Token: <opaquelocktoken:other>"], "").0, 409);
+        assert_eq!(send(&mut stream, "UNLOCK", "/new.txt", &[&format!("Lock-Token: {token}")], "").0, 204);
+        assert_eq!(send(&mut stream, "LOCK", "/new.txt", &[&format!("If: ({token})")], "").0, 412);
+    }
+
+    #[test]
+    fn locks_are_enforced() {
+        let mut stream = start();
+        let exclusive = "<?xml version=\"1.0\"?><D:lockinfo xmlns:D=\"DAV:\"><D:lockscope><D:exclusive/></D:lockscope>\
+                         <D:locktype><D:write/></D:locktype></D:lockinfo>";
+        let shared = exclusive.replace("exclusive", "shared");
+
+        assert_eq!(send(&mut stream, "MKCOL", "/dir", &[], "").0, 201);
+        let (status, headers, _) = send(&mut stream, "LOCK", "/dir", &[], exclusive);
+        assert_eq!(status, 200);
+        let token = headers.iter().find_map(|header| header.strip_prefix("Lock-Token: ")).unwrap().to_string();
+        let submitted = format!("If: ({token})");
+
+        // Everything inside the locked collection needs the token to be changed. Refused bodies close the connection, so the refused
+        // request has none
+        assert_eq!(send(&mut stream, "PUT", "/dir/a.txt", &[], "").0, 423);
+        assert_eq!(send(&mut stream, "PUT", "/dir/a.txt", &[&submitted], "hello").0, 201);
+        assert_eq!(send(&mut stream, "MKCOL", "/dir/sub", &[], "").0, 423);
+        assert_eq!(send(&mut stream, "COPY", "/dir/a.txt", &["Destination: /b.txt"], "").0, 201);
+        assert_eq!(send(&mut stream, "COPY", "/b.txt", &["Destination: /dir/b.txt"], "").0, 423);
+        assert_eq!(send(&mut stream, "MOVE", "/dir/a.txt", &["Destination: /c.txt"], "").0, 423);
+        assert_eq!(send(&mut stream, "DELETE", "/dir", &[], "").0, 423);
+        // Deleting a collection that has a locked file inside it is refused too
+        assert_eq!(send(&mut stream, "LOCK", "/b.txt", &["Depth: 0"], exclusive).0, 200);
+        assert_eq!(send(&mut stream, "DELETE", "/b.txt", &[], "").0, 423);
+
+        // Overlapping locks conflict unless they are both shared
+        assert_eq!(send(&mut stream, "LOCK", "/dir/a.txt", &[], exclusive).0, 423);
+        assert_eq!(send(&mut stream, "LOCK", "/", &[], &shared).0, 423);
+        assert_eq!(send(&mut stream, "UNLOCK", "/dir", &[&format!("Lock-Token: {token}")], "").0, 204);
+        assert_eq!(send(&mut stream, "LOCK", "/dir", &[], &shared).0, 200);
+        let (status, headers, _) = send(&mut stream, "LOCK", "/dir", &[], &shared);
+        assert_eq!(status, 200);
+        let token = headers.iter().find_map(|header| header.strip_prefix("Lock-Token: ")).unwrap().to_string();
+
+        // One of the shared locks is enough, and deleting the collection drops its locks
+        assert_eq!(send(&mut stream, "DELETE", "/dir", &[&format!("If: ({token})")], "").0, 204);
+        assert_eq!(send(&mut stream, "MKCOL", "/dir", &[], "").0, 201);
+        assert_eq!(send(&mut stream, "PUT", "/dir/a.txt", &[], "hello").0, 201);
+    }
+}
@@ -1,12 +1,15 @@
 #![feature(buf_read_has_data_left)]
-use std::{net::{TcpListener, TcpStream}, io::Write, process};
+use std::{net::TcpListener, os::unix::net::UnixListener, fs, process, sync::Arc, thread, time::Duration};
 
-use file_share::{Share, Location, Config};
+use file_share::{Config, server::{ServerState, Session, handle_client}, discovery, connection::Connection, tls::{self, ServerConfig}, http, webdav};
 
 mod threadpool;
 
 use threadpool::ThreadPool;
 
+/// Handles a single client connection, either speaking the native protocol or HTTP
+type Handler = fn(Connection, Session, &ServerState);
+
 fn main() {
     let config = Config::build("Config.toml").unwrap_or_else(|error| {
         eprintln!("Config build error: {error}");
@@ -17,7 +20,7 @@ fn main() {
     });
 
     // Create a new thread pool
-    let pool = match ThreadPool::build(config.thread_count()) {
+    let pool: ThreadPool = match ThreadPool::build(config.thread_count()) {
         Ok(p) => p,
         // Thread pool could not be created
         Err(e) => {
@@ -40,29 +43,174 @@ fn main() {
         }
     };
 
-    // Create a TcpListener and attempt to bind to the given ip
-    let listener = TcpListener::bind(config.ip()).unwrap_or_else(|error| {
-        eprintln!("Failed in binding to address: {error}. Trying to connect to backups");
+    let ips = config.ip_backups().clone();
+    let purge_interval = Duration::from_secs(config.purge_interval());
+    let discovery = config.discovery().then(|| (config.name().to_string(), config.discovery_port()));
 
-        // Loop through the vector of ip backups and try to connect to one until a success
-        for (i, ip) in config.ip_backups().iter().enumerate() {
-            match TcpListener::bind(ip) {
-                Ok(listener) => {
-                    println!("Backup ip: {i} successfully bound");
-                    return listener
-                }
-                Err(error) => {
-                    println!("Backup ip: {i} failed to bind: {error}");
-                    continue;
+    // Create the storage and state all clients share
+    let state = Arc::new(ServerState::build(config).unwrap_or_else(|error| {
+        eprintln!("Storage build error: {error}");
+        process::exit(1);
+    }));
+
+    // Start the background job that purges old files from the trash and versions store
+    let purge_state = Arc::clone(&state);
+    thread::spawn(move || loop {
+        if let Err(error) = purge_state.storage().purge() {
+            eprintln!("Failed to purge storage: {error}");
+        }
+
+        thread::sleep(purge_interval);
+    });
+
+    // Bind every address at once, or the first address that works
+    let listeners = if state.config().listen_all() {
+        bind_all(&ips)
+    } else {
+        vec![bind_first(&ips)]
+    };
+
+    // Reply to clients looking for servers on the local network
+    if let Some((name, port)) = discovery {
+        let addresses = listeners.iter().filter_map(|(_, listener)| listener.local_addr().ok()).collect();
+
+        if let Err(error) = discovery::respond(name, port, addresses) {
+            eprintln!("Failed to start discovery responder: {error}");
+        }
+    }
+
+    // All listeners share the same pool
+    let pool = Arc::new(pool);
+    let mut handles = Vec::new();
+
+    for (address, listener) in listeners {
+        let tls = load_tls(&state, &address);
+        let pool = Arc::clone(&pool);
+        let state = Arc::clone(&state);
+
+        handles.push(thread::spawn(move || serve(listener, address, tls, handle_client, &pool, &state)));
+    }
+
+    // Serve the files over HTTP and WebDAV
+    let gateways: [(&str, Option<String>, Handler); 2] = [
+        ("HTTP gateway", state.config().http().map(String::from), http::handle_client),
+        ("WebDAV frontend", state.config().webdav().map(String::from), webdav::handle_client),
+    ];
+
+    for (name, address, handler) in gateways {
+        let Some(address) = address else {
+            continue;
+        };
+
+        match TcpListener::bind(&address) {
+            Ok(listener) => {
+                println!("{name} listening on {address}");
+
+                let tls = load_tls(&state, &address);
+                let pool = Arc::clone(&pool);
+                let state = Arc::clone(&state);
+
+                handles.push(thread::spawn(move || serve(listener, address, tls, handler, &pool, &state)));
+            }
+            Err(error) => eprintln!("Failed to bind {name} {address}: {error}"),
+        }
+    }
+
+    // Listen for clients on this host
+    if let Some(path) = state.config().unix_socket() {
+        // Remove the socket left behind by a previous run, binding fails if the path exists
+        let _ = fs::remove_file(path);
+
+        match UnixListener::bind(path) {
+            Ok(listener) => {
+                println!("Listening on {path}");
+
+                let pool = Arc::clone(&pool);
+                let state = Arc::clone(&state);
+
+                handles.push(thread::spawn(move || serve_unix(listener, &pool, &state)));
+            }
+            Err(error) => eprintln!("Failed to bind Unix socket {path}: {error}"),
+        }
+    }
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+/// Create a TcpListener and attempt to bind to the first ip, if that fails the other ips are tried as backups. Returns the ip that
+/// was bound and the listener
+fn bind_first(ips: &[String]) -> (String, TcpListener) {
+    match TcpListener::bind(&ips[0]) {
+        Ok(listener) => (ips[0].clone(), listener),
+        Err(error) => {
+            eprintln!("Failed in binding to address: {error}. Trying to connect to backups");
+
+            // Loop through the vector of ip backups and try to connect to one until a success
+            for (i, ip) in ips.iter().enumerate() {
+                match TcpListener::bind(ip) {
+                    Ok(listener) => {
+                        println!("Backup ip: {i} successfully bound");
+                        return (ip.clone(), listener)
+                    }
+                    Err(error) => {
+                        println!("Backup ip: {i} failed to bind: {error}");
+                        continue;
+                    }
                 }
             }
+
+            eprintln!("All backup ip's failed to bind!");
+
+            process::exit(1);
+        }
+    }
+}
+
+/// Create a TcpListener for every ip, ips that fail to bind are skipped. Returns the ips that were bound and their listeners
+fn bind_all(ips: &[String]) -> Vec<(String, TcpListener)> {
+    let mut listeners = Vec::new();
+
+    for ip in ips {
+        match TcpListener::bind(ip) {
+            Ok(listener) => {
+                println!("Listening on {ip}");
+                listeners.push((ip.clone(), listener));
+            }
+            Err(error) => eprintln!("Failed to bind {ip}: {error}"),
         }
+    }
 
-        eprintln!("All backup ip's failed to bind!");
+    if listeners.is_empty() {
+        eprintln!("All ip's failed to bind!");
 
         process::exit(1);
-    });
+    }
 
+    listeners
+}
+
+/// Load the TLS configuration of the listener for the given address, or None if it does not use TLS
+fn load_tls(state: &ServerState, address: &str) -> Option<Arc<ServerConfig>> {
+    state.config().listener(address).and_then(|listener| listener.tls()).map(|(cert, key)| {
+        tls::server_config(cert, key).unwrap_or_else(|error| {
+            eprintln!("Failed to load TLS configuration for {address}: {error}");
+            process::exit(1);
+        })
+    })
+}
+
+/// Accept connections on a listener and handle them in the pool with handler, address is the ip the listener was bound to (as
+/// written in the configuration)
+fn serve(
+    listener: TcpListener,
+    address: String,
+    tls: Option<Arc<ServerConfig>>,
+    handler: Handler,
+    pool: &ThreadPool,
+    state: &Arc<ServerState>,
+) {
     // Loop through each connection
     for stream in listener.incoming() {
         // Get the value inside stream
@@ -80,49 +228,57 @@ fn main() {
             }
         };
 
-        // Execute the handle_client() function for each connection
-        pool.execute(|| {
-            handle_client(stream)
+        // Rate limits and bans are per ip
+        let ip = stream.peer_addr().ok().map(|address| address.ip());
+
+        // Wrap the stream in TLS if the listener uses it
+        let stream = match tls.as_ref() {
+            Some(config) => match tls::accept(stream, Arc::clone(config)) {
+                Ok(stream) => stream,
+                Err(error) => {
+                    eprintln!("TLS error: {error}");
+                    continue;
+                }
+            },
+            None => Connection::Tcp(stream),
+        };
+
+        let state = Arc::clone(state);
+        let address = address.clone();
+
+        // Execute the handler for each connection
+        pool.execute(move || {
+            let session = Session::for_listener(state.config().listener(&address), ip);
+
+            handler(stream, session, &state)
         });
     }
 }
 
-/// Only the official client will work for the most part so the server wont have
-/// to handle additional things like making sure your command was correct (this
-/// is checked on the official client)
-fn handle_client(mut stream: TcpStream) {
-    loop {
-        // Read data that was sent from client
-        let mut share = match Share::read_from_stream(&mut stream, Location::Server) {
-            // Successful read
-            Ok(share) => share,
-            // Invalid read
+/// Accept connections on the Unix socket and handle them in the pool, clients are logged in as the user id of their process
+fn serve_unix(listener: UnixListener, pool: &ThreadPool, state: &Arc<ServerState>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => Connection::Unix(stream),
             Err(error) => {
-                eprintln!("{error}");
-                // Just returns since unofficial clients/requests wont be supported, no error is returned since with the official client
-                // these will be checked on the client side, I dont want the server to have to use extra CPU power to check this and
-                // return an error. This should not effect most people.
-                return;
+                eprintln!("Connection to client failed: {error}");
+                continue;
             }
         };
 
-        // Execute the recieved command
-        if let Err(e) = share.execute() {
-            // If there was an error set the servers error response
-            share.set_error_response(e);
-        };
-
-        // Write share to stream since we executed the command and all the data needed is inside
-        match share.write_to_stream(&mut stream, Location::Server) {
-            Ok(_) => (),
+        // The kernel reports who the client is, so it does not have to log in
+        let session = match stream.peer_uid() {
+            Ok(uid) => Session::for_peer(uid),
             Err(error) => {
-                eprintln!("Failed to write to stream: {error}");
-                return;
+                eprintln!("Failed to get peer credentials: {error}");
+                continue;
             }
-        }
+        };
 
-        stream.flush().unwrap_or_else(|error| {
-            eprintln!("Failed to flush stream: {error}: Client ip {}", stream.peer_addr().unwrap());
-        });
+        println!("Client {} connected", stream.peer());
+
+        let state = Arc::clone(state);
+
+        pool.execute(move || handle_client(stream, session, &state));
     }
-}
\ No newline at end of file
+}